@@ -0,0 +1,145 @@
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use super::connection::Connection;
+use super::ejson::EjsonValue;
+use super::error::DdpError;
+use super::messages::Ejson;
+
+/// Who's logging in with a password, as `Meteor.loginWithPassword` takes it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum User {
+    Username(String),
+    Email(String),
+    Id(String),
+}
+
+impl User {
+    fn selector(&self) -> Ejson {
+        match *self {
+            User::Username(ref username) => json!({ "username": username }),
+            User::Email(ref email)       => json!({ "email": email }),
+            User::Id(ref id)             => json!({ "id": id }),
+        }
+    }
+}
+
+/// The user a connection is logged in as, and the token to log back in with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Login {
+    user_id: String,
+    token:   String,
+    expires: Option<SystemTime>,
+}
+
+impl Login {
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn expires(&self) -> Option<SystemTime> {
+        self.expires
+    }
+
+    fn decode(result: &Ejson) -> Option<Self> {
+        Some(Login {
+            user_id: result["id"].as_str()?.to_string(),
+            token:   result["token"].as_str()?.to_string(),
+            expires: EjsonValue::decode(&result["tokenExpires"]).ok().and_then(|date| date.as_date()),
+        })
+    }
+}
+
+/// Meteor's accounts-base and accounts-password methods. A successful login is
+/// remembered, and its token used to log back in whenever the connection
+/// reconnects; a failed one forgets it.
+impl Connection {
+    /// The password is only ever sent as a SHA-256 digest.
+    pub fn login_with_password<F>(&self, user: &User, password: &str, callback: F)
+    where F: FnMut(Result<&Login, &DdpError>) + Send + 'static {
+        let params = json!({
+            "user": user.selector(),
+            "password": {
+                "digest": format!("{:x}", Sha256::digest(password.as_bytes())),
+                "algorithm": "sha-256",
+            },
+        });
+        self.login(&params, callback);
+    }
+
+    pub fn login_with_token<F>(&self, token: &str, callback: F)
+    where F: FnMut(Result<&Login, &DdpError>) + Send + 'static {
+        self.login(&json!({ "resume": token }), callback);
+    }
+
+    pub fn logout<F>(&self, mut callback: F)
+    where F: FnMut(Result<(), &DdpError>) + Send + 'static {
+        let account = self.account.clone();
+        self.call("logout", None, Box::new(move |result| {
+            if result.is_ok() {
+                *account.lock().unwrap() = None;
+            }
+            callback(result.map(|_| ()));
+        }));
+    }
+
+    /// Logs out every other connection of this user. This connection gets a
+    /// new token first, so it stays logged in even after reconnecting.
+    pub fn logout_other_clients<F>(&self, mut callback: F)
+    where F: FnMut(Result<(), &DdpError>) + Send + 'static {
+        // Methods run in order, so there's no need to wait for the new token.
+        let failed = Arc::new(Mutex::new(None));
+        let (account, first) = (self.account.clone(), failed.clone());
+        self.call("getNewToken", None, Box::new(move |result| {
+            match result.map(Login::decode) {
+                Ok(Some(login)) => *account.lock().unwrap() = Some(login),
+                Ok(None)        => *first.lock().unwrap() = Some(malformed()),
+                Err(error)      => *first.lock().unwrap() = Some(error.clone()),
+            }
+        }));
+        self.call("removeOtherTokens", None, Box::new(move |result| {
+            match *failed.lock().unwrap() {
+                Some(ref error) => callback(Err(error)),
+                None            => callback(result.map(|_| ())),
+            }
+        }));
+    }
+
+    pub fn user_id(&self) -> Option<String> {
+        self.account.lock().unwrap().as_ref().map(|login| login.user_id.clone())
+    }
+
+    pub fn login_token(&self) -> Option<String> {
+        self.account.lock().unwrap().as_ref().map(|login| login.token.clone())
+    }
+
+    pub(crate) fn relogin(&self) {
+        if let Some(token) = self.login_token() {
+            self.login_with_token(&token, |_| {});
+        }
+    }
+
+    fn login<F>(&self, params: &Ejson, mut callback: F)
+    where F: FnMut(Result<&Login, &DdpError>) + Send + 'static {
+        let account = self.account.clone();
+        self.call("login", Some(&vec![params]), Box::new(move |result| {
+            let login = match result.map(Login::decode) {
+                Ok(Some(login)) => Ok(login),
+                Ok(None)        => Err(malformed()),
+                Err(error)      => Err(error.clone()),
+            };
+            *account.lock().unwrap() = login.as_ref().ok().cloned();
+            callback(login.as_ref());
+        }));
+    }
+}
+
+fn malformed() -> DdpError {
+    DdpError::local("malformed-login", "The server's login result is missing the user id or token")
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::Duration;
+
+use websocket::client::Url;
+
+use super::{Client, Collection, DdpConnError, DdpError, MethodCallbacks, Subscription, TlsConfig};
+use super::messages::Ejson;
+
+/// A `Client` for straight-line code: calls wait for their result and
+/// subscriptions wait until they're ready. Without a timeout a call waits for
+/// as long as the connection does.
+pub struct BlockingClient {
+    client:  Client,
+    timeout: Option<Duration>,
+}
+
+impl BlockingClient {
+    pub fn new(url: Url) -> Result<Self, DdpConnError> {
+        Client::new(url).map(BlockingClient::from)
+    }
+
+    pub fn with_tls(url: Url, tls: &TlsConfig) -> Result<Self, DdpConnError> {
+        Client::with_tls(url, tls).map(BlockingClient::from)
+    }
+
+    /// How long `call` and `subscribe` wait before giving up with a `"timeout"`
+    /// error. `None`, the default, waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn call(&self, method: &str, params: Option<&Vec<&Ejson>>) -> Result<Ejson, DdpError> {
+        let (tx, rx) = channel();
+        let callbacks = MethodCallbacks::new().on_result(move |result| {
+            tx.send(result.map(|r| r.clone()).map_err(|e| e.clone())).ok();
+        });
+        // Timing the method out as well means it doesn't linger once we've given up.
+        let callbacks = match self.timeout {
+            Some(timeout) => callbacks.timeout(timeout),
+            None          => callbacks,
+        };
+        self.client.apply(method, params, callbacks);
+        self.wait(rx.recv_timeout(self.wait_for()))
+    }
+
+    /// Blocks until the publication is ready. If it's refused or the wait times
+    /// out the subscription is stopped and the error returned.
+    pub fn subscribe(&self, name: &str, params: Option<&Vec<&Ejson>>) -> Result<Subscription, DdpError> {
+        let sub = self.client.subscribe(name, params);
+        let (tx, rx) = channel();
+        sub.on_ready(move |result| {
+            tx.send(result.map_err(|e| e.clone())).ok();
+        });
+        match self.wait(rx.recv_timeout(self.wait_for())) {
+            Ok(()) => Ok(sub),
+            Err(e) => {
+                sub.stop();
+                Err(e)
+            },
+        }
+    }
+
+    #[inline]
+    pub fn mongo<S>(&self, collection: S) -> Arc<Collection>
+    where S: Into<String> {
+        self.client.mongo(collection)
+    }
+
+    #[inline]
+    pub fn session(&self) -> String {
+        self.client.session()
+    }
+
+    /// The underlying client, for callbacks and reconnect settings.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn client_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    // recv_timeout overflows on Duration::max_value, a year is forever enough.
+    fn wait_for(&self) -> Duration {
+        self.timeout.unwrap_or_else(|| Duration::from_secs(365 * 24 * 60 * 60))
+    }
+
+    fn wait<T>(&self, received: Result<Result<T, DdpError>, RecvTimeoutError>) -> Result<T, DdpError> {
+        match received {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(DdpError::local("timeout", "Timed out waiting for the server")),
+            Err(RecvTimeoutError::Disconnected) => Err(DdpError::local("disconnected", "The connection was closed")),
+        }
+    }
+}
+
+impl From<Client> for BlockingClient {
+    fn from(client: Client) -> Self {
+        BlockingClient {
+            client:  client,
+            timeout: None,
+        }
+    }
+}
@@ -1,30 +1,195 @@
-use websocket::client::sender;
-use websocket::client::receiver;
-use websocket::ws::receiver::Receiver;
-use websocket::ws::sender::Sender;
-use websocket::stream::WebSocketStream;
-use websocket::result::WebSocketError;
-use websocket::Message;
-
-trait Socket {
-    fn send(&self, msg: Message) -> Result<(), WebSocketError>;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Condvar, Mutex};
+use std::sync::mpsc::Sender as AtomicSender;
+use std::thread::{self, ThreadId};
+
+/// What to do with a message sent while offline once the outbox is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Make room by failing the oldest queued message.
+    DropOldest,
+    /// Fail the new message.
+    Reject,
+    /// Block the caller until the connection is back or there's room.
+    Block,
 }
 
-struct QueuedSocket {
-    sender:   Mutex<Box<Sender<WebSocketStream>>>,
-    receiver: receiver::Receiver<WebSocketStream>,
+pub enum Sent {
+    Live,
+    Queued,
+    /// Queued, but the message with this id was dropped to make room.
+    Dropped(String),
+    /// Offline, and not queueing, so it's up to the resume logic.
+    Lost,
+    Rejected,
 }
 
-impl Socket for QueuedSocket {
-    pub fn send(&self, msg: Message) -> Result<(), WebSocketError> {
-        match sender.try_lock() {
-            Ok(locked) => {
-                match locked.send_message(msg) {
-                    Err(_) => 
-                }
+struct Entry {
+    id:   String,
+    text: String,
+}
+
+struct State {
+    sender:   Option<AtomicSender<String>>,
+    queue:    VecDeque<Entry>,
+    limit:    Option<usize>,
+    overflow: Overflow,
+    // Threads that waited for room with `reserve` and are yet to `send`.
+    reserved: Vec<ThreadId>,
+    // Shut for good, nothing more will be queued.
+    shut:     bool,
+}
+
+/// Every outgoing message goes through here. While the socket is down
+/// messages are either let go, which is fine for methods and subscriptions as
+/// they're replayed after reconnecting, or held in a bounded queue and flushed
+/// in order once the socket is back.
+pub struct Outbox {
+    state: Mutex<State>,
+    space: Condvar,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Outbox {
+            state: Mutex::new(State {
+                sender:   None,
+                queue:    VecDeque::new(),
+                limit:    None,
+                overflow: Overflow::Reject,
+                reserved: Vec::new(),
+                shut:     false,
+            }),
+            space: Condvar::new(),
+        }
+    }
+
+    /// Starts queueing up to `limit` messages while offline. `None` stops
+    /// queueing; anything already queued is still flushed.
+    pub fn queue(&self, limit: Option<usize>, overflow: Overflow) {
+        let mut state = self.state.lock().unwrap();
+        state.limit = limit;
+        state.overflow = overflow;
+        self.space.notify_all();
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().queue.len()
+    }
+
+    /// Waits for room when the overflow policy is `Block`, and holds it for
+    /// this thread's next `send`. Must be called before taking any lock the
+    /// reconnecting thread needs, which is why `send` itself never waits.
+    pub fn reserve(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let limit = match state.limit {
+                Some(limit) if state.sender.is_none() && !state.shut && state.overflow == Overflow::Block => limit,
+                _ => return,
+            };
+            if state.queue.len() + state.reserved.len() < limit {
+                state.reserved.push(thread::current().id());
+                return;
+            }
+            state = self.space.wait(state).unwrap();
+        }
+    }
+
+    /// `id` identifies the method or subscription the message belongs to.
+    pub fn send(&self, id: &str, text: String) -> Sent {
+        let mut state = self.state.lock().unwrap();
+        let me = thread::current().id();
+        let reserved = match state.reserved.iter().position(|&thread| thread == me) {
+            Some(index) => {
+                state.reserved.swap_remove(index);
+                true
+            },
+            None => false,
+        };
+        let text = match state.sender.take() {
+            Some(sender) => match sender.send(text) {
+                Ok(()) => {
+                    state.sender = Some(sender);
+                    return Sent::Live;
+                },
+                Err(unsent) => unsent.0,
+            },
+            None => text,
+        };
+
+        let limit = match state.limit {
+            Some(limit) if !state.shut => limit,
+            _                          => return Sent::Lost,
+        };
+        let mut sent = Sent::Queued;
+        // Room held by other threads' reservations isn't ours to take.
+        if !reserved && state.queue.len() + state.reserved.len() >= limit {
+            match state.overflow {
+                // Only a thread that couldn't wait in `reserve` ends up here.
+                Overflow::Reject | Overflow::Block => return Sent::Rejected,
+                Overflow::DropOldest => match state.queue.pop_front() {
+                    Some(oldest) => sent = Sent::Dropped(oldest.id),
+                    None         => return Sent::Rejected,
+                },
             }
         }
+        state.queue.push_back(Entry { id: id.to_string(), text: text });
+        sent
+    }
+
+    /// Takes back a message that hasn't gone out yet.
+    pub fn cancel(&self, id: &str) {
+        self.state.lock().unwrap().queue.retain(|entry| entry.id != id);
+        self.space.notify_all();
+    }
+
+    /// Only sent if the socket is up, for things like pongs.
+    pub fn send_live(&self, text: String) {
+        if let Some(ref sender) = self.state.lock().unwrap().sender {
+            sender.send(text).ok();
+        }
+    }
+
+    /// Drops the sender, which ends the sending thread once it's drained.
+    pub fn close(&self) {
+        self.state.lock().unwrap().sender = None;
+        self.space.notify_all();
     }
 
-    fn try_send
+    /// Closes for good: the queue is thrown away and nobody waits for room
+    /// any more. Only `open` brings the outbox back.
+    pub fn shut(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.sender = None;
+        state.queue.clear();
+        state.reserved.clear();
+        state.shut = true;
+        self.space.notify_all();
+    }
+
+    /// Goes live on `sender` without flushing the queue, so whatever is sent
+    /// next goes out ahead of it. `open` with the same sender finishes the job.
+    pub fn attach(&self, sender: AtomicSender<String>) {
+        self.state.lock().unwrap().sender = Some(sender);
+    }
+
+    /// Goes live on `sender`, sending `replays` (id and text) first and then
+    /// the queue. Replays for messages that are still queued are skipped.
+    pub fn open(&self, sender: AtomicSender<String>, replays: Vec<(String, String)>) {
+        let mut state = self.state.lock().unwrap();
+        {
+            let queued: HashSet<&str> = state.queue.iter().map(|entry| &entry.id[..]).collect();
+            for (id, text) in replays {
+                if !queued.contains(&id[..]) {
+                    sender.send(text).ok();
+                }
+            }
+        }
+        for entry in state.queue.drain(..) {
+            sender.send(entry.text).ok();
+        }
+        state.sender = Some(sender);
+        state.shut = false;
+        self.space.notify_all();
+    }
 }
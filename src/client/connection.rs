@@ -1,14 +1,19 @@
 use serde_json;
 extern crate websocket;
 
+use std::collections::HashSet;
 use std::collections::hash_map::HashMap;
 use std::io;
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex, MutexGuard};
-use std::sync::mpsc::channel;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
 use std::sync::mpsc::Sender as AtomicSender;
 use std::thread;
-use std::thread::JoinHandle;
+use std::thread::{JoinHandle, ThreadId};
+use std::time::Duration;
+use std::vec;
+use native_tls::TlsConnector;
 use serde_json::Value;
 use websocket::client::Url;
 use websocket::{ClientBuilder, Message};
@@ -16,58 +21,255 @@ use websocket::client::sync::Client;
 use websocket::message::OwnedMessage;
 use websocket::result::WebSocketError;
 
+use super::accounts::Login;
 use super::messages::*;
-
-use random::Random;
-
-type MethodCallback = Box<FnMut(Result<&Ejson, &Ejson>) + Send + 'static>;
+use super::bufferedws::{Outbox, Overflow, Sent};
+use super::error::DdpError;
+use super::events::CollectionEvent;
+use super::heartbeat::Heartbeat;
+use super::minimongo::{diff, id_string, Documents};
+use super::observe::{LiveQuery, Observe, ObserveChanges};
+use super::query::Query;
+use super::status::{State, Status, Statuses};
+use super::stream::{Stream, TlsConfig};
+use super::stub::StubContext;
+use super::timer::Timer;
+
+use crate::random::{Random, RandomStream};
+
+type MethodCallback = Box<FnMut(Result<&Ejson, &DdpError>) + Send + 'static>;
+type UpdatedCallback = Box<FnMut() + Send + 'static>;
+type ReadyCallback = Box<FnMut(Result<(), &DdpError>) + Send + 'static>;
+type StopCallback = Box<FnMut(Option<&DdpError>) + Send + 'static>;
 type MongoLock<'s> = MutexGuard<'s, HashMap<String, Arc<Collection>>>;
+type Stub = Arc<Fn(&mut StubContext, &[&Ejson]) + Send + Sync + 'static>;
 
 pub struct Connection {
     core:       Core,
-    session_id: String,
-    version:    &'static str,
+    url:        Url,
+    tls:        Option<TlsConnector>,
+    session_id: Mutex<String>,
+    version:    Mutex<&'static str>,
+    // Called once the connection closes for good.
+    on_close:   Option<Box<Fn() + Sync + Send>>,
+    pub(crate) account: Arc<Mutex<Option<Login>>>,
 }
 
 impl Connection {
     pub fn new<F>(url: &Url, on_crash: F) -> Result<(Self, ConnectionHandle), DdpConnError>
     where F: Fn() + Sync + Send + 'static {
-        if url.scheme() != WS && url.scheme() != WSS {
-            return Err(DdpConnError::UrlIsNotWebsocket);
-        }
-        let (client, session_id, v_index) = Connection::connect(url)?;
-        let ( mut receiver, mut sender) = client.split().map_err(|e| DdpConnError::IoError(e))?;
-        let sreport = Arc::new(OnDrop(Arc::new(on_crash)));
-        let rreport = sreport.clone();
+        Connection::with_tls(url, &TlsConfig::new(), on_crash)
+    }
 
-        let (tx, rx) = channel();
-        let tx      = Arc::new(Mutex::new(tx));
-        let methods = Arc::new(Mutex::new(Methods::new(tx.clone())));
+    /// Like `new`, but `wss://` urls are secured according to `tls`.
+    ///
+    /// Once the socket is gone every pending method and live subscription
+    /// fails with a `"disconnected"` error, just before `on_crash` is called,
+    /// and so does any called or made after.
+    pub fn with_tls<F>(url: &Url, tls: &TlsConfig, on_crash: F) -> Result<(Self, ConnectionHandle), DdpConnError>
+    where F: Fn() + Sync + Send + 'static {
+        Connection::open(url, tls, Arc::new(on_crash), None)
+    }
+
+    /// Like `with_tls`, but pending methods are kept when the socket goes, for
+    /// `reconnect` to replay. `fail_pending` is up to the caller. `on_close` is
+    /// called once the connection is disconnected or dropped.
+    pub(crate) fn resumable<F, G>(url: &Url, tls: &TlsConfig, on_crash: F, on_close: G)
+    -> Result<(Self, ConnectionHandle), DdpConnError>
+    where F: Fn() + Sync + Send + 'static, G: Fn() + Sync + Send + 'static {
+        Connection::open(url, tls, Arc::new(on_crash), Some(Box::new(on_close)))
+    }
+
+    // Only a resumable connection has an `on_close`.
+    fn open(url: &Url, tls: &TlsConfig, on_crash: Arc<Fn() + Sync + Send>, on_close: Option<Box<Fn() + Sync + Send>>)
+    -> Result<(Self, ConnectionHandle), DdpConnError> {
+        let tls = match url.scheme() {
+            WS  => None,
+            WSS => Some(tls.connector()?),
+            _   => return Err(DdpConnError::UrlIsNotWebsocket),
+        };
+        let (client, session_id, v_index) = Connection::connect(url, tls.as_ref(), None)?;
+
+        // Goes live once `spawn` has a socket for it.
+        let outbox  = Arc::new(Outbox::new());
+        let methods = Arc::new(Mutex::new(Methods::new(outbox.clone())));
         let mongos  = Arc::new(Mutex::new(HashMap::new()));
-        let subs    = Arc::new(Mutex::new(Subscriptions::new(tx.clone())));
+        let subs    = Arc::new(Mutex::new(Subscriptions::new(outbox.clone())));
 
         let core = Core {
-            methods:  methods,
-            mongos:   mongos,
-            subs:     subs,
-            transfer: tx,
+            methods:   methods,
+            mongos:    mongos,
+            subs:      subs,
+            outbox:    outbox,
+            stubs:     Arc::new(Mutex::new(HashMap::new())),
+            timer:     Arc::new(Timer::new()),
+            heartbeat: Arc::new(Heartbeat::new()),
+            status:    Arc::new(Statuses::new()),
+            closing:   Arc::new(AtomicBool::new(false)),
+            resuming:  Arc::new(Mutex::new(())),
+            running:   Arc::new(Running::new()),
+        };
+        let on_crash = if on_close.is_some() { on_crash } else {
+            let crashed = core.clone();
+            Arc::new(move || {
+                crashed.status.set(State::Offline, Some(lost()));
+                crashed.fail_pending();
+                on_crash();
+            })
         };
-        let client_core = core.clone();
+        let (handle, sender) = Connection::spawn(&core, client, on_crash)?;
+        core.outbox.open(sender, Vec::new());
+
+        Ok((Connection {
+            core:       core,
+            url:        url.clone(),
+            tls:        tls,
+            session_id: Mutex::new(session_id),
+            version:    Mutex::new(VERSIONS[v_index]),
+            on_close:   on_close,
+            account:    Arc::new(Mutex::new(None)),
+        }, handle))
+    }
+
+    /// Opens a new socket to the same server, asking it to resume the current session,
+    /// logs back in if there's a resume token, then restarts every live subscription,
+    /// re-sends every unanswered method and flushes the outbox. Only meant to be used once `on_crash` has fired for the
+    /// previous socket.
+    /// Fails without trying once `disconnect` has been called.
+    pub(crate) fn reconnect<F>(&self, on_crash: F) -> Result<ConnectionHandle, DdpConnError>
+    where F: Fn() + Sync + Send + 'static {
+        if self.is_closing() {
+            return Err(closed());
+        }
+        let session = self.session();
+        let (client, session_id, v_index) = Connection::connect(&self.url, self.tls.as_ref(), Some(session))?;
+        let (handle, settled) = {
+            let _resuming = self.core.resuming.lock().unwrap();
+            if self.is_closing() {
+                return Err(closed());
+            }
+            *self.session_id.lock().unwrap() = session_id;
+            *self.version.lock().unwrap() = VERSIONS[v_index];
+            let (handle, sender) = Connection::spawn(&self.core, client, Arc::new(on_crash))?;
+            let settled = self.core.resume(sender, || self.relogin());
+            self.core.status.set(State::Connected, None);
+            (handle, settled)
+        };
+        if settled {
+            self.core.resubscribed();
+        }
+        Ok(handle)
+    }
+
+    /// Pings the server once it has been quiet for `interval`, and hangs up if
+    /// nothing comes back within `timeout`, going down the same path as when
+    /// the socket breaks. On by default, with Meteor's 17.5 and 15 seconds.
+    pub fn heartbeat(&self, interval: Duration, timeout: Duration) {
+        self.core.heartbeat.configure(Some((interval, timeout)));
+        Heartbeat::start(&self.core.heartbeat, &self.core.outbox, &self.core.timer);
+    }
+
+    pub fn no_heartbeat(&self) {
+        self.core.heartbeat.configure(None);
+        Heartbeat::start(&self.core.heartbeat, &self.core.outbox, &self.core.timer);
+    }
+
+    pub fn status(&self) -> Status {
+        self.core.status.get()
+    }
+
+    /// Called with the new status whenever it changes.
+    pub fn on_status<F>(&self, f: F)
+    where F: Fn(&Status) + Send + 'static {
+        self.core.status.listen(Box::new(f));
+    }
+
+    pub(crate) fn set_status(&self, state: State, error: Option<String>) {
+        self.core.status.set(state, error);
+    }
+
+    /// Closes the connection for good. Every subscription is stopped, every
+    /// pending method fails with a `"disconnected"` error and so will any
+    /// method called or subscription made later, and the socket is closed with a close frame. Returns
+    /// once the connection's threads are done, or all but the one calling.
+    /// Neither `on_crash` nor a `Client`'s reconnecting kick in.
+    pub fn disconnect(&self) {
+        if !self.close() {
+            return;
+        }
+        {
+            let _resuming = self.core.resuming.lock().unwrap();
+            let callbacks = self.core.subs.lock().unwrap().unsub_all();
+            for mut callback in callbacks {
+                callback(None);
+            }
+            self.core.fail_pending();
+            self.core.outbox.close();
+            self.core.status.set(State::Offline, None);
+        }
+        self.core.running.wait();
+    }
+
+    pub(crate) fn is_closing(&self) -> bool {
+        self.core.closing.load(Ordering::SeqCst)
+    }
+
+    // Returns false if it was already closing.
+    fn close(&self) -> bool {
+        if self.core.closing.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        self.core.heartbeat.stop();
+        if let Some(ref on_close) = self.on_close {
+            on_close();
+        }
+        true
+    }
+
+    /// Fails every pending method and live subscription, and any called or
+    /// made from now on until the next `reconnect`.
+    pub(crate) fn fail_pending(&self) {
+        self.core.fail_pending();
+    }
+
+    fn spawn(core: &Core, client: Client<Stream>, on_crash: Arc<Fn() + Sync + Send>)
+    -> Result<(ConnectionHandle, AtomicSender<String>), DdpConnError> {
+        let (mut receiver, mut sender) = client.split().map_err(|e| DdpConnError::IoError(e))?;
+        // Closing on purpose isn't a crash.
+        let closing = core.closing.clone();
+        let sreport = Arc::new(OnDrop(Arc::new(move || if !closing.load(Ordering::SeqCst) {
+            on_crash();
+        })));
+        let rreport = sreport.clone();
+
+        // Before the outbox goes live, so nothing still watching the last socket
+        // can close it.
+        Heartbeat::start(&core.heartbeat, &core.outbox, &core.timer);
+        // Held until both threads are registered, so neither can exit before.
+        let running = core.running.clone();
+        let mut threads = running.threads.lock().unwrap();
+
+        let (tx, rx) = channel();
+        let (closing, exits) = (core.closing.clone(), core.running.clone());
+        let core = core.clone();
 
         let receiving = thread::spawn(move || {
             let mut handlers: HashMap<&'static str, Box<Fn(&Core, &Value)>> = HashMap::new();
 
             handlers.insert("ping",    Box::new(Core::handle_ping));
+            handlers.insert("pong",    Box::new(Core::handle_pong));
             handlers.insert("result",  Box::new(Core::handle_result));
             handlers.insert("added",   Box::new(Core::handle_added));
             handlers.insert("changed", Box::new(Core::handle_changed));
             handlers.insert("removed", Box::new(Core::handle_removed));
             handlers.insert("ready",   Box::new(Core::handle_ready));
             handlers.insert("nosub",   Box::new(Core::handle_nosub));
+            handlers.insert("updated", Box::new(Core::handle_updated));
 
             for message in receiver.incoming_messages() {
                 match message {
                     Ok(OwnedMessage::Text(text)) => {
+                        core.heartbeat.seen();
                         let decoded = serde_json::from_str(&text).ok();
                         let message: Option<String> = decoded.as_ref().and_then(|data: &serde_json::Value|
                             data["msg"].as_str().and_then(|s|
@@ -83,6 +285,10 @@ impl Connection {
                     _ => break
                 }
             }
+            // Hang up on the sending thread, anything sent from now on is queued
+            // or dropped.
+            core.outbox.close();
+            core.running.exited();
             sreport.consume();
         });
 
@@ -92,23 +298,91 @@ impl Connection {
                     break;
                 }
             }
+            if closing.load(Ordering::SeqCst) {
+                sender.send_message(&Message::close()).ok();
+            }
+            // Unblocks the receiving thread if it is still waiting on the socket.
+            sender.shutdown_all().ok();
+            exits.exited();
             rreport.consume();
         });
+        threads.push(receiving.thread().id());
+        threads.push(sending.thread().id());
 
-        Ok((Connection {
-            core:       client_core,
-            session_id: session_id,
-            version:    VERSIONS[v_index],
-        }, ConnectionHandle {
+        Ok((ConnectionHandle {
             sending:   sending,
             receiving: receiving,
-        }))
+        }, tx))
     }
 
     #[inline]
     pub fn call(&self, method: &str, params: Option<&Vec<&Ejson>>,
-                callback: Box<FnMut(Result<&Ejson, &Ejson>) + Send + 'static>) {
-        self.core.methods.lock().unwrap().send(method, params, callback);
+                callback: Box<FnMut(Result<&Ejson, &DdpError>) + Send + 'static>) -> MethodHandle {
+        self.apply(method, params, MethodCallbacks::new().on_result(callback))
+    }
+
+    /// Runs the stub registered for `method` first, if there is one.
+    pub fn apply(&self, method: &str, params: Option<&Vec<&Ejson>>, callbacks: MethodCallbacks) -> MethodHandle {
+        let timeout = callbacks.timeout;
+        let (id, seed) = {
+            let mut methods = self.core.methods.lock().unwrap();
+            (methods.rng.id(), methods.rng.secret())
+        };
+        let stub = self.core.stubs.lock().unwrap().get(method).cloned();
+        let layers = match stub {
+            Some(stub) => {
+                let mut context = StubContext::new(self, &id, &seed);
+                stub(&mut context, params.map_or(&[][..], |params| &params[..]));
+                context.into_layers()
+            },
+            None => Vec::new(),
+        };
+        // Only now, as whatever the stub sends needs room of its own first.
+        self.core.outbox.reserve();
+        let handle = MethodHandle {
+            id:      id.clone(),
+            seed:    seed.clone(),
+            methods: Arc::downgrade(&self.core.methods),
+        };
+        let dropped = self.core.methods.lock().unwrap().send(id, seed, method, params, callbacks, layers);
+        fail_dropped(&self.core.methods, &self.core.subs, dropped);
+        if let Some(timeout) = timeout {
+            let handle = handle.clone();
+            self.core.timer.after(timeout, move || handle.expire());
+        }
+        handle
+    }
+
+    /// Registers a stub for `method`, replacing any previous one. It runs on
+    /// the calling thread whenever `method` is called, simulating the method
+    /// against the local cache. Its writes show straight away, and hide what
+    /// the server publishes for the same documents until the server says the
+    /// method's own writes are in, see `StubContext`.
+    ///
+    /// Only `call` and `apply` run stubs, the writes of `Collection::insert`
+    /// and friends aren't simulated.
+    pub fn stub<F>(&self, method: &str, f: F)
+    where F: Fn(&mut StubContext, &[&Ejson]) + Send + Sync + 'static {
+        self.core.stubs.lock().unwrap().insert(method.to_string(), Arc::new(f));
+    }
+
+    /// Subscribes to the publication `name`, independent of any collection.
+    pub fn subscribe(&self, name: &str, params: Option<&Vec<&Ejson>>) -> Subscription {
+        self.core.outbox.reserve();
+        Subscription::new(&self.core.methods, &self.core.subs, name, params)
+    }
+
+    /// Holds up to `limit` methods and subscriptions made while disconnected and
+    /// sends them in order after reconnecting. `overflow` says what happens once
+    /// it's full. Without this, which is the default, they're still replayed but
+    /// there's no bound. `None` turns it off again.
+    pub fn queue_offline(&self, limit: Option<usize>, overflow: Overflow) {
+        self.core.outbox.queue(limit, overflow);
+    }
+
+    /// How many messages are waiting for the connection to come back.
+    pub fn queued(&self) -> usize {
+        self.core.outbox.len()
     }
 
     pub fn mongo(&self, collection: String) -> Arc<Collection> {
@@ -119,26 +393,28 @@ impl Connection {
         callbacks.clone()
     }
 
-    pub fn session(&self) -> &str {
-        &self.session_id
+    pub fn session(&self) -> String {
+        self.session_id.lock().unwrap().clone()
     }
 
     pub fn version(&self) -> &'static str {
-        &self.version
+        *self.version.lock().unwrap()
     }
 
-    fn handshake(url: &Url) -> Result<Client<TcpStream>, DdpConnError> {
+    fn handshake(url: &Url, tls: Option<&TlsConnector>) -> Result<Client<Stream>, DdpConnError> {
+        let stream = Stream::open(url, tls)?;
         // Handshake with the server
         Ok(ClientBuilder::new(&url.to_string())
             .map_err(|e| DdpConnError::Parse(e))?
-            .connect_insecure().map_err(|e| DdpConnError::Network(e) )?)
+            .connect_on(stream).map_err(|e| DdpConnError::Network(e) )?)
     }
 
-    fn negotiate(client: &mut Client<TcpStream>, version: &'static str) -> Result<NegotiateResp, DdpConnError> {
-        let request = Connect::new(version);
+    fn negotiate(client: &mut Client<Stream>, version: &'static str, session: Option<String>)
+    -> Result<NegotiateResp, DdpConnError> {
+        let request = Connect::new(version, session);
         let request = Message::text(serde_json::to_string(&request).unwrap());
 
-        try!( client.send_message(&request).map_err(|e| DdpConnError::Network(e)) );
+        client.send_message(&request).map_err(|e| DdpConnError::Network(e))?;
 
         while let Ok(OwnedMessage::Text(plaintext)) = client.recv_message() {
             let decoded: Option<serde_json::Value> = serde_json::from_str(&plaintext).ok();
@@ -170,12 +446,13 @@ impl Connection {
         Err(DdpConnError::MalformedPacket)
     }
 
-    fn connect(url: &Url) -> Result<(Client<TcpStream>, String, usize), DdpConnError> {
+    fn connect(url: &Url, tls: Option<&TlsConnector>, session: Option<String>)
+    -> Result<(Client<Stream>, String, usize), DdpConnError> {
         let mut v_index = 0;
 
         loop {
-            let mut client = try!( Connection::handshake(url) );
-            match Connection::negotiate(&mut client, VERSIONS[v_index]) {
+            let mut client = Connection::handshake(url, tls)?;
+            match Connection::negotiate(&mut client, VERSIONS[v_index], session.clone()) {
                 Err(e) => return Err(e),
                 Ok(NegotiateResp::SessionId(session)) => return Ok((client, session, v_index)),
                 Ok(NegotiateResp::Version(server_version)) => {
@@ -196,25 +473,86 @@ struct Core {
     methods:    Arc<Mutex<Methods>>,
     mongos:     Arc<Mutex<HashMap<String, Arc<Collection>>>>,
     subs:       Arc<Mutex<Subscriptions>>,
-    transfer:   Arc<Mutex<AtomicSender<String>>>,
+    outbox:     Arc<Outbox>,
+    stubs:      Arc<Mutex<HashMap<String, Stub>>>,
+    timer:      Arc<Timer>,
+    heartbeat:  Arc<Heartbeat>,
+    status:     Arc<Statuses>,
+    // Set by `disconnect`, or dropping the connection, for good.
+    closing:    Arc<AtomicBool>,
+    // Held while `reconnect` brings a socket up and while `disconnect` tears
+    // one down, so neither can undo the other.
+    resuming:   Arc<Mutex<()>>,
+    running:    Arc<Running>,
 }
 
 impl Core {
+    // Whatever `first` sends goes out before anything is replayed. Returns
+    // whether every restarted subscription has already settled, in which case
+    // it's up to the caller to call `resubscribed`.
+    fn resume<F: FnOnce()>(&self, sender: AtomicSender<String>, first: F) -> bool {
+        for mongo in self.mongos.lock().unwrap().values() {
+            mongo.docs.lock().unwrap().reset();
+        }
+        let (mut replays, settled) = self.subs.lock().unwrap().resume();
+        {
+            let mut methods = self.methods.lock().unwrap();
+            methods.closed = false;
+            replays.extend(methods.resume());
+        }
+        settle(&self.methods);
+        self.outbox.attach(sender.clone());
+        first();
+        self.outbox.open(sender, replays);
+        settled
+    }
+
+    // Every subscription restarted by `resume` has settled.
+    fn resubscribed(&self) {
+        for mongo in self.mongos.lock().unwrap().values() {
+            mongo.flush_stale();
+        }
+        self.methods.lock().unwrap().resubscribed();
+        settle(&self.methods);
+    }
+
+    fn fail_pending(&self) {
+        self.methods.lock().unwrap().fail_all();
+        settle(&self.methods);
+        self.subs.lock().unwrap().fail_all();
+        self.outbox.shut();
+    }
+
     fn handle_ping(&self, message: &Value) {
-        self.transfer.lock().unwrap().send(Pong::text(message.id())).unwrap();
+        self.outbox.send_live(Pong::text(message.id()));
+    }
+
+    fn handle_pong(&self, message: &Value) {
+        self.heartbeat.pong(message.id());
     }
 
     fn handle_result(&self, message: &Value) {
         if let Some(ref id) = message.id() {
             let result = match (message.get("error"), message.get("result")) {
-                (Some(e), None)    => Err(e),
+                (Some(e), None)    => Err(DdpError::from(e)),
                 (None,    Some(r)) => Ok(r),
                 _                  => return,
             };
-            self.methods.lock().unwrap().apply(id, result);
+            self.methods.lock().unwrap().apply(id, result.as_ref().map(|&r| r));
+            settle(&self.methods);
         }
     }
 
+    fn handle_updated(&self, message: &Value) {
+        if let Some(ids) = message.methods().and_then(|m| m.as_array()) {
+            let mut methods = self.methods.lock().unwrap();
+            for id in ids.iter().filter_map(|id| id.as_str()) {
+                methods.data_visible(id);
+            }
+        }
+        settle(&self.methods);
+    }
+
     fn handle_added(&self, message: &Value) {
         let lock = self.mongos.lock().unwrap();
         let collection = self.collection(&lock, message);
@@ -258,15 +596,22 @@ impl Core {
             Some(idies)
         });
         if let Some(ids) = ids {
-            self.subs.lock().unwrap().notify(Ok(ids));
+            if self.subs.lock().unwrap().notify(Ok(ids)) {
+                self.resubscribed();
+            }
         }
     }
 
     fn handle_nosub(&self, message: &Value) {
         let id = message.id();
-        let error = message.error();
-        if let (Some(error), Some(id)) = (error, id) {
-            self.subs.lock().unwrap().notify(Err((id, error)));
+        let error = message.error().map(DdpError::from);
+        let settled = match (error, id) {
+            (Some(error), Some(id)) => self.subs.lock().unwrap().notify(Err((id, &error))),
+            (None,        Some(id)) => self.subs.lock().unwrap().stopped(id),
+            _ => false,
+        };
+        if settled {
+            self.resubscribed();
         }
     }
 
@@ -279,43 +624,330 @@ impl Core {
 }
 
 struct Methods {
-    outgoing:        Arc<Mutex<AtomicSender<String>>>,
-    pending_methods: HashMap<String, MethodCallback>,
+    outgoing:        Arc<Outbox>,
+    pending_methods: HashMap<String, PendingMethod>,
+    settling:        Vec<(String, Vec<Arc<Collection>>)>,
+    // Callbacks to run once the lock is released, in order.
+    due:             Vec<Due>,
+    // Answered before the socket went, and made visible once the restarted
+    // subscriptions are ready.
+    answered:        Vec<String>,
+    closed:          bool,
+    sent:            u64,
     rng: Random,
 }
 
 impl Methods {
-    fn new(outgoing: Arc<Mutex<AtomicSender<String>>>) -> Self {
+    fn new(outgoing: Arc<Outbox>) -> Self {
         Methods {
             rng:             Random::new(),
             pending_methods: HashMap::new(),
+            settling:        Vec::new(),
+            due:             Vec::new(),
+            answered:        Vec::new(),
+            closed:          false,
+            sent:            0,
             outgoing:        outgoing,
         }
     }
 
-    fn send(&mut self, method: &str, params: Option<&Vec<&Ejson>>,
-            callback: Box<FnMut(Result<&Ejson, &Ejson>) + Send + 'static>) {
-        let id = self.rng.id();
-        let method = Method::text(&id, method, params);
-        self.outgoing.lock().unwrap().send(method).unwrap();
-        self.pending_methods.insert(id, callback);
+    // Returns the id of anything the outbox dropped that isn't a method.
+    // `layers` are the collections a stub wrote to, settled along with the method.
+    fn send(&mut self, id: String, seed: String, method: &str, params: Option<&Vec<&Ejson>>,
+            callbacks: MethodCallbacks, layers: Vec<Arc<Collection>>) -> Option<String> {
+        let method = Method::text(&id, method, params, Some(&seed));
+        let sent = self.outgoing.send(&id, method.clone());
+        self.sent += 1;
+        self.pending_methods.insert(id.clone(), PendingMethod {
+            message:   method,
+            order:     self.sent,
+            callbacks: callbacks,
+            result:    None,
+            updated:   false,
+            layers:    layers,
+        });
+
+        if self.closed {
+            self.outgoing.cancel(&id);
+            self.fail(&id, &disconnected_error());
+            return None;
+        }
+        match sent {
+            Sent::Rejected => {
+                self.fail(&id, &DdpError::local("outbox-full", "Too many messages waiting to be sent"));
+                None
+            },
+            Sent::Dropped(dropped) => {
+                if self.fail(&dropped, &dropped_error()) { None } else { Some(dropped) }
+            },
+            _ => None,
+        }
+    }
+
+    // Nothing will come of the method, so neither will its `updated`.
+    fn fail(&mut self, id: &str, error: &DdpError) -> bool {
+        let layers = match self.pending_methods.get_mut(id) {
+            Some(method) => {
+                if let Some(callback) = method.callbacks.result.take() {
+                    self.due.push(Due::Result(callback, Err(error.clone())));
+                }
+                method.result = Some(Err(error.clone()));
+                method.layers.drain(..).collect()
+            },
+            None => return false,
+        };
+        self.settling.push((id.to_string(), layers));
+        self.complete(id);
+        true
+    }
+
+    // Forgets the method without calling anything back.
+    fn cancel(&mut self, id: &str) -> bool {
+        match self.pending_methods.remove(id) {
+            Some(method) => {
+                self.outgoing.cancel(id);
+                self.settling.push((id.to_string(), method.layers));
+                true
+            },
+            None => false,
+        }
     }
 
-    fn apply(&mut self, id: &str, response: Result<&Ejson, &Ejson>) {
+    // Times the method out, unless its result is already in.
+    fn expire(&mut self, id: &str) {
+        if self.pending_methods.get(id).map_or(false, |method| method.result.is_none()) {
+            self.outgoing.cancel(id);
+            self.fail(id, &DdpError::local("timeout", "Timed out waiting for the server"));
+        }
+    }
+
+    // For when the connection is gone for good. Methods that already have a
+    // result complete with it, since their `updated` won't come either.
+    fn fail_all(&mut self) {
+        self.closed = true;
+        self.answered.clear();
+        let mut pending: Vec<(String, u64)> = self.pending_methods.iter()
+            .map(|(id, method)| (id.clone(), method.order))
+            .collect();
+        pending.sort_by_key(|&(_, order)| order);
+        for (id, _) in pending {
+            self.outgoing.cancel(&id);
+            let layers = match self.pending_methods.get_mut(&id) {
+                Some(ref mut method) if method.result.is_some() => method.layers.drain(..).collect(),
+                _ => {
+                    self.fail(&id, &disconnected_error());
+                    continue;
+                },
+            };
+            self.settling.push((id.clone(), layers));
+            self.complete(&id);
+        }
+    }
+
+    fn apply(&mut self, id: &str, response: Result<&Ejson, &DdpError>) {
+        let done = match self.pending_methods.get_mut(id) {
+            Some(method) => {
+                let response = response.map(|r| r.clone()).map_err(|e| e.clone());
+                if let Some(callback) = method.callbacks.result.take() {
+                    self.due.push(Due::Result(callback, response.clone()));
+                }
+                method.result = Some(response);
+                method.updated || (!method.callbacks.waits_for_update() && method.layers.is_empty())
+            },
+            None => false,
+        };
+        if done {
+            self.complete(id);
+        }
+    }
+
+    fn data_visible(&mut self, id: &str) {
+        let done = match self.pending_methods.get_mut(id) {
+            Some(method) => {
+                if let Some(callback) = method.callbacks.updated.take() {
+                    self.due.push(Due::Updated(callback));
+                }
+                method.updated = true;
+                let layers = method.layers.drain(..).collect();
+                self.settling.push((id.to_string(), layers));
+                method.result.is_some()
+            },
+            None => false,
+        };
+        if done {
+            self.complete(id);
+        }
+    }
+
+    fn complete(&mut self, id: &str) {
         if let Some(method) = self.pending_methods.remove(id) {
-            let mut method: MethodCallback = method;
-            method(response);
+            if let (Some(callback), Some(result)) = (method.callbacks.complete, method.result) {
+                self.due.push(Due::Result(callback, result));
+            }
         }
     }
+
+    // Anything without a result may never have reached the server, so send it
+    // again with the same id, oldest first, like Meteor does. Writes from methods
+    // that already returned come back through the restarted subscriptions, so
+    // they wait for `resubscribed`.
+    fn resume(&mut self) -> Vec<(String, String)> {
+        self.answered = self.pending_methods.iter()
+            .filter(|&(_, method)| method.result.is_some())
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        let mut pending: Vec<(&String, &PendingMethod)> = self.pending_methods.iter()
+            .filter(|&(_, method)| method.result.is_none())
+            .collect();
+        pending.sort_by_key(|&(_, method)| method.order);
+        pending.into_iter().map(|(id, method)| (id.clone(), method.message.clone())).collect()
+    }
+
+    fn resubscribed(&mut self) {
+        for id in mem::take(&mut self.answered) {
+            self.data_visible(&id);
+        }
+    }
+}
+
+// A method callback held back until the methods lock is released, since it
+// may well call another method.
+enum Due {
+    Result(MethodCallback, Result<Ejson, DdpError>),
+    Updated(UpdatedCallback),
+}
+
+impl Due {
+    fn run(self) {
+        match self {
+            Due::Result(mut callback, result) => callback(result.as_ref()),
+            Due::Updated(mut callback)        => callback(),
+        }
+    }
+}
+
+struct PendingMethod {
+    message:   String,
+    order:     u64,
+    callbacks: MethodCallbacks,
+    result:    Option<Result<Ejson, DdpError>>,
+    updated:   bool,
+    layers:    Vec<Arc<Collection>>,
+}
+
+/// A method that has been sent, or queued to be. Its `randomSeed` is what the
+/// server generates ids from, so the same ids can be had here.
+#[derive(Clone, Debug)]
+pub struct MethodHandle {
+    id:      String,
+    seed:    String,
+    methods: Weak<Mutex<Methods>>,
+}
+
+impl MethodHandle {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn random_seed(&self) -> &str {
+        &self.seed
+    }
+
+    pub fn random_stream(&self) -> RandomStream {
+        RandomStream::new(self.seed.clone())
+    }
+
+    /// Forgets the method: none of its callbacks are called from now on, and
+    /// its stub's writes are dropped. It's taken back if it's still waiting
+    /// to be sent, otherwise the server may well run it anyway. Returns
+    /// whether it was still pending.
+    pub fn cancel(&self) -> bool {
+        let methods = match self.methods.upgrade() {
+            Some(methods) => methods,
+            None          => return false,
+        };
+        let cancelled = methods.lock().unwrap().cancel(&self.id);
+        settle(&methods);
+        cancelled
+    }
+
+    fn expire(&self) {
+        if let Some(methods) = self.methods.upgrade() {
+            methods.lock().unwrap().expire(&self.id);
+            settle(&methods);
+        }
+    }
+}
+
+/// Callbacks for the two halves of a method's completion, following Meteor:
+/// `on_result` fires when the `result` message arrives, `on_updated` once the
+/// server's writes are visible to this client, and `on_complete` after both.
+pub struct MethodCallbacks {
+    result:   Option<MethodCallback>,
+    updated:  Option<UpdatedCallback>,
+    complete: Option<MethodCallback>,
+    timeout:  Option<Duration>,
+}
+
+impl MethodCallbacks {
+    pub fn new() -> Self {
+        MethodCallbacks {
+            result:   None,
+            updated:  None,
+            complete: None,
+            timeout:  None,
+        }
+    }
+
+    pub fn on_result<F>(mut self, f: F) -> Self
+    where F: FnMut(Result<&Ejson, &DdpError>) + Send + 'static {
+        self.result = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_updated<F>(mut self, f: F) -> Self
+    where F: FnMut() + Send + 'static {
+        self.updated = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_complete<F>(mut self, f: F) -> Self
+    where F: FnMut(Result<&Ejson, &DdpError>) + Send + 'static {
+        self.complete = Some(Box::new(f));
+        self
+    }
+
+    /// Fails the method with a `"timeout"` error unless its result arrives
+    /// within `timeout`. It's taken back if it hasn't been sent by then.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn waits_for_update(&self) -> bool {
+        self.updated.is_some() || self.complete.is_some()
+    }
+}
+
+impl Default for MethodCallbacks {
+    fn default() -> Self {
+        MethodCallbacks::new()
+    }
 }
 
 pub struct Collection {
     remove_listeners: Arc<Mutex<HashMap<u32, Box<Fn(&str) + Send + 'static>>>>,
     insert_listeners: Arc<Mutex<HashMap<u32, Box<Fn(&str, Option<&Ejson>) + Send + 'static>>>>,
     change_listeners: Arc<Mutex<HashMap<u32, Box<Fn(&str, Option<&Ejson>, Option<&Ejson>) + Send + 'static>>>>,
+    docs:             Arc<Mutex<Documents>>,
+    observers:        Arc<Mutex<HashMap<u32, LiveQuery>>>,
     methods:          Arc<Mutex<Methods>>,
     subs:             Arc<Mutex<Subscriptions>>,
-    id:               Arc<Mutex<Option<String>>>,
+    outbox:           Arc<Outbox>,
+    events:           Arc<Mutex<Vec<AtomicSender<CollectionEvent>>>>,
+    subscriptions:    Arc<Mutex<Vec<Subscription>>>,
+    waiting:          Arc<Mutex<Vec<ReadyCallback>>>,
     count:            Arc<Mutex<u32>>,
     name:             String,
     ops:              OpNames,
@@ -333,31 +965,174 @@ impl Collection {
             remove_listeners: Arc::new(Mutex::new(HashMap::new())),
             insert_listeners: Arc::new(Mutex::new(HashMap::new())),
             change_listeners: Arc::new(Mutex::new(HashMap::new())),
+            docs:             Arc::new(Mutex::new(Documents::new())),
+            observers:        Arc::new(Mutex::new(HashMap::new())),
             methods:          core.methods.clone(),
             subs:             core.subs.clone(),
-            id:               Arc::new(Mutex::new(None)),
+            outbox:           core.outbox.clone(),
+            events:           Arc::new(Mutex::new(Vec::new())),
+            subscriptions:    Arc::new(Mutex::new(Vec::new())),
+            waiting:          Arc::new(Mutex::new(Vec::new())),
             count:            Arc::new(Mutex::new(0)),
             name:             name,
             ops:              ops,
         }
     }
 
+    // Documents a stub wrote to only change underneath, until it settles.
     fn notify_remove(&self, id: &str) {
+        let before = {
+            let mut docs = self.docs.lock().unwrap();
+            let before = docs.remove(id);
+            if docs.is_simulated(id) { None } else { before }
+        };
+        if before.is_some() {
+            self.removed(id, before);
+        }
+    }
+
+    fn removed(&self, id: &str, before: Option<Ejson>) {
         for listener in self.remove_listeners.lock().unwrap().values() {
             listener(id);
         }
+        emit(&self.events, CollectionEvent::Removed { id: id.to_string() });
+        self.refresh_observers(id, before);
     }
 
+    // A document that is already cached, because another subscription publishes
+    // it or it survived a reconnect, shows up as a change instead.
     fn notify_insert(&self, id: &str, fields: Option<&Ejson>) {
+        let (before, after) = {
+            let mut docs = self.docs.lock().unwrap();
+            let before = docs.add(id, fields);
+            if docs.is_simulated(id) {
+                return;
+            }
+            (before, docs.get(id).cloned())
+        };
+        match (before, after) {
+            (Some(before), Some(after)) => self.changed(id, before, &after),
+            _ => self.added(id, fields),
+        }
+    }
+
+    fn added(&self, id: &str, fields: Option<&Ejson>) {
         for listener in self.insert_listeners.lock().unwrap().values() {
             listener(id, fields);
         }
+        emit(&self.events, CollectionEvent::Added {
+            id:     id.to_string(),
+            fields: fields.cloned(),
+        });
+        self.refresh_observers(id, None);
+    }
+
+    // Reports whatever differs between the two versions, if anything does.
+    fn changed(&self, id: &str, before: Ejson, after: &Ejson) {
+        let (fields, cleared) = diff(after, &before);
+        if fields.is_none() && cleared.is_none() {
+            return;
+        }
+        for listener in self.change_listeners.lock().unwrap().values() {
+            listener(id, fields.as_ref(), cleared.as_ref());
+        }
+        emit(&self.events, CollectionEvent::Changed {
+            id:      id.to_string(),
+            fields:  fields,
+            cleared: cleared,
+        });
+        self.refresh_observers(id, Some(before));
+    }
+
+    // For when a document's visible version is swapped wholesale, by a stub
+    // writing it or settling.
+    fn replaced(&self, id: &str, before: Option<Ejson>, after: Option<Ejson>) {
+        match (before, after) {
+            (Some(before), Some(after)) => self.changed(id, before, &after),
+            (Some(before), None)        => self.removed(id, Some(before)),
+            (None, Some(mut after))     => {
+                if let Some(doc) = after.as_object_mut() {
+                    doc.remove("_id");
+                }
+                self.added(id, Some(&after));
+            },
+            (None, None) => {},
+        }
+    }
+
+    /// Shows `doc` in place of the document with this id until the method
+    /// `method` settles, `None` removing it.
+    pub(crate) fn simulate(&self, method: &str, id: &str, doc: Option<Ejson>) {
+        let before = self.docs.lock().unwrap().simulate(method, id, doc.clone());
+        self.replaced(id, before, doc);
+    }
+
+    // Drops what `method`'s stub wrote, showing the server's version again.
+    fn settle(&self, method: &str) {
+        let settled: Vec<_> = {
+            let mut docs = self.docs.lock().unwrap();
+            docs.settle(method).into_iter()
+                .map(|(id, before)| {
+                    let after = docs.get(&id).cloned();
+                    (id, before, after)
+                })
+                .collect()
+        };
+        for (id, before, after) in settled {
+            self.replaced(&id, before, after);
+        }
+    }
+
+    fn flush_stale(&self) {
+        let stale = self.docs.lock().unwrap().flush();
+        for (id, before) in stale {
+            self.removed(&id, Some(before));
+        }
     }
 
     fn notify_change(&self, id: &str, fields: Option<&Ejson>, cleared: Option<&Ejson>) {
+        let before = {
+            let mut docs = self.docs.lock().unwrap();
+            let before = docs.get(id).cloned();
+            docs.change(id, fields, cleared);
+            if docs.is_simulated(id) {
+                return;
+            }
+            before
+        };
         for listener in self.change_listeners.lock().unwrap().values() {
             listener(id, fields, cleared);
         }
+        emit(&self.events, CollectionEvent::Changed {
+            id:      id.to_string(),
+            fields:  fields.cloned(),
+            cleared: cleared.cloned(),
+        });
+        self.refresh_observers(id, before);
+    }
+
+    // The cache lock is released before any observer callback runs, so they
+    // are free to read the collection.
+    fn refresh_observers(&self, id: &str, before: Option<Ejson>) {
+        let mut observers = self.observers.lock().unwrap();
+        let changes: Vec<_> = {
+            let docs = self.docs.lock().unwrap();
+            observers.iter_mut()
+                .map(|(&count, observer)| (count, observer.refresh(&docs, Some((id, before.as_ref())))))
+                .collect()
+        };
+        for (count, changes) in changes {
+            observers[&count].dispatch(&changes);
+        }
+    }
+
+    fn start_observer(&self, mut observer: LiveQuery) -> ListenerId {
+        let count = self.increment();
+        let mut observers = self.observers.lock().unwrap();
+        let changes = observer.refresh(&self.docs.lock().unwrap(), None);
+        observer.dispatch(&changes);
+        observers.insert(count, observer);
+        ListenerId(Listener::Observer, count)
     }
 
     fn increment(&self) -> u32 {
@@ -387,9 +1162,35 @@ impl Collection {
         ListenerId(Listener::Changed, count)
     }
 
+    /// Every change to the collection from now on, for reading on a thread of your own.
+    pub fn events(&self) -> Receiver<CollectionEvent> {
+        let (tx, rx) = channel();
+        self.events.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Called once the latest subscription made with `subscribe` is ready, or
+    /// the next one if there is none yet.
     pub fn on_ready<F>(&self, f: F)
-    where F: FnMut(Result<(), &Ejson>) + Send + 'static {
-        self.subs.lock().unwrap().add_listener(&mut *self.id.lock().unwrap(), f);
+    where F: FnMut(Result<(), &DdpError>) + Send + 'static {
+        match self.subscriptions.lock().unwrap().last() {
+            Some(sub) => sub.on_ready(f),
+            None      => self.waiting.lock().unwrap().push(Box::new(f)),
+        }
+    }
+
+    /// Watches the documents matching `query`, calling back with whole
+    /// documents. Every current match is reported as added straight away.
+    /// Positions are tracked, and moves reported, only when ordered callbacks
+    /// are given, as in Meteor.
+    /// Callbacks must not start or stop observers on this collection.
+    pub fn observe(&self, query: &Query, callbacks: Observe) -> ListenerId {
+        self.start_observer(LiveQuery::observe(query.clone(), callbacks))
+    }
+
+    /// Like `observe`, but only the fields that changed are reported.
+    pub fn observe_changes(&self, query: &Query, callbacks: ObserveChanges) -> ListenerId {
+        self.start_observer(LiveQuery::observe_changes(query.clone(), callbacks))
     }
 
     pub fn clear_listener(&self, id: ListenerId) {
@@ -397,110 +1198,274 @@ impl Collection {
             ListenerId(Listener::Inserted, c) => { self.insert_listeners.lock().unwrap().remove(&c); },
             ListenerId(Listener::Changed,  c) => { self.change_listeners.lock().unwrap().remove(&c); },
             ListenerId(Listener::Removed,  c) => { self.remove_listeners.lock().unwrap().remove(&c); },
+            ListenerId(Listener::Observer, c) => { self.observers.lock().unwrap().remove(&c); },
         }
     }
 
-    pub fn insert<F>(&self, record: &Ejson, callback: F)
-    where F: FnMut(Result<&Ejson, &Ejson>) + Send + 'static {
-        self.methods.lock().unwrap().send(&self.ops.insert, Some(&vec![&record]), Box::new(callback));
+    fn send(&self, op: &str, params: Option<&Vec<&Ejson>>, seed: Option<String>, callbacks: MethodCallbacks) {
+        self.outbox.reserve();
+        let dropped = {
+            let mut methods = self.methods.lock().unwrap();
+            let id = methods.rng.id();
+            let seed = seed.unwrap_or_else(|| methods.rng.secret());
+            methods.send(id, seed, op, params, callbacks, Vec::new())
+        };
+        fail_dropped(&self.methods, &self.subs, dropped);
+    }
+
+    /// Returns the document's id. Without an `_id` one is generated from the
+    /// method's `randomSeed`, the same one the server will pick. `None` if
+    /// `record` isn't a document.
+    pub fn insert<F>(&self, record: &Ejson, callback: F) -> Option<String>
+    where F: FnMut(Result<&Ejson, &DdpError>) + Send + 'static {
+        let mut record = record.clone();
+        let seed = self.methods.lock().unwrap().rng.secret();
+        let id = match record.as_object_mut() {
+            Some(doc) => match doc.get("_id") {
+                Some(id) => Some(id_string(id)),
+                None     => {
+                    let id = RandomStream::new(&seed[..]).sequence(&format!("/collection/{}", self.name)).id();
+                    doc.insert("_id".to_string(), json!(id));
+                    Some(id)
+                },
+            },
+            None => None,
+        };
+        self.send(&self.ops.insert, Some(&vec![&record]), Some(seed), MethodCallbacks::new().on_result(callback));
+        id
     }
 
     pub fn update<F>(&self, selector: &Ejson, modifier: &Ejson, callback: F)
-    where F: FnMut(Result<&Ejson, &Ejson>) + Send + 'static {
-        self.methods.lock().unwrap().send(&self.ops.update, Some(&vec![&selector, &modifier]), Box::new(callback));
+    where F: FnMut(Result<&Ejson, &DdpError>) + Send + 'static {
+        self.send(&self.ops.update, Some(&vec![&selector, &modifier]), None, MethodCallbacks::new().on_result(callback));
     }
 
     pub fn upsert<F>(&self, selector: &Ejson, modifier: &Ejson, callback: F)
-    where F: FnMut(Result<&Ejson, &Ejson>) + Send + 'static {
-        self.methods.lock().unwrap().send(&self.ops.upsert, Some(&vec![&selector, &modifier]), Box::new(callback));
+    where F: FnMut(Result<&Ejson, &DdpError>) + Send + 'static {
+        self.send(&self.ops.upsert, Some(&vec![&selector, &modifier]), None, MethodCallbacks::new().on_result(callback));
     }
 
     pub fn remove<F>(&self, selector: &Ejson, callback: F)
-    where F: FnMut(Result<&Ejson, &Ejson>) + Send + 'static {
-        self.methods.lock().unwrap().send(&self.ops.remove, Some(&vec![&selector]), Box::new(callback));
+    where F: FnMut(Result<&Ejson, &DdpError>) + Send + 'static {
+        self.send(&self.ops.remove, Some(&vec![&selector]), None, MethodCallbacks::new().on_result(callback));
     }
 
-    pub fn subscribe(&self) {
-        self.subs.lock().unwrap().sub(&self.name, &mut *self.id.lock().unwrap());
+    /// Subscribes to the publication named after this collection, without
+    /// params. Use `Connection::subscribe` for any other publication.
+    /// Each call starts a separate subscription with its own id.
+    pub fn subscribe(&self) -> Subscription {
+        self.outbox.reserve();
+        let sub = Subscription::new(&self.methods, &self.subs, &self.name, None);
+        for f in self.waiting.lock().unwrap().drain(..) {
+            sub.on_ready(f);
+        }
+        let (events, id) = (self.events.clone(), sub.id.clone());
+        sub.on_ready(move |result| if result.is_ok() {
+            emit(&events, CollectionEvent::Ready { subscription: id.clone() });
+        });
+        let (events, id) = (self.events.clone(), sub.id.clone());
+        sub.on_stop(move |error| {
+            emit(&events, CollectionEvent::NoSub { subscription: id.clone(), error: error.cloned() });
+        });
+        self.subscriptions.lock().unwrap().push(sub.clone());
+        sub
     }
 
+    /// Stops every subscription started with `subscribe`.
     pub fn unsubscribe(&self) {
-        let id_maybe = &mut *self.id.lock().unwrap();
-        if let &mut Some(ref mut id) = id_maybe {
-            self.subs.lock().unwrap().unsub(&id);
-        }
-        if id_maybe.is_some() {
-            *id_maybe = None;
+        for sub in self.subscriptions.lock().unwrap().drain(..) {
+            sub.stop();
         }
     }
 
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// The locally cached copy of the document with this id, including `_id`.
+    pub fn find_one(&self, id: &str) -> Option<Ejson> {
+        self.docs.lock().unwrap().get(id).cloned()
+    }
+
+    /// A snapshot of every cached document, ordered by id.
+    pub fn all(&self) -> Vec<Ejson> {
+        self.docs.lock().unwrap().values()
+    }
+
+    /// Every cached document matching `query`, see `Query` for what's supported.
+    pub fn find(&self, query: &Query) -> Vec<Ejson> {
+        query.run(self.docs.lock().unwrap().iter())
+    }
+
+    pub fn iter(&self) -> vec::IntoIter<Ejson> {
+        self.all().into_iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.docs.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 struct Subscriptions {
-    outgoing: Arc<Mutex<AtomicSender<String>>>,
-    subs:     HashMap<String, Vec<Box<FnMut(Result<(), &Ejson>) + Send + 'static>>>,
+    outgoing: Arc<Outbox>,
+    subs:     HashMap<String, Vec<ReadyCallback>>,
+    stops:    HashMap<String, Vec<StopCallback>>,
+    active:   HashMap<String, ActiveSub>,
+    // Resent after a reconnect and not ready yet.
+    resuming: HashSet<String>,
+    // Set by `fail_all`, until the next `resume`.
+    closed:   bool,
     rng:      Random,
 }
 
+struct ActiveSub {
+    name:   String,
+    params: Option<Vec<Ejson>>,
+    ready:  bool,
+}
+
 impl Subscriptions {
-    fn new(outgoing: Arc<Mutex<AtomicSender<String>>>) -> Self {
+    fn new(outgoing: Arc<Outbox>) -> Self {
         Subscriptions {
             outgoing: outgoing,
             subs:     HashMap::new(),
+            stops:    HashMap::new(),
+            active:   HashMap::new(),
+            resuming: HashSet::new(),
+            closed:   false,
             rng:      Random::new(),
         }
     }
 
-    fn notify(&mut self, subs: Result<Vec<&str>, (&str, &Ejson)>) {
+    // Returns true when this settles the last subscription resent by `resume`.
+    fn notify(&mut self, subs: Result<Vec<&str>, (&str, &DdpError)>) -> bool {
+        let settled = match subs {
+            Ok(ref successes) => successes.iter().any(|id| self.resuming.remove(*id)),
+            Err((id, _))      => self.resuming.remove(id),
+        };
         match subs {
             Ok(successes) => {
                 for id in successes.iter() {
+                    if let Some(sub) = self.active.get_mut(*id) {
+                        sub.ready = true;
+                    }
                     self.relay(id, Ok(()));
                 }
             },
-            Err((id, err)) => self.relay(id, Err(err)),
+            Err((id, err)) => {
+                self.active.remove(id);
+                self.relay(id, Err(err));
+                for mut callback in self.stops.remove(id).unwrap_or_default() {
+                    callback(Some(err));
+                }
+            },
         };
+        settled && self.resuming.is_empty()
+    }
+
+    // A `nosub` without an error, the server has stopped the subscription.
+    fn stopped(&mut self, id: &str) -> bool {
+        self.active.remove(id);
+        self.subs.remove(id);
+        for mut callback in self.stops.remove(id).unwrap_or_default() {
+            callback(None);
+        }
+        self.resuming.remove(id) && self.resuming.is_empty()
+    }
+
+    // Also returns the id of anything the outbox dropped that isn't a subscription.
+    fn sub(&mut self, name: &str, params: Option<&Vec<&Ejson>>) -> (String, Result<Option<String>, DdpError>) {
+        let id = self.rng.id();
+        if self.closed {
+            return (id, Err(disconnected_error()));
+        }
+        let sub_msg = Subscribe::text(&id, &name, params);
+        let sent = self.outgoing.send(&id, sub_msg);
+        if let Sent::Rejected = sent {
+            return (id, Err(DdpError::local("outbox-full", "Too many messages waiting to be sent")));
+        }
+        self.active.insert(id.clone(), ActiveSub {
+            name:   name.to_string(),
+            params: params.map(|params| params.iter().map(|&p| p.clone()).collect()),
+            ready:  false,
+        });
+
+        match sent {
+            Sent::Dropped(dropped) => {
+                if self.fail(&dropped, &dropped_error()) { (id, Ok(None)) } else { (id, Ok(Some(dropped))) }
+            },
+            _ => (id, Ok(None)),
+        }
     }
 
-    fn sub(&mut self, name: &str, id: &mut Option<String>) {
-        if id.is_none() {
-            self.create_profile(id);
+    fn fail(&mut self, id: &str, error: &DdpError) -> bool {
+        if !self.active.contains_key(id) {
+            return false;
         }
-        if let &mut Some(ref id) = id {
-            // TODO: Use the extra params.
-            let sub_msg = Subscribe::text(&id, &name, None);
-            self.outgoing.lock().unwrap().send(sub_msg).unwrap();
+        self.notify(Err((id, error)));
+        true
+    }
+
+    // A subscription that hasn't gone out yet is simply taken back. Otherwise
+    // there's no need to tell a server we aren't connected to.
+    fn unsub(&mut self, id: &str) -> Vec<StopCallback> {
+        self.outgoing.cancel(id);
+        self.outgoing.send_live(Unsubscribe::text(id));
+        self.active.remove(id);
+        self.subs.remove(id);
+        self.resuming.remove(id);
+        self.stops.remove(id).unwrap_or_default()
+    }
+
+    // Ends every subscription with a `"disconnected"` error, and any made from
+    // now on until the next `resume`.
+    fn fail_all(&mut self) {
+        self.closed = true;
+        let ids: Vec<String> = self.active.keys().cloned().collect();
+        for id in ids {
+            self.notify(Err((&id, &disconnected_error())));
         }
     }
 
-    fn unsub(&mut self, id: &str) {
-        let unsub_msg = Unsubscribe::text(id);
-        self.outgoing.lock().unwrap().send(unsub_msg).unwrap();
+    fn unsub_all(&mut self) -> Vec<StopCallback> {
+        let ids: Vec<String> = self.active.keys().cloned().collect();
+        ids.iter().flat_map(|id| self.unsub(id)).collect()
     }
 
-    fn add_listener<F>(&mut self, id: &mut Option<String>, f: F)
-    where F: FnMut(Result<(), &Ejson>) + Send + 'static {
-        if id.is_none() {
-            self.create_profile(id);
+    // Also returns true if nothing was resent, so there's nothing to wait for.
+    fn resume(&mut self) -> (Vec<(String, String)>, bool) {
+        let mut replays = Vec::new();
+        self.closed = false;
+        for (id, sub) in self.active.iter_mut() {
+            let params = sub.params.as_ref().map(|params| params.iter().collect());
+            replays.push((id.clone(), Subscribe::text(id, &sub.name, params.as_ref())));
+            sub.ready = false;
         }
-        if let &mut Some(ref id) = id {
-            if let Some(mut listeners) = self.subs.get_mut(id) {
-                listeners.push(Box::new(f));
-            }
+        self.resuming = self.active.keys().cloned().collect();
+        (replays, self.resuming.is_empty())
+    }
+
+    fn is_ready(&self, id: &str) -> bool {
+        self.active.get(id).map_or(false, |sub| sub.ready)
+    }
+
+    fn add_ready_listener(&mut self, id: &str, f: ReadyCallback) {
+        if self.active.contains_key(id) {
+            self.subs.entry(id.to_string()).or_insert_with(Vec::new).push(f);
         }
     }
 
-    fn create_profile(&mut self, key: &mut Option<String>) {
-        let id = self.rng.id();
-        // Don't clone
-        *key = Some(id.clone());
-        self.subs.insert(id, Vec::new());
+    fn add_stop_listener(&mut self, id: &str, f: StopCallback) {
+        if self.active.contains_key(id) {
+            self.stops.entry(id.to_string()).or_insert_with(Vec::new).push(f);
+        }
     }
 
-    fn relay(&mut self, id: &str, data: Result<(), &Ejson>) {
+    fn relay(&mut self, id: &str, data: Result<(), &DdpError>) {
         if let Some(mut callbacks) = self.subs.remove(id) {
             while let Some(mut callback) = callbacks.pop() {
                 callback(data.clone());
@@ -509,6 +1474,198 @@ impl Subscriptions {
     }
 }
 
+/// A subscription to a named publication, which may feed any number of
+/// collections. Dropping the handle leaves the subscription running, call
+/// `stop` to end it.
+#[derive(Clone)]
+pub struct Subscription {
+    id:    String,
+    name:  String,
+    subs:  Arc<Mutex<Subscriptions>>,
+    // Set once it has stopped, so listeners added late still hear about it.
+    ended: Arc<Mutex<Option<Option<DdpError>>>>,
+}
+
+impl Subscription {
+    fn new(methods: &Arc<Mutex<Methods>>, subs: &Arc<Mutex<Subscriptions>>, name: &str,
+           params: Option<&Vec<&Ejson>>) -> Self {
+        let ended = Arc::new(Mutex::new(None));
+        let (id, dropped) = {
+            let mut lock = subs.lock().unwrap();
+            let (id, sent) = lock.sub(name, params);
+            let record = ended.clone();
+            lock.add_stop_listener(&id, Box::new(move |error: Option<&DdpError>| {
+                *record.lock().unwrap() = Some(error.cloned());
+            }));
+            match sent {
+                Ok(dropped) => (id, dropped),
+                Err(error)  => {
+                    *ended.lock().unwrap() = Some(Some(error));
+                    (id, None)
+                },
+            }
+        };
+        fail_dropped(methods, subs, dropped);
+
+        Subscription {
+            id:    id,
+            name:  name.to_string(),
+            subs:  subs.clone(),
+            ended: ended,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the server has sent the initial batch of documents.
+    pub fn ready(&self) -> bool {
+        self.subs.lock().unwrap().is_ready(&self.id)
+    }
+
+    /// Called once the subscription is ready, or with the error if the server
+    /// refused it. Runs straight away if either already happened.
+    pub fn on_ready<F>(&self, mut f: F)
+    where F: FnMut(Result<(), &DdpError>) + Send + 'static {
+        // Anything that's already happened is called back with the locks
+        // released, so `f` is free to subscribe or stop.
+        let ended = {
+            let mut subs = self.subs.lock().unwrap();
+            let ended = self.ended.lock().unwrap().clone();
+            if ended.is_none() && !subs.is_ready(&self.id) {
+                subs.add_ready_listener(&self.id, Box::new(f));
+                return;
+            }
+            ended
+        };
+        match ended {
+            Some(Some(ref error)) => f(Err(error)),
+            Some(None)            => {},
+            None                  => f(Ok(())),
+        }
+    }
+
+    /// Called once the subscription ends, with the error if the server stopped it
+    /// because of one. Runs straight away if it already has.
+    pub fn on_stop<F>(&self, mut f: F)
+    where F: FnMut(Option<&DdpError>) + Send + 'static {
+        let ended = {
+            let mut subs = self.subs.lock().unwrap();
+            let ended = self.ended.lock().unwrap().clone();
+            match ended {
+                Some(error) => error,
+                None        => {
+                    subs.add_stop_listener(&self.id, Box::new(f));
+                    return;
+                },
+            }
+        };
+        f(ended.as_ref());
+    }
+
+    pub fn stop(&self) {
+        let callbacks = self.subs.lock().unwrap().unsub(&self.id);
+        for mut callback in callbacks {
+            callback(None);
+        }
+    }
+}
+
+fn emit(events: &Mutex<Vec<AtomicSender<CollectionEvent>>>, event: CollectionEvent) {
+    events.lock().unwrap().retain(|tx| tx.send(event.clone()).is_ok());
+}
+
+/// Why the socket went, as far as anyone can tell.
+pub(crate) fn lost() -> String {
+    "The connection was lost".to_string()
+}
+
+// What `reconnect` fails with once the connection was closed on purpose.
+fn closed() -> DdpConnError {
+    DdpConnError::IoError(io::Error::new(io::ErrorKind::NotConnected, "disconnected"))
+}
+
+fn disconnected_error() -> DdpError {
+    DdpError::local("disconnected", "The connection was closed")
+}
+
+fn dropped_error() -> DdpError {
+    DdpError::local("outbox-dropped", "Dropped from the outbox to make room")
+}
+
+// Fails whatever the outbox dropped to make room for a message of another kind.
+fn fail_dropped(methods: &Arc<Mutex<Methods>>, subs: &Arc<Mutex<Subscriptions>>, dropped: Option<String>) {
+    if let Some(id) = dropped {
+        if !methods.lock().unwrap().fail(&id, &dropped_error()) {
+            subs.lock().unwrap().fail(&id, &dropped_error());
+        }
+    }
+    settle(methods);
+}
+
+// Runs the method callbacks that came due, then drops the stub writes of every
+// method that settled. Either may call methods, so this runs without the
+// methods lock.
+fn settle(methods: &Mutex<Methods>) {
+    let (due, settled): (Vec<_>, Vec<_>) = {
+        let mut methods = methods.lock().unwrap();
+        (methods.due.drain(..).collect(), methods.settling.drain(..).collect())
+    };
+    for callback in due {
+        callback.run();
+    }
+    for (id, layers) in settled {
+        for collection in layers {
+            collection.settle(&id);
+        }
+    }
+}
+
+/// Hangs up without waiting, and without calling anything back: pending
+/// methods and subscriptions are simply dropped along with the connection.
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if self.close() {
+            self.core.outbox.close();
+        }
+    }
+}
+
+// The threads of the current socket, so `disconnect` can wait them out.
+struct Running {
+    threads: Mutex<Vec<ThreadId>>,
+    exited:  Condvar,
+}
+
+impl Running {
+    fn new() -> Self {
+        Running {
+            threads: Mutex::new(Vec::new()),
+            exited:  Condvar::new(),
+        }
+    }
+
+    fn exited(&self) {
+        let me = thread::current().id();
+        self.threads.lock().unwrap().retain(|&id| id != me);
+        self.exited.notify_all();
+    }
+
+    // Doesn't wait for the calling thread, if it's one of them.
+    fn wait(&self) {
+        let me = thread::current().id();
+        let mut threads = self.threads.lock().unwrap();
+        while threads.iter().any(|&id| id != me) {
+            threads = self.exited.wait(threads).unwrap();
+        }
+    }
+}
+
 struct OnDrop(Arc<Fn() + Sync + Send>);
 
 impl Drop for OnDrop {
@@ -540,6 +1697,7 @@ enum Listener {
     Inserted,
     Removed,
     Changed,
+    Observer,
 }
 
 pub enum NegotiateResp {
@@ -555,6 +1713,9 @@ pub enum DdpConnError {
     UrlIsNotWebsocket,
     IoError(io::Error),
     Parse(websocket::client::ParseError),
+    Tls(native_tls::Error),
+    #[cfg(feature = "async")]
+    Transport(tokio_tungstenite::tungstenite::Error),
 }
 
 struct OpNames {
@@ -600,7 +1761,12 @@ trait Reply<'a> {
         self.get_ejson("subs")
     }
 
-    fn get_ejson(&'a self, &str) -> Option<&'a Ejson>;
+    #[inline]
+    fn methods(&'a self) -> Option<&'a Ejson> {
+        self.get_ejson("methods")
+    }
+
+    fn get_ejson(&'a self, key: &str) -> Option<&'a Ejson>;
 }
 
 impl<'a> Reply<'a> for Value {
@@ -610,5 +1776,5 @@ impl<'a> Reply<'a> for Value {
     }
 }
 
-const WS:  &'static str = "ws";
-const WSS: &'static str = "wss";
+pub(crate) const WS:  &'static str = "ws";
+pub(crate) const WSS: &'static str = "wss";
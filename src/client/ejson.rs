@@ -0,0 +1,349 @@
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Number};
+
+use super::messages::Ejson;
+
+type Factory = Box<Fn(&Ejson) -> Result<Arc<Any + Send + Sync>, String> + Send + Sync + 'static>;
+
+/// A value with Meteor's extended types decoded.
+///
+/// Nothing in the client decodes or encodes for you: collections, typed
+/// collections and method results hold the wire JSON, so a date reads as
+/// `{"$date": ...}`. Run those values through `EjsonValue::decode`, or a
+/// `Codec` when custom types are involved, and `encode` values yourself
+/// before passing them as method params.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EjsonValue {
+    Null,
+    Bool(bool),
+    Number(Number),
+    /// Infinity, -Infinity or NaN, which JSON can't carry as numbers.
+    NonFinite(f64),
+    String(String),
+    Array(Vec<EjsonValue>),
+    /// Keys are as the application sees them, escaping is undone.
+    Object(BTreeMap<String, EjsonValue>),
+    Date(SystemTime),
+    Binary(Vec<u8>),
+    Custom(Custom),
+}
+
+impl EjsonValue {
+    /// Decodes the built in types; any `$type` is an error, see `Codec`.
+    pub fn decode(value: &Ejson) -> Result<Self, EjsonError> {
+        Codec::new().decode(value)
+    }
+
+    pub fn encode(&self) -> Ejson {
+        match *self {
+            EjsonValue::Null              => Ejson::Null,
+            EjsonValue::Bool(b)           => Ejson::Bool(b),
+            EjsonValue::Number(ref n)     => Ejson::Number(n.clone()),
+            EjsonValue::NonFinite(f)      => json!({ "$InfNaN": if f.is_nan() { 0 } else if f > 0.0 { 1 } else { -1 } }),
+            EjsonValue::String(ref s)     => Ejson::String(s.clone()),
+            EjsonValue::Array(ref values) => Ejson::Array(values.iter().map(|v| v.encode()).collect()),
+            EjsonValue::Object(ref map)   => {
+                let object: Map<String, Ejson> = map.iter().map(|(k, v)| (k.clone(), v.encode())).collect();
+                if is_reserved(&object) {
+                    json!({ "$escape": object })
+                } else {
+                    Ejson::Object(object)
+                }
+            },
+            EjsonValue::Date(time)        => json!({ "$date": to_millis(time) }),
+            EjsonValue::Binary(ref bytes) => json!({ "$binary": base64_encode(bytes) }),
+            EjsonValue::Custom(ref value) => json!({ "$type": value.name, "$value": value.json }),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&EjsonValue> {
+        match *self {
+            EjsonValue::Object(ref map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_date(&self) -> Option<SystemTime> {
+        match *self {
+            EjsonValue::Date(time) => Some(time),
+            _ => None,
+        }
+    }
+
+    pub fn as_binary(&self) -> Option<&[u8]> {
+        match *self {
+            EjsonValue::Binary(ref bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_custom<T: Any>(&self) -> Option<&T> {
+        match *self {
+            EjsonValue::Custom(ref value) => value.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl From<SystemTime> for EjsonValue {
+    fn from(time: SystemTime) -> Self {
+        EjsonValue::Date(time)
+    }
+}
+
+impl From<Vec<u8>> for EjsonValue {
+    fn from(bytes: Vec<u8>) -> Self {
+        EjsonValue::Binary(bytes)
+    }
+}
+
+impl From<f64> for EjsonValue {
+    fn from(f: f64) -> Self {
+        Number::from_f64(f).map_or(EjsonValue::NonFinite(f), EjsonValue::Number)
+    }
+}
+
+impl From<i64> for EjsonValue {
+    fn from(n: i64) -> Self {
+        EjsonValue::Number(n.into())
+    }
+}
+
+impl<'a> From<&'a str> for EjsonValue {
+    fn from(s: &'a str) -> Self {
+        EjsonValue::String(s.to_string())
+    }
+}
+
+impl From<String> for EjsonValue {
+    fn from(s: String) -> Self {
+        EjsonValue::String(s)
+    }
+}
+
+impl From<Custom> for EjsonValue {
+    fn from(value: Custom) -> Self {
+        EjsonValue::Custom(value)
+    }
+}
+
+/// A type the server registered with `EJSON.addType`. `type_name` must match
+/// the server's, and `to_json` and `from_json` mirror `toJSONValue` and the
+/// factory.
+pub trait CustomType: Sized + Send + Sync + 'static {
+    fn type_name() -> &'static str;
+    fn to_json(&self) -> Ejson;
+    fn from_json(value: &Ejson) -> Result<Self, String>;
+}
+
+/// A decoded custom value, along with the JSON it's sent as.
+#[derive(Clone)]
+pub struct Custom {
+    name:  String,
+    json:  Ejson,
+    value: Arc<Any + Send + Sync>,
+}
+
+impl Custom {
+    pub fn new<T: CustomType>(value: T) -> Self {
+        Custom {
+            name:  T::type_name().to_string(),
+            json:  value.to_json(),
+            value: Arc::new(value),
+        }
+    }
+
+    pub fn type_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn json(&self) -> &Ejson {
+        &self.json
+    }
+
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.value.downcast_ref()
+    }
+}
+
+impl fmt::Debug for Custom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Custom({}, {})", self.name, self.json)
+    }
+}
+
+impl PartialEq for Custom {
+    fn eq(&self, other: &Custom) -> bool {
+        self.name == other.name && self.json == other.json
+    }
+}
+
+/// Decodes EJSON, including the custom types registered with it. It's a
+/// helper you apply to what you read, the connection never uses one.
+pub struct Codec {
+    types: HashMap<String, Factory>,
+}
+
+impl Codec {
+    pub fn new() -> Self {
+        Codec {
+            types: HashMap::new(),
+        }
+    }
+
+    pub fn register<T: CustomType>(&mut self) {
+        self.types.insert(T::type_name().to_string(), Box::new(|json: &Ejson| {
+            T::from_json(json).map(|value| Arc::new(value) as Arc<Any + Send + Sync>)
+        }));
+    }
+
+    pub fn decode(&self, value: &Ejson) -> Result<EjsonValue, EjsonError> {
+        let object = match *value {
+            Ejson::Null              => return Ok(EjsonValue::Null),
+            Ejson::Bool(b)           => return Ok(EjsonValue::Bool(b)),
+            Ejson::Number(ref n)     => return Ok(EjsonValue::Number(n.clone())),
+            Ejson::String(ref s)     => return Ok(EjsonValue::String(s.clone())),
+            Ejson::Array(ref values) => {
+                return values.iter().map(|v| self.decode(v)).collect::<Result<_, _>>().map(EjsonValue::Array);
+            },
+            Ejson::Object(ref object) => object,
+        };
+        if !is_reserved(object) {
+            return self.decode_object(object);
+        }
+
+        if let Some(ms) = object.get("$date") {
+            let ms = ms.as_f64().ok_or_else(|| EjsonError::new("$date must be a number"))?;
+            Ok(EjsonValue::Date(from_millis(ms)))
+        } else if let Some(bytes) = object.get("$binary") {
+            let bytes = bytes.as_str().and_then(base64_decode)
+                .ok_or_else(|| EjsonError::new("$binary must be base64"))?;
+            Ok(EjsonValue::Binary(bytes))
+        } else if let Some(sign) = object.get("$InfNaN") {
+            match sign.as_f64() {
+                Some(sign) if sign > 0.0 => Ok(EjsonValue::NonFinite(f64::INFINITY)),
+                Some(sign) if sign < 0.0 => Ok(EjsonValue::NonFinite(f64::NEG_INFINITY)),
+                Some(_)                  => Ok(EjsonValue::NonFinite(f64::NAN)),
+                None                     => Err(EjsonError::new("$InfNaN must be a number")),
+            }
+        } else if let Some(escaped) = object.get("$escape") {
+            match *escaped {
+                Ejson::Object(ref escaped) => self.decode_object(escaped),
+                _ => Err(EjsonError::new("$escape must be an object")),
+            }
+        } else {
+            let name = object["$type"].as_str().ok_or_else(|| EjsonError::new("$type must be a string"))?;
+            let factory = self.types.get(name)
+                .ok_or_else(|| EjsonError::new(format!("custom type {} is not registered", name)))?;
+            let json = &object["$value"];
+            let value = factory(json).map_err(|e| EjsonError::new(format!("bad {}: {}", name, e)))?;
+            Ok(EjsonValue::Custom(Custom {
+                name:  name.to_string(),
+                json:  json.clone(),
+                value: value,
+            }))
+        }
+    }
+
+    fn decode_object(&self, object: &Map<String, Ejson>) -> Result<EjsonValue, EjsonError> {
+        object.iter()
+            .map(|(key, value)| self.decode(value).map(|value| (key.clone(), value)))
+            .collect::<Result<_, _>>()
+            .map(EjsonValue::Object)
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct EjsonError(String);
+
+impl EjsonError {
+    fn new<S: Into<String>>(message: S) -> Self {
+        EjsonError(message.into())
+    }
+}
+
+impl fmt::Display for EjsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid EJSON: {}", self.0)
+    }
+}
+
+impl Error for EjsonError {}
+
+// Objects shaped like one of the extended types, which have to be escaped
+// when they're plain data.
+fn is_reserved(object: &Map<String, Ejson>) -> bool {
+    match object.len() {
+        1 => ["$date", "$binary", "$InfNaN", "$escape"].iter().any(|key| object.contains_key(*key)),
+        2 => object.contains_key("$type") && object.contains_key("$value"),
+        _ => false,
+    }
+}
+
+fn from_millis(ms: f64) -> SystemTime {
+    let since = |ms: f64| if ms.fract() == 0.0 {
+        Duration::from_millis(ms as u64)
+    } else {
+        Duration::from_nanos((ms * 1e6).round() as u64)
+    };
+    if ms >= 0.0 {
+        UNIX_EPOCH + since(ms)
+    } else {
+        UNIX_EPOCH - since(-ms)
+    }
+}
+
+fn to_millis(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after)   => after.as_millis() as i64,
+        Err(before) => -(before.duration().as_millis() as i64),
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 4 / 3 + 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let digit = BASE64.iter().position(|&d| d == c)? as u32;
+            n |= digit << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            out.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(out)
+}
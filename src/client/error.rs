@@ -0,0 +1,122 @@
+use std::error::Error;
+use std::fmt;
+
+use super::messages::Ejson;
+
+/// The `error` field of a `Meteor.Error`, which servers set to either a
+/// number (often an HTTP status) or a string.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorCode {
+    Number(i64),
+    Text(String),
+    Unknown,
+}
+
+impl PartialEq<i64> for ErrorCode {
+    fn eq(&self, other: &i64) -> bool {
+        *self == ErrorCode::Number(*other)
+    }
+}
+
+impl<'a> PartialEq<&'a str> for ErrorCode {
+    fn eq(&self, other: &&'a str) -> bool {
+        match *self {
+            ErrorCode::Text(ref code) => code == other,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorCode::Number(code)   => write!(f, "{}", code),
+            ErrorCode::Text(ref code) => write!(f, "{}", code),
+            ErrorCode::Unknown        => write!(f, "unknown"),
+        }
+    }
+}
+
+/// An error sent by the server in a method `result` or a `nosub`, decoded from
+/// the shape `Meteor.Error` serializes to. The original value is kept in
+/// `raw()` for servers that send something else.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DdpError {
+    code:        ErrorCode,
+    reason:      Option<String>,
+    details:     Option<Ejson>,
+    error_type:  Option<String>,
+    client_safe: bool,
+    raw:         Ejson,
+}
+
+impl DdpError {
+    /// An error raised on this side of the connection, shaped like one from the
+    /// server so it goes through the same callbacks.
+    pub fn local(code: &str, reason: &str) -> Self {
+        DdpError::from(&json!({
+            "error": code,
+            "reason": reason,
+            "errorType": "Meteor.Error",
+            "isClientSafe": true,
+        }))
+    }
+
+    pub fn code(&self) -> &ErrorCode {
+        &self.code
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_ref().map(|r| &r[..])
+    }
+
+    /// Any EJSON value the server attached, usually a string.
+    pub fn details(&self) -> Option<&Ejson> {
+        self.details.as_ref()
+    }
+
+    /// Usually `"Meteor.Error"`.
+    pub fn error_type(&self) -> Option<&str> {
+        self.error_type.as_ref().map(|t| &t[..])
+    }
+
+    pub fn is_client_safe(&self) -> bool {
+        self.client_safe
+    }
+
+    pub fn raw(&self) -> &Ejson {
+        &self.raw
+    }
+}
+
+impl<'a> From<&'a Ejson> for DdpError {
+    fn from(raw: &'a Ejson) -> Self {
+        let code = match raw.get("error") {
+            Some(&Ejson::String(ref code)) => ErrorCode::Text(code.clone()),
+            Some(&Ejson::Number(ref code)) => code.as_i64().map_or(ErrorCode::Unknown, ErrorCode::Number),
+            _ => ErrorCode::Unknown,
+        };
+        let text = |key: &str| raw.get(key).and_then(|v| v.as_str()).map(|v| v.to_string());
+
+        DdpError {
+            code:        code,
+            reason:      text("reason"),
+            details:     raw.get("details").cloned(),
+            error_type:  text("errorType"),
+            client_safe: raw.get("isClientSafe").and_then(|v| v.as_bool()).unwrap_or(false),
+            raw:         raw.clone(),
+        }
+    }
+}
+
+impl fmt::Display for DdpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.code, &self.reason) {
+            (&ErrorCode::Unknown, _)  => write!(f, "{}", self.raw),
+            (code, &Some(ref reason)) => write!(f, "{} [{}]", reason, code),
+            (code, &None)             => write!(f, "[{}]", code),
+        }
+    }
+}
+
+impl Error for DdpError {}
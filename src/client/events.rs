@@ -0,0 +1,28 @@
+use super::error::DdpError;
+use super::messages::Ejson;
+
+/// A change to a collection, as the server described it. The async client
+/// only sends the document events.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CollectionEvent {
+    Added {
+        id:     String,
+        fields: Option<Ejson>,
+    },
+    Changed {
+        id:      String,
+        fields:  Option<Ejson>,
+        cleared: Option<Ejson>,
+    },
+    Removed {
+        id: String,
+    },
+    Ready {
+        subscription: String,
+    },
+    /// The subscription stopped, or was refused if there's an error.
+    NoSub {
+        subscription: String,
+        error:        Option<DdpError>,
+    },
+}
@@ -0,0 +1,140 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use super::bufferedws::Outbox;
+use super::messages::Ping;
+use super::timer::Timer;
+
+// Meteor's client defaults.
+const INTERVAL: Duration = Duration::from_millis(17500);
+const TIMEOUT: Duration = Duration::from_millis(15000);
+
+struct State {
+    interval:   Option<Duration>,
+    timeout:    Duration,
+    last_seen:  Instant,
+    // The id of the unanswered ping, and when it went out.
+    waiting:    Option<(String, Instant)>,
+    pings:      u64,
+    generation: u64,
+}
+
+/// Keeps an eye on a quiet socket, like Meteor does. Once nothing has come in
+/// for `interval` a `ping` goes out, and if nothing at all comes back within
+/// `timeout` the connection is taken down by closing the outbox, which ends
+/// the sending thread and shuts the socket.
+pub struct Heartbeat {
+    state: Mutex<State>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Heartbeat {
+            state: Mutex::new(State {
+                interval:   Some(INTERVAL),
+                timeout:    TIMEOUT,
+                last_seen:  Instant::now(),
+                waiting:    None,
+                pings:      0,
+                generation: 0,
+            }),
+        }
+    }
+
+    /// Takes effect from the next `start`. `None` turns heartbeats off.
+    pub fn configure(&self, settings: Option<(Duration, Duration)>) {
+        let mut state = self.state.lock().unwrap();
+        state.interval = settings.map(|(interval, _)| interval);
+        state.timeout = settings.map_or(TIMEOUT, |(_, timeout)| timeout);
+    }
+
+    /// Anything the server sends shows it's alive.
+    pub fn seen(&self) {
+        self.state.lock().unwrap().last_seen = Instant::now();
+    }
+
+    pub fn pong(&self, id: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        if state.waiting.as_ref().map_or(false, |&(ref ping, _)| Some(&ping[..]) == id) {
+            state.waiting = None;
+        }
+    }
+
+    /// Stops whatever is watching, until the next `start`.
+    pub fn stop(&self) {
+        self.state.lock().unwrap().generation += 1;
+    }
+
+    /// Starts watching a new socket, or the same one with new settings.
+    /// Whatever was watching before stops.
+    pub fn start(heartbeat: &Arc<Heartbeat>, outbox: &Arc<Outbox>, timer: &Arc<Timer>) {
+        let (generation, interval) = {
+            let mut state = heartbeat.state.lock().unwrap();
+            state.generation += 1;
+            state.last_seen = Instant::now();
+            state.waiting = None;
+            (state.generation, state.interval)
+        };
+        if let Some(interval) = interval {
+            let pulse = Pulse {
+                heartbeat:  Arc::downgrade(heartbeat),
+                outbox:     Arc::downgrade(outbox),
+                timer:      Arc::downgrade(timer),
+                generation: generation,
+            };
+            timer.after(interval, move || pulse.check());
+        }
+    }
+}
+
+// Scheduled on the timer, which it only holds weakly so the connection can
+// still go away.
+struct Pulse {
+    heartbeat:  Weak<Heartbeat>,
+    outbox:     Weak<Outbox>,
+    timer:      Weak<Timer>,
+    generation: u64,
+}
+
+impl Pulse {
+    fn check(self) {
+        let (heartbeat, outbox, timer) = match (self.heartbeat.upgrade(), self.outbox.upgrade(), self.timer.upgrade()) {
+            (Some(heartbeat), Some(outbox), Some(timer)) => (heartbeat, outbox, timer),
+            _ => return,
+        };
+        let next = {
+            let mut state = heartbeat.state.lock().unwrap();
+            let interval = match state.interval {
+                Some(interval) if state.generation == self.generation => interval,
+                _ => return,
+            };
+            let now = Instant::now();
+            if state.waiting.as_ref().map_or(false, |&(_, sent)| state.last_seen >= sent) {
+                state.waiting = None;
+            }
+            match state.waiting {
+                Some((_, sent)) => {
+                    let waited = now.saturating_duration_since(sent);
+                    if waited >= state.timeout {
+                        outbox.close();
+                        return;
+                    }
+                    state.timeout - waited
+                },
+                None => {
+                    let quiet = now.saturating_duration_since(state.last_seen);
+                    if quiet < interval {
+                        interval - quiet
+                    } else {
+                        state.pings += 1;
+                        let id = state.pings.to_string();
+                        outbox.send_live(Ping::text(Some(&id)));
+                        state.waiting = Some((id, now));
+                        state.timeout
+                    }
+                },
+            }
+        };
+        timer.after(next, move || self.check());
+    }
+}
@@ -41,6 +41,8 @@ pub struct Connect {
     msg: &'static str,
     version: &'static str,
     support: &'static [&'static str],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<String>,
 }
 
 pub struct Pong;
@@ -66,22 +68,29 @@ impl Pong {
     }
 }
 
+impl Ping {
+    pub fn text<'l>(id: Option<&'l str>) -> String {
+        match id {
+            Some(id) => json!({ "msg": "ping", "id": id }).to_string(),
+            None     => json!({ "msg": "ping" }).to_string(),
+        }
+    }
+}
+
 impl Method {
-    pub fn text<'l>(id: &'l str, method: &'l str, params: Option<&Vec<&Ejson>>) -> String {
+    pub fn text<'l>(id: &'l str, method: &'l str, params: Option<&Vec<&Ejson>>, seed: Option<&'l str>) -> String {
+        let mut message = json!({
+            "msg": "method",
+            "id": id,
+            "method": method
+        });
         if let Some(args) = params {
-             json!({
-                "msg": "method",
-                "id": id,
-                "method": method,
-                "params": args
-            }).to_string()
-        } else {
-            json!({
-                "msg": "method",
-                "id": id,
-                "method": method
-            }).to_string()
+            message["params"] = json!(args);
+        }
+        if let Some(seed) = seed {
+            message["randomSeed"] = json!(seed);
         }
+        message.to_string()
     }
 }
 
@@ -113,12 +122,104 @@ impl Unsubscribe {
     }
 }
 
+/***************************
+ *     Server messages     *
+ ***************************/
+
+pub struct Added;
+
+pub struct Changed;
+
+pub struct Removed;
+
+pub struct Ready;
+
+pub struct NoSub;
+
+pub struct Updated;
+
+pub struct Malformed;
+
+impl Connected {
+    pub fn text<'l>(session: &'l str) -> String {
+        json!({ "msg": "connected", "session": session }).to_string()
+    }
+}
+
+impl Failed {
+    pub fn text<'l>(version: &'l str) -> String {
+        json!({ "msg": "failed", "version": version }).to_string()
+    }
+}
+
+impl MethodResult {
+    pub fn text<'l>(id: &'l str, result: Result<&Ejson, &Ejson>) -> String {
+        match result {
+            Ok(result) => json!({ "msg": "result", "id": id, "result": result }).to_string(),
+            Err(error) => json!({ "msg": "result", "id": id, "error": error }).to_string(),
+        }
+    }
+}
+
+impl Added {
+    pub fn text<'l>(collection: &'l str, id: &'l str, fields: &Ejson) -> String {
+        json!({ "msg": "added", "collection": collection, "id": id, "fields": fields }).to_string()
+    }
+}
+
+impl Changed {
+    pub fn text<'l>(collection: &'l str, id: &'l str, fields: &Ejson, cleared: &[&'l str]) -> String {
+        let mut message = json!({ "msg": "changed", "collection": collection, "id": id, "fields": fields });
+        if !cleared.is_empty() {
+            message["cleared"] = json!(cleared);
+        }
+        message.to_string()
+    }
+}
+
+impl Removed {
+    pub fn text<'l>(collection: &'l str, id: &'l str) -> String {
+        json!({ "msg": "removed", "collection": collection, "id": id }).to_string()
+    }
+}
+
+impl Ready {
+    pub fn text<'l>(subs: &[&'l str]) -> String {
+        json!({ "msg": "ready", "subs": subs }).to_string()
+    }
+}
+
+impl NoSub {
+    pub fn text<'l>(id: &'l str, error: Option<&Ejson>) -> String {
+        match error {
+            Some(error) => json!({ "msg": "nosub", "id": id, "error": error }).to_string(),
+            None        => json!({ "msg": "nosub", "id": id }).to_string(),
+        }
+    }
+}
+
+impl Updated {
+    pub fn text<'l>(methods: &[&'l str]) -> String {
+        json!({ "msg": "updated", "methods": methods }).to_string()
+    }
+}
+
+impl Malformed {
+    pub fn text<'l>(reason: &'l str, offending: Option<&Ejson>) -> String {
+        match offending {
+            Some(message) => json!({ "msg": "error", "reason": reason, "offendingMessage": message }).to_string(),
+            None          => json!({ "msg": "error", "reason": reason }).to_string(),
+        }
+    }
+}
+
 impl Connect {
-    pub fn new(version: &'static str) -> Self {
+    pub fn new(version: &'static str, session: Option<String>) -> Self {
         Connect {
             msg: "connect",
             version: version,
             support: VERSIONS,
+            session: session,
         }
     }
 }
@@ -0,0 +1,192 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::vec;
+use serde_json::Map;
+
+use super::messages::Ejson;
+
+/// The merged state of every document the server has published to a collection,
+/// keyed by id. Documents carry their id in `_id`, like they do in Mongo.
+///
+/// Servers without a merge box send `added` once per subscription publishing a
+/// document, so each one is counted and only dropped by the last `removed`.
+///
+/// Writes made by method stubs are kept apart, in an overlay that hides the
+/// server's version of a document until every method that wrote it settles.
+/// Reads see the overlay, server messages only ever touch what's under it.
+pub struct Documents {
+    docs:    BTreeMap<String, Ejson>,
+    refs:    HashMap<String, usize>,
+    stale:   HashSet<String>,
+    overlay: HashMap<String, Option<Ejson>>,
+    writers: HashMap<String, HashSet<String>>,
+}
+
+impl Documents {
+    pub fn new() -> Self {
+        Documents {
+            docs:    BTreeMap::new(),
+            refs:    HashMap::new(),
+            stale:   HashSet::new(),
+            overlay: HashMap::new(),
+            writers: HashMap::new(),
+        }
+    }
+
+    /// Returns the previous version if the document was already cached.
+    pub fn add(&mut self, id: &str, fields: Option<&Ejson>) -> Option<Ejson> {
+        let revived = self.stale.remove(id);
+        *self.refs.entry(id.to_string()).or_insert(0) += 1;
+
+        let previous = self.docs.get(id).cloned();
+        if previous.is_some() && !revived {
+            self.change(id, fields, None);
+            return previous;
+        }
+
+        let mut doc = match fields {
+            Some(&Ejson::Object(ref fields)) => fields.clone(),
+            _ => Map::new(),
+        };
+        doc.insert("_id".to_string(), Ejson::String(id.to_string()));
+        self.docs.insert(id.to_string(), Ejson::Object(doc));
+        previous
+    }
+
+    pub fn change(&mut self, id: &str, fields: Option<&Ejson>, cleared: Option<&Ejson>) {
+        let doc = match self.docs.get_mut(id) {
+            Some(&mut Ejson::Object(ref mut doc)) => doc,
+            _ => return,
+        };
+        if let Some(&Ejson::Object(ref fields)) = fields {
+            for (key, value) in fields.iter() {
+                doc.insert(key.clone(), value.clone());
+            }
+        }
+        if let Some(&Ejson::Array(ref cleared)) = cleared {
+            for key in cleared.iter().filter_map(|key| key.as_str()) {
+                doc.remove(key);
+            }
+        }
+    }
+
+    /// Returns the document once nothing publishes it anymore.
+    pub fn remove(&mut self, id: &str) -> Option<Ejson> {
+        if let Some(count) = self.refs.get_mut(id) {
+            *count -= 1;
+            if *count > 0 {
+                return None;
+            }
+        }
+        self.refs.remove(id);
+        self.stale.remove(id);
+        self.docs.remove(id)
+    }
+
+    /// After a reconnect the server publishes everything again. Until `flush`,
+    /// documents are kept and replaced as they're re-added.
+    pub fn reset(&mut self) {
+        self.refs.clear();
+        self.stale = self.docs.keys().cloned().collect();
+    }
+
+    /// Drops every document that wasn't re-added since `reset`, returning the
+    /// ones that were visible.
+    pub fn flush(&mut self) -> Vec<(String, Ejson)> {
+        let stale: Vec<String> = self.stale.drain().collect();
+        let (docs, overlay) = (&mut self.docs, &self.overlay);
+        stale.into_iter()
+            .filter_map(|id| docs.remove(&id).map(|doc| (id, doc)))
+            .filter(|&(ref id, _)| !overlay.contains_key(id))
+            .collect()
+    }
+
+    /// Whether a stub's write is hiding the server's version of the document.
+    pub fn is_simulated(&self, id: &str) -> bool {
+        self.overlay.contains_key(id)
+    }
+
+    /// Shows `doc` in place of the document until `method` settles, `None`
+    /// hiding it. Returns what was visible before.
+    pub fn simulate(&mut self, method: &str, id: &str, doc: Option<Ejson>) -> Option<Ejson> {
+        let before = self.get(id).cloned();
+        self.overlay.insert(id.to_string(), doc);
+        self.writers.entry(id.to_string()).or_insert_with(HashSet::new).insert(method.to_string());
+        before
+    }
+
+    /// Drops the writes of `method`. Returns the documents that show the
+    /// server's version again, with what was visible until now.
+    pub fn settle(&mut self, method: &str) -> Vec<(String, Option<Ejson>)> {
+        let settled: Vec<String> = self.writers.iter_mut()
+            .filter_map(|(id, writers)| if writers.remove(method) && writers.is_empty() { Some(id.clone()) } else { None })
+            .collect();
+        settled.into_iter()
+            .map(|id| {
+                self.writers.remove(&id);
+                let before = self.overlay.remove(&id).and_then(|doc| doc);
+                (id, before)
+            })
+            .collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Ejson> {
+        match self.overlay.get(id) {
+            Some(doc) => doc.as_ref(),
+            None      => self.docs.get(id),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().len()
+    }
+
+    /// Ordered by id.
+    pub fn iter(&self) -> vec::IntoIter<&Ejson> {
+        if self.overlay.is_empty() {
+            return self.docs.values().collect::<Vec<_>>().into_iter();
+        }
+        let mut visible: BTreeMap<&str, &Ejson> = self.docs.iter()
+            .filter(|&(id, _)| !self.overlay.contains_key(id))
+            .map(|(id, doc)| (&id[..], doc))
+            .collect();
+        visible.extend(self.overlay.iter().filter_map(|(id, doc)| doc.as_ref().map(|doc| (&id[..], doc))));
+        visible.into_values().collect::<Vec<_>>().into_iter()
+    }
+
+    pub fn values(&self) -> Vec<Ejson> {
+        self.iter().cloned().collect()
+    }
+}
+
+/// The fields set and cleared going from `old` to `new`, shaped like the
+/// arguments of a `changed` message.
+pub fn diff(new: &Ejson, old: &Ejson) -> (Option<Ejson>, Option<Ejson>) {
+    let empty = Map::new();
+    let new = new.as_object().unwrap_or(&empty);
+    let old = old.as_object().unwrap_or(&empty);
+
+    let fields: Map<String, Ejson> = new.iter()
+        .filter(|&(key, value)| old.get(key) != Some(value))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let cleared: Vec<Ejson> = old.keys()
+        .filter(|key| !new.contains_key(*key))
+        .map(|key| Ejson::String(key.clone()))
+        .collect();
+
+    (if fields.is_empty() { None } else { Some(Ejson::Object(fields)) },
+     if cleared.is_empty() { None } else { Some(Ejson::Array(cleared)) })
+}
+
+/// The id a document with this `_id` goes by on the wire and in the cache,
+/// after Meteor's `MongoID.idStringify`: strings as they are, ObjectIDs as
+/// their hex and anything else as `~` and its JSON.
+pub fn id_string(id: &Ejson) -> String {
+    match *id {
+        Ejson::String(ref id) => id.clone(),
+        Ejson::Object(ref oid) if oid.get("$type").map_or(false, |t| t == "oid") => {
+            oid.get("$value").and_then(|hex| hex.as_str()).unwrap_or("").to_string()
+        },
+        ref other => format!("~{}", other),
+    }
+}
@@ -1,42 +1,183 @@
-use std::sync::Arc;
+use std::cmp;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, SystemTime};
 
 extern crate websocket;
 use websocket::client::Url;
 
+mod accounts;
+pub use self::accounts::{Login, User};
+
+mod blocking;
+pub use self::blocking::BlockingClient;
+
+mod bufferedws;
+pub use self::bufferedws::Overflow;
+
 mod connection;
 pub use self::connection::Connection;
-pub use self::connection::{Collection, DdpConnError};
+pub use self::connection::{Collection, DdpConnError, MethodCallbacks, MethodHandle, Subscription};
+
+mod ejson;
+pub use self::ejson::{Codec, Custom, CustomType, EjsonError, EjsonValue};
+
+mod error;
+pub use self::error::{DdpError, ErrorCode};
+
+mod events;
+pub use self::events::CollectionEvent;
 
-mod messages;
+mod heartbeat;
+
+pub(crate) mod messages;
 use self::messages::Ejson;
 
+mod minimongo;
+mod modifier;
+#[cfg(feature = "async")]
+mod nonblocking;
+#[cfg(feature = "async")]
+pub use self::nonblocking::{AsyncClient, AsyncSubscription};
+
+mod observe;
+pub use self::observe::{Observe, ObserveChanges};
+
+mod query;
+pub use self::query::{Order, Query, QueryError};
+
+mod status;
+pub use self::status::{State, Status};
+
+mod stream;
+pub use self::stream::TlsConfig;
+
+mod stub;
+pub use self::stub::StubContext;
+
+mod timer;
+
+pub use crate::random::{Alea, RandomStream};
+
+mod typed;
+pub use self::typed::TypedCollection;
+
+type RetryListener = Box<Fn(u32, Result<&str, &DdpConnError>) + Send + 'static>;
+
 pub struct Client {
     // url:    Url,
-    retry:  Option<Retry>,
-    conn:   Connection,
+    conn:       Arc<Connection>,
+    supervisor: Arc<Supervisor>,
 }
 /*
- * Should Have BlockingClient, QueuedClient, NonblockingClient
+ * See also BlockingClient, AsyncClient and Client::queue_offline
  */
 impl Client {
     pub fn new(url: Url) -> Result<Self, DdpConnError> {
-        let (conn, _) = try!(Connection::new(&url, || {
-            /* TODO */
-        }));
+        Client::with_tls(url, &TlsConfig::new())
+    }
+
+    pub fn with_tls(url: Url, tls: &TlsConfig) -> Result<Self, DdpConnError> {
+        let supervisor = Arc::new(Supervisor {
+            conn:      Mutex::new(Weak::new()),
+            retry:     Mutex::new(None),
+            listeners: Mutex::new(Vec::new()),
+            closed:    Mutex::new(false),
+            wake:      Condvar::new(),
+        });
+
+        let (on_crash, on_close) = (supervisor.clone(), supervisor.clone());
+        let (conn, _) = Connection::resumable(&url, tls, move || {
+            Supervisor::reconnect(&on_crash);
+        }, move || on_close.close())?;
+        let conn = Arc::new(conn);
+        *supervisor.conn.lock().unwrap() = Arc::downgrade(&conn);
 
         Ok(Client {
             // url:    url,
-            retry:  None,
-            conn:   conn,
+            conn:       conn,
+            supervisor: supervisor,
         })
     }
 
     #[inline]
-    pub fn call<C>(&self, method: &str, params: Option<&Vec<&Ejson>>, callback: C)
-    where C: FnMut(Result<&Ejson, &Ejson>) + Send + 'static {
+    pub fn call<C>(&self, method: &str, params: Option<&Vec<&Ejson>>, callback: C) -> MethodHandle
+    where C: FnMut(Result<&Ejson, &DdpError>) + Send + 'static {
         self.conn.call(method, params, Box::new(callback))
     }
 
+    #[inline]
+    pub fn apply(&self, method: &str, params: Option<&Vec<&Ejson>>, callbacks: MethodCallbacks) -> MethodHandle {
+        self.conn.apply(method, params, callbacks)
+    }
+
+    /// See `Connection::stub`.
+    #[inline]
+    pub fn stub<F>(&self, method: &str, f: F)
+    where F: Fn(&mut StubContext, &[&Ejson]) + Send + Sync + 'static {
+        self.conn.stub(method, f)
+    }
+
+    #[inline]
+    pub fn subscribe(&self, name: &str, params: Option<&Vec<&Ejson>>) -> Subscription {
+        self.conn.subscribe(name, params)
+    }
+
+    #[inline]
+    pub fn login_with_password<F>(&self, user: &User, password: &str, callback: F)
+    where F: FnMut(Result<&Login, &DdpError>) + Send + 'static {
+        self.conn.login_with_password(user, password, callback)
+    }
+
+    #[inline]
+    pub fn login_with_token<F>(&self, token: &str, callback: F)
+    where F: FnMut(Result<&Login, &DdpError>) + Send + 'static {
+        self.conn.login_with_token(token, callback)
+    }
+
+    #[inline]
+    pub fn logout<F>(&self, callback: F)
+    where F: FnMut(Result<(), &DdpError>) + Send + 'static {
+        self.conn.logout(callback)
+    }
+
+    #[inline]
+    pub fn logout_other_clients<F>(&self, callback: F)
+    where F: FnMut(Result<(), &DdpError>) + Send + 'static {
+        self.conn.logout_other_clients(callback)
+    }
+
+    #[inline]
+    pub fn user_id(&self) -> Option<String> {
+        self.conn.user_id()
+    }
+
+    #[inline]
+    pub fn login_token(&self) -> Option<String> {
+        self.conn.login_token()
+    }
+
+    /// See `Connection::heartbeat`.
+    #[inline]
+    pub fn heartbeat(&self, interval: Duration, timeout: Duration) {
+        self.conn.heartbeat(interval, timeout)
+    }
+
+    #[inline]
+    pub fn no_heartbeat(&self) {
+        self.conn.no_heartbeat()
+    }
+
+    /// See `Connection::queue_offline`.
+    #[inline]
+    pub fn queue_offline(&self, limit: Option<usize>, overflow: Overflow) {
+        self.conn.queue_offline(limit, overflow)
+    }
+
+    #[inline]
+    pub fn queued(&self) -> usize {
+        self.conn.queued()
+    }
+
     #[inline]
     pub fn mongo<S>(&self, collection: S) -> Arc<Collection>
     where S: Into<String> {
@@ -44,38 +185,179 @@ impl Client {
     }
 
     #[inline]
-    pub fn session(&self) -> &str {
+    pub fn session(&self) -> String {
         self.conn.session()
     }
 
+    /// See `Connection::disconnect`.
+    #[inline]
+    pub fn disconnect(&self) {
+        self.conn.disconnect()
+    }
+
+    #[inline]
+    pub fn status(&self) -> Status {
+        self.conn.status()
+    }
+
+    /// See `Connection::on_status`.
+    #[inline]
+    pub fn on_status<F>(&self, f: F)
+    where F: Fn(&Status) + Send + 'static {
+        self.conn.on_status(f)
+    }
+
     #[inline]
     pub fn version(&self) -> &'static str {
         self.conn.version()
     }
 
     pub fn retry(&mut self, curve: Retry) {
-        self.retry = Some(curve);
+        *self.supervisor.retry.lock().unwrap() = Some(curve);
     }
 
     pub fn retry_custom<F>(&mut self, f: F)
-    where F: Fn(u32, u32) -> Option<u32> + 'static {
-        self.retry = Some(Retry::Custom(Box::new(f)));
+    where F: Fn(u32, u32) -> Option<u32> + Send + 'static {
+        *self.supervisor.retry.lock().unwrap() = Some(Retry::Custom(Box::new(f)));
     }
 
     pub fn no_retry(&mut self) {
-        self.retry = None;
+        *self.supervisor.retry.lock().unwrap() = None;
+    }
+
+    /// Called after every reconnection attempt with the attempt number and
+    /// either the new session id or the reason the attempt failed.
+    pub fn on_retry<F>(&self, f: F)
+    where F: Fn(u32, Result<&str, &DdpConnError>) + Send + 'static {
+        self.supervisor.listeners.lock().unwrap().push(Box::new(f));
     }
 }
 
+/// Delays are in milliseconds. `Custom` is given the attempt number (starting
+/// at 1) and the previous delay, and gives up by returning `None`.
 pub enum Retry {
     Linear,
     Exponential,
     Quadratic,
-    Custom(Box<Fn(u32, u32) -> Option<u32>>),
+    Custom(Box<Fn(u32, u32) -> Option<u32> + Send + 'static>),
+}
+
+impl Retry {
+    /// How long to wait before reconnection attempt `attempt`, given the
+    /// previous delay. The built-in curves count in seconds and top out at
+    /// five minutes.
+    pub fn delay(&self, attempt: u32, last: u32) -> Option<u32> {
+        let seconds = match *self {
+            Retry::Linear        => attempt,
+            Retry::Exponential   => 1u32.checked_shl(attempt - 1).unwrap_or(::std::u32::MAX),
+            Retry::Quadratic     => attempt.saturating_mul(attempt),
+            Retry::Custom(ref f) => return f(attempt, last),
+        };
+        Some(cmp::min(seconds.saturating_mul(1000), MAX_RETRY_DELAY))
+    }
 }
 
+/// Nothing takes one, see `Client`, `BlockingClient` and `Client::queue_offline`.
+#[deprecated(note = "unused, pick `Client`, `BlockingClient` or `Client::queue_offline` instead")]
 pub enum ClientType {
     Blocking,
     Nonblocking,
     Queueing,
 }
+
+struct Supervisor {
+    conn:      Mutex<Weak<Connection>>,
+    retry:     Mutex<Option<Retry>>,
+    listeners: Mutex<Vec<RetryListener>>,
+    // Set, and `wake` notified, once the connection closes for good.
+    closed:    Mutex<bool>,
+    wake:      Condvar,
+}
+
+impl Supervisor {
+    // Runs on whichever connection thread exited last, so it's fine to block
+    // here. Stops as soon as the connection is disconnected, even mid-wait,
+    // putting back the `Offline` a status set meanwhile may have hidden.
+    fn reconnect(supervisor: &Arc<Supervisor>) {
+        let mut attempt = 0;
+        let mut delay = 0;
+        let mut error = connection::lost();
+
+        loop {
+            if Supervisor::closing(supervisor) {
+                return Supervisor::set_status(supervisor, State::Offline, None);
+            }
+            attempt += 1;
+            delay = match supervisor.retry.lock().unwrap().as_ref().and_then(|r| r.delay(attempt, delay)) {
+                Some(delay) => delay,
+                None        => return Supervisor::give_up(supervisor, attempt, error),
+            };
+            let delay_ms = Duration::from_millis(delay as u64);
+            Supervisor::set_status(supervisor, State::Waiting {
+                retry_count: attempt - 1,
+                retry_time:  SystemTime::now() + delay_ms,
+            }, Some(error.clone()));
+            if supervisor.wait(delay_ms) {
+                return Supervisor::set_status(supervisor, State::Offline, None);
+            }
+
+            let conn = match supervisor.conn.lock().unwrap().upgrade() {
+                Some(ref conn) if conn.is_closing() => return conn.set_status(State::Offline, None),
+                Some(conn) => conn,
+                None       => return,
+            };
+            conn.set_status(State::Connecting, None);
+            let on_crash = supervisor.clone();
+            let result = conn.reconnect(move || {
+                Supervisor::reconnect(&on_crash);
+            });
+            if let Err(ref e) = result {
+                error = format!("{:?}", e);
+            }
+
+            let session = conn.session();
+            for listener in supervisor.listeners.lock().unwrap().iter() {
+                listener(attempt, result.as_ref().map(|_| &session[..]));
+            }
+            if result.is_ok() {
+                return;
+            }
+        }
+    }
+
+    // Nothing will be replayed, so fail whatever is still waiting. Without a
+    // single attempt there was nothing to give up on, the client is just offline.
+    fn give_up(supervisor: &Supervisor, attempt: u32, error: String) {
+        let conn = supervisor.conn.lock().unwrap().upgrade();
+        if let Some(conn) = conn.filter(|conn| !conn.is_closing()) {
+            conn.set_status(if attempt == 1 { State::Offline } else { State::Failed }, Some(error));
+            conn.fail_pending();
+        }
+    }
+
+    fn close(&self) {
+        *self.closed.lock().unwrap() = true;
+        self.wake.notify_all();
+    }
+
+    // Returns true if the connection closed before `delay` was up.
+    fn wait(&self, delay: Duration) -> bool {
+        let closed = self.closed.lock().unwrap();
+        let (closed, _) = self.wake.wait_timeout_while(closed, delay, |closed| !*closed).unwrap();
+        *closed
+    }
+
+    // Gone counts as closing.
+    fn closing(supervisor: &Supervisor) -> bool {
+        supervisor.conn.lock().unwrap().upgrade().map_or(true, |conn| conn.is_closing())
+    }
+
+    fn set_status(supervisor: &Supervisor, state: State, error: Option<String>) {
+        let conn = supervisor.conn.lock().unwrap().upgrade();
+        if let Some(conn) = conn {
+            conn.set_status(state, error);
+        }
+    }
+}
+
+const MAX_RETRY_DELAY: u32 = 5 * 60 * 1000;
@@ -0,0 +1,208 @@
+use std::cmp::Ordering;
+
+use serde_json::{Map, Number};
+
+use super::messages::Ejson;
+use super::query::{compare, Query, QueryError};
+
+/// Applies a Mongo modifier to `doc`, for stubs to write with. Supports `$set`,
+/// `$unset`, `$inc`, `$mul`, `$min`, `$max`, `$rename`, `$push`, `$addToSet`,
+/// `$pop`, `$pull` and `$pullAll`; a modifier without operators replaces the
+/// document, keeping its `_id`.
+pub fn apply(doc: &Ejson, modifier: &Ejson) -> Result<Ejson, QueryError> {
+    let modifier = modifier.as_object().ok_or_else(|| QueryError::new("modifiers must be objects"))?;
+    let operators = modifier.keys().filter(|key| key.starts_with('$')).count();
+
+    if operators == 0 {
+        let mut replaced = modifier.clone();
+        if let Some(id) = doc.get("_id") {
+            replaced.insert("_id".to_string(), id.clone());
+        }
+        return Ok(Ejson::Object(replaced));
+    }
+    if operators != modifier.len() {
+        return Err(QueryError::new("modifiers can't mix operators and fields"));
+    }
+
+    let mut doc = doc.clone();
+    for (op, fields) in modifier.iter() {
+        let fields = fields.as_object().ok_or_else(|| QueryError::new(format!("{} needs an object", op)))?;
+        for (field, arg) in fields.iter() {
+            if field == "_id" || field.starts_with("_id.") {
+                return Err(QueryError::new("_id can't be modified"));
+            }
+            update(&mut doc, op, field, arg)?;
+        }
+    }
+    Ok(doc)
+}
+
+fn update(doc: &mut Ejson, op: &str, field: &str, arg: &Ejson) -> Result<(), QueryError> {
+    match op {
+        "$set"   => *entry(doc, field)? = arg.clone(),
+        "$unset" => { take(doc, field); },
+        "$inc" | "$mul" => {
+            if !arg.is_number() {
+                return Err(QueryError::new(format!("{} needs a number", op)));
+            }
+            // A missing field counts as 0, unlike one that's null.
+            let current = match lookup(doc, field) {
+                None                            => 0.into(),
+                Some(&mut Ejson::Number(ref n)) => n.clone(),
+                Some(_) => return Err(QueryError::new(format!("Cannot apply {} to a value of non-numeric type", op))),
+            };
+            *entry(doc, field)? = arithmetic(op, &current, arg);
+        },
+        "$min" | "$max" => {
+            let value = entry(doc, field)?;
+            let wanted = if op == "$min" { Ordering::Less } else { Ordering::Greater };
+            if value.is_null() || compare(arg, value) == wanted {
+                *value = arg.clone();
+            }
+        },
+        "$rename" => {
+            let to = arg.as_str().ok_or_else(|| QueryError::new("$rename needs a field name"))?;
+            if let Some(value) = take(doc, field) {
+                *entry(doc, to)? = value;
+            }
+        },
+        "$push" | "$addToSet" => {
+            let items = match arg.get("$each") {
+                Some(&Ejson::Array(ref each)) => each.clone(),
+                Some(_) => return Err(QueryError::new("$each needs an array")),
+                None    => vec![arg.clone()],
+            };
+            let array = array(entry(doc, field)?, op, field)?;
+            for item in items {
+                if op == "$push" || !array.contains(&item) {
+                    array.push(item);
+                }
+            }
+        },
+        "$pop" => {
+            let array = array(entry(doc, field)?, op, field)?;
+            if arg.as_f64().map_or(false, |first| first < 0.0) {
+                if !array.is_empty() {
+                    array.remove(0);
+                }
+            } else {
+                array.pop();
+            }
+        },
+        "$pull" | "$pullAll" => {
+            let pulled = match lookup(doc, field) {
+                Some(value) => value,
+                None        => return Ok(()),
+            };
+            let array = array(pulled, op, field)?;
+            if op == "$pullAll" {
+                let values = arg.as_array().ok_or_else(|| QueryError::new("$pullAll needs an array"))?;
+                array.retain(|item| !values.contains(item));
+            } else {
+                let condition = Condition::new(arg)?;
+                array.retain(|item| !condition.matches(item));
+            }
+        },
+        _ => return Err(QueryError::new(format!("unsupported modifier {}", op))),
+    }
+    Ok(())
+}
+
+fn arithmetic(op: &str, current: &Number, arg: &Ejson) -> Ejson {
+    if let (Some(a), Some(b)) = (current.as_i64(), arg.as_i64()) {
+        let result = if op == "$inc" { a.checked_add(b) } else { a.checked_mul(b) };
+        if let Some(result) = result {
+            return Ejson::Number(result.into());
+        }
+    }
+    let (a, b) = (current.as_f64().unwrap_or(0.0), arg.as_f64().unwrap_or(0.0));
+    let result = if op == "$inc" { a + b } else { a * b };
+    Number::from_f64(result).map_or(Ejson::Null, Ejson::Number)
+}
+
+fn array<'a>(value: &'a mut Ejson, op: &str, field: &str) -> Result<&'a mut Vec<Ejson>, QueryError> {
+    if value.is_null() {
+        *value = Ejson::Array(Vec::new());
+    }
+    match *value {
+        Ejson::Array(ref mut array) => Ok(array),
+        _ => Err(QueryError::new(format!("{} needs an array at {}", op, field))),
+    }
+}
+
+// What `$pull` takes out: values equal to its argument, or matching it when
+// it's a selector or a set of operators.
+enum Condition<'a> {
+    Equals(&'a Ejson),
+    Operators(Query),
+    Selector(Query),
+}
+
+impl<'a> Condition<'a> {
+    fn new(arg: &'a Ejson) -> Result<Self, QueryError> {
+        match *arg {
+            Ejson::Object(ref object) if object.keys().all(|key| key.starts_with('$')) => {
+                Ok(Condition::Operators(Query::new(&json!({ "v": arg }))?))
+            },
+            Ejson::Object(_) => Ok(Condition::Selector(Query::new(arg)?)),
+            _ => Ok(Condition::Equals(arg)),
+        }
+    }
+
+    fn matches(&self, item: &Ejson) -> bool {
+        match *self {
+            Condition::Equals(value)       => item == value,
+            Condition::Operators(ref query) => query.matches(&json!({ "v": item })),
+            Condition::Selector(ref query)  => item.is_object() && query.matches(item),
+        }
+    }
+}
+
+// The value at a dotted path, null if it has to be created. Objects are made
+// along the way, and arrays are padded with nulls up to a numeric index.
+fn entry<'a>(doc: &'a mut Ejson, field: &str) -> Result<&'a mut Ejson, QueryError> {
+    let mut value = doc;
+    for part in field.split('.') {
+        if value.is_null() {
+            *value = Ejson::Object(Map::new());
+        }
+        value = match *value {
+            Ejson::Object(ref mut object) => object.entry(part.to_string()).or_insert(Ejson::Null),
+            Ejson::Array(ref mut items) => {
+                let index = part.parse::<usize>()
+                    .map_err(|_| QueryError::new(format!("can't create {} in an array", field)))?;
+                if items.len() <= index {
+                    items.resize(index + 1, Ejson::Null);
+                }
+                &mut items[index]
+            },
+            _ => return Err(QueryError::new(format!("can't create {} in a non-object", field))),
+        };
+    }
+    Ok(value)
+}
+
+fn lookup<'a>(doc: &'a mut Ejson, field: &str) -> Option<&'a mut Ejson> {
+    field.split('.').try_fold(doc, |value, part| match *value {
+        Ejson::Object(ref mut object) => object.get_mut(part),
+        Ejson::Array(ref mut items)   => part.parse::<usize>().ok().and_then(move |index| items.get_mut(index)),
+        _ => None,
+    })
+}
+
+// Removes the value at a dotted path. Array elements are nulled instead, like
+// Mongo does, so the other indexes don't shift.
+fn take(doc: &mut Ejson, field: &str) -> Option<Ejson> {
+    let (parent, last) = match field.rfind('.') {
+        Some(dot) => (lookup(doc, &field[..dot])?, &field[dot + 1..]),
+        None      => (doc, field),
+    };
+    match *parent {
+        Ejson::Object(ref mut object) => object.remove(last),
+        Ejson::Array(ref mut items)   => {
+            let index = last.parse::<usize>().ok().filter(|&index| index < items.len())?;
+            Some(::std::mem::replace(&mut items[index], Ejson::Null))
+        },
+        _ => None,
+    }
+}
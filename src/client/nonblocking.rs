@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use futures::channel::{mpsc, oneshot};
+use futures::{SinkExt, Stream, StreamExt};
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream};
+use websocket::client::Url;
+
+use super::connection::{NegotiateResp, WS, WSS};
+use super::events::CollectionEvent;
+use super::messages::*;
+use super::{DdpConnError, DdpError, TlsConfig};
+use crate::random::Random;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+enum Command {
+    Call(String, String, oneshot::Sender<Result<Ejson, DdpError>>),
+    Sub(String, String, oneshot::Sender<Result<(), DdpError>>),
+    Unsub(String),
+    Watch(String, mpsc::UnboundedSender<CollectionEvent>),
+}
+
+/// A client for async code. The socket is driven by a task spawned on the
+/// current tokio runtime, which lives until the server hangs up or every
+/// handle onto it is dropped. There's no reconnecting: once the socket is
+/// gone, everything pending fails with a `"disconnected"` error.
+#[derive(Clone)]
+pub struct AsyncClient {
+    commands: mpsc::UnboundedSender<Command>,
+    rng:      Arc<Mutex<Random>>,
+    session:  String,
+    version:  &'static str,
+}
+
+impl AsyncClient {
+    pub async fn connect(url: &Url) -> Result<Self, DdpConnError> {
+        AsyncClient::connect_with_tls(url, &TlsConfig::new()).await
+    }
+
+    pub async fn connect_with_tls(url: &Url, tls: &TlsConfig) -> Result<Self, DdpConnError> {
+        let connector = match url.scheme() {
+            WS  => None,
+            WSS => Some(tls.connector()?),
+            _   => return Err(DdpConnError::UrlIsNotWebsocket),
+        };
+        let mut v_index = 0;
+
+        loop {
+            let (mut socket, _) = connect_async_tls_with_config(url.as_str(), None, false,
+                                                                connector.clone().map(Connector::NativeTls))
+                .await.map_err(DdpConnError::Transport)?;
+
+            match negotiate(&mut socket, VERSIONS[v_index]).await? {
+                NegotiateResp::SessionId(session) => {
+                    let (commands, rx) = mpsc::unbounded();
+                    tokio::spawn(drive(socket, rx));
+                    return Ok(AsyncClient {
+                        commands: commands,
+                        rng:      Arc::new(Mutex::new(Random::new())),
+                        session:  session,
+                        version:  VERSIONS[v_index],
+                    });
+                },
+                NegotiateResp::Version(server_version) => {
+                    v_index = match VERSIONS.iter().position(|&v| v == server_version) {
+                        Some(i) => i,
+                        None    => return Err(DdpConnError::NoMatchingVersion),
+                    };
+                },
+            }
+        }
+    }
+
+    /// Resolves with the method's result, as soon as the server sends it. Like
+    /// `Connection::call`, the method carries a fresh `randomSeed`.
+    pub fn call(&self, method: &str, params: Option<&Vec<&Ejson>>)
+    -> impl Future<Output = Result<Ejson, DdpError>> {
+        let (id, seed) = {
+            let mut rng = self.rng.lock().unwrap();
+            (rng.id(), rng.secret())
+        };
+        let (tx, rx) = oneshot::channel();
+        let sent = self.commands.unbounded_send(Command::Call(id.clone(), Method::text(&id, method, params, Some(&seed)), tx));
+
+        async move {
+            sent.map_err(|_| disconnected())?;
+            rx.await.unwrap_or_else(|_| Err(disconnected()))
+        }
+    }
+
+    /// Resolves once the publication is ready, or with the error it was refused with.
+    pub fn subscribe(&self, name: &str, params: Option<&Vec<&Ejson>>)
+    -> impl Future<Output = Result<AsyncSubscription, DdpError>> {
+        let id = self.rng.lock().unwrap().id();
+        let (tx, rx) = oneshot::channel();
+        let sent = self.commands.unbounded_send(Command::Sub(id.clone(), Subscribe::text(&id, name, params), tx));
+        let sub = AsyncSubscription {
+            id:       id,
+            name:     name.to_string(),
+            commands: self.commands.clone(),
+        };
+
+        async move {
+            sent.map_err(|_| disconnected())?;
+            rx.await.unwrap_or_else(|_| Err(disconnected()))?;
+            Ok(sub)
+        }
+    }
+
+    /// Every change the server makes to `collection` from now on. The stream
+    /// ends along with the connection.
+    pub fn watch(&self, collection: &str) -> impl Stream<Item = CollectionEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.commands.unbounded_send(Command::Watch(collection.to_string(), tx)).ok();
+        rx
+    }
+
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    pub fn version(&self) -> &'static str {
+        self.version
+    }
+}
+
+pub struct AsyncSubscription {
+    id:       String,
+    name:     String,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl AsyncSubscription {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn stop(&self) {
+        self.commands.unbounded_send(Command::Unsub(self.id.clone())).ok();
+    }
+}
+
+fn disconnected() -> DdpError {
+    DdpError::local("disconnected", "The connection was closed")
+}
+
+async fn negotiate(socket: &mut Socket, version: &'static str) -> Result<NegotiateResp, DdpConnError> {
+    let request = serde_json::to_string(&Connect::new(version, None)).unwrap();
+    socket.send(Message::Text(request)).await.map_err(DdpConnError::Transport)?;
+
+    while let Some(message) = socket.next().await {
+        let text = match message.map_err(DdpConnError::Transport)? {
+            Message::Text(text) => text,
+            Message::Close(_)   => break,
+            _                   => continue,
+        };
+        let message: Value = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(_)      => continue,
+        };
+        if message.get("server_id").is_some() {
+            continue;
+        }
+        match (message["msg"].as_str(), message["session"].as_str(), message["version"].as_str()) {
+            (Some("connected"), Some(session), _) => return Ok(NegotiateResp::SessionId(session.to_string())),
+            (Some("failed"), _, Some(version))    => return Ok(NegotiateResp::Version(version.to_string())),
+            _ => break,
+        }
+    }
+    Err(DdpConnError::MalformedPacket)
+}
+
+async fn drive(mut socket: Socket, mut commands: mpsc::UnboundedReceiver<Command>) {
+    let mut driver = Driver {
+        methods:  HashMap::new(),
+        subs:     HashMap::new(),
+        watchers: HashMap::new(),
+    };
+
+    loop {
+        let outgoing = tokio::select! {
+            command = commands.next() => match command {
+                Some(command) => driver.command(command),
+                None          => break,
+            },
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(text)))    => driver.handle(&text),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_))                      => None,
+            },
+        };
+        if let Some(text) = outgoing {
+            if socket.send(Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+    }
+    socket.close(None).await.ok();
+
+    for (_, reply) in driver.methods.drain() {
+        reply.send(Err(disconnected())).ok();
+    }
+    for (_, reply) in driver.subs.drain() {
+        reply.send(Err(disconnected())).ok();
+    }
+}
+
+struct Driver {
+    methods:  HashMap<String, oneshot::Sender<Result<Ejson, DdpError>>>,
+    subs:     HashMap<String, oneshot::Sender<Result<(), DdpError>>>,
+    watchers: HashMap<String, Vec<mpsc::UnboundedSender<CollectionEvent>>>,
+}
+
+impl Driver {
+    // Returns what needs sending to the server.
+    fn command(&mut self, command: Command) -> Option<String> {
+        match command {
+            Command::Call(id, text, reply) => {
+                self.methods.insert(id, reply);
+                Some(text)
+            },
+            Command::Sub(id, text, reply) => {
+                self.subs.insert(id, reply);
+                Some(text)
+            },
+            Command::Unsub(id) => {
+                self.subs.remove(&id);
+                Some(Unsubscribe::text(&id))
+            },
+            Command::Watch(collection, events) => {
+                self.watchers.entry(collection).or_insert_with(Vec::new).push(events);
+                None
+            },
+        }
+    }
+
+    // Returns the reply, if the message needs one.
+    fn handle(&mut self, text: &str) -> Option<String> {
+        let message: Value = serde_json::from_str(text).ok()?;
+        let id = message["id"].as_str();
+
+        match message["msg"].as_str()? {
+            "ping" => return Some(Pong::text(id)),
+            "result" => {
+                let reply = self.methods.remove(id?)?;
+                let result = match (message.get("error"), message.get("result")) {
+                    (Some(error), _) => Err(DdpError::from(error)),
+                    (None, result)   => Ok(result.cloned().unwrap_or(Value::Null)),
+                };
+                reply.send(result).ok();
+            },
+            "ready" => {
+                for id in message["subs"].as_array()?.iter().filter_map(|id| id.as_str()) {
+                    if let Some(reply) = self.subs.remove(id) {
+                        reply.send(Ok(())).ok();
+                    }
+                }
+            },
+            "nosub" => {
+                let reply = self.subs.remove(id?)?;
+                let error = match message.get("error") {
+                    Some(error) => DdpError::from(error),
+                    None        => DdpError::local("nosub", "The subscription was stopped"),
+                };
+                reply.send(Err(error)).ok();
+            },
+            "added" | "changed" | "removed" => {
+                let id = id?.to_string();
+                let event = match message["msg"].as_str() {
+                    Some("added") => CollectionEvent::Added {
+                        id:     id,
+                        fields: message.get("fields").cloned(),
+                    },
+                    Some("changed") => CollectionEvent::Changed {
+                        id:      id,
+                        fields:  message.get("fields").cloned(),
+                        cleared: message.get("cleared").cloned(),
+                    },
+                    _ => CollectionEvent::Removed { id: id },
+                };
+                if let Some(watchers) = self.watchers.get_mut(message["collection"].as_str()?) {
+                    watchers.retain(|watcher| watcher.unbounded_send(event.clone()).is_ok());
+                }
+            },
+            _ => {},
+        }
+        None
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::{HashMap, HashSet};
+
+use super::messages::Ejson;
+use super::minimongo::{diff, Documents};
+use super::query::Query;
+
+type DocCallback = Box<Fn(&Ejson) + Send + 'static>;
+type DocBeforeCallback = Box<Fn(&Ejson, Option<&str>) + Send + 'static>;
+type IdCallback = Box<Fn(&str) + Send + 'static>;
+type FieldsCallback = Box<Fn(&str, &Ejson) + Send + 'static>;
+type FieldsBeforeCallback = Box<Fn(&str, &Ejson, Option<&str>) + Send + 'static>;
+type MovedCallback = Box<Fn(&str, Option<&str>) + Send + 'static>;
+
+/// Whole-document callbacks for `Collection::observe`, like Meteor's `observe`.
+/// Ordered callbacks are handed the id of the document that now follows, or
+/// `None` when the document moved to the end.
+pub struct Observe {
+    added:        Option<DocCallback>,
+    added_before: Option<DocBeforeCallback>,
+    changed:      Option<Box<Fn(&Ejson, &Ejson) + Send + 'static>>,
+    moved_before: Option<DocBeforeCallback>,
+    removed:      Option<DocCallback>,
+}
+
+impl Observe {
+    pub fn new() -> Self {
+        Observe {
+            added:        None,
+            added_before: None,
+            changed:      None,
+            moved_before: None,
+            removed:      None,
+        }
+    }
+
+    pub fn on_added<F>(mut self, f: F) -> Self
+    where F: Fn(&Ejson) + Send + 'static {
+        self.added = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_added_before<F>(mut self, f: F) -> Self
+    where F: Fn(&Ejson, Option<&str>) + Send + 'static {
+        self.added_before = Some(Box::new(f));
+        self
+    }
+
+    /// Called with the new and then the old version of the document.
+    pub fn on_changed<F>(mut self, f: F) -> Self
+    where F: Fn(&Ejson, &Ejson) + Send + 'static {
+        self.changed = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_moved_before<F>(mut self, f: F) -> Self
+    where F: Fn(&Ejson, Option<&str>) + Send + 'static {
+        self.moved_before = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_removed<F>(mut self, f: F) -> Self
+    where F: Fn(&Ejson) + Send + 'static {
+        self.removed = Some(Box::new(f));
+        self
+    }
+}
+
+impl Default for Observe {
+    fn default() -> Self {
+        Observe::new()
+    }
+}
+
+/// Field level callbacks for `Collection::observe_changes`, like Meteor's
+/// `observeChanges`. Fields never include `_id`.
+pub struct ObserveChanges {
+    added:        Option<FieldsCallback>,
+    added_before: Option<FieldsBeforeCallback>,
+    changed:      Option<Box<Fn(&str, Option<&Ejson>, Option<&Ejson>) + Send + 'static>>,
+    moved_before: Option<MovedCallback>,
+    removed:      Option<IdCallback>,
+}
+
+impl ObserveChanges {
+    pub fn new() -> Self {
+        ObserveChanges {
+            added:        None,
+            added_before: None,
+            changed:      None,
+            moved_before: None,
+            removed:      None,
+        }
+    }
+
+    pub fn on_added<F>(mut self, f: F) -> Self
+    where F: Fn(&str, &Ejson) + Send + 'static {
+        self.added = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_added_before<F>(mut self, f: F) -> Self
+    where F: Fn(&str, &Ejson, Option<&str>) + Send + 'static {
+        self.added_before = Some(Box::new(f));
+        self
+    }
+
+    /// Same arguments as `Collection::on_change`: the id, the fields that were
+    /// set and an array with the names of the fields that were cleared.
+    pub fn on_changed<F>(mut self, f: F) -> Self
+    where F: Fn(&str, Option<&Ejson>, Option<&Ejson>) + Send + 'static {
+        self.changed = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_moved_before<F>(mut self, f: F) -> Self
+    where F: Fn(&str, Option<&str>) + Send + 'static {
+        self.moved_before = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_removed<F>(mut self, f: F) -> Self
+    where F: Fn(&str) + Send + 'static {
+        self.removed = Some(Box::new(f));
+        self
+    }
+}
+
+impl Default for ObserveChanges {
+    fn default() -> Self {
+        ObserveChanges::new()
+    }
+}
+
+enum Callbacks {
+    Docs(Observe),
+    Fields(ObserveChanges),
+}
+
+impl Callbacks {
+    fn ordered(&self) -> bool {
+        match *self {
+            Callbacks::Docs(ref o)   => o.added_before.is_some() || o.moved_before.is_some(),
+            Callbacks::Fields(ref o) => o.added_before.is_some() || o.moved_before.is_some(),
+        }
+    }
+}
+
+pub enum Change {
+    Added(String, Ejson, Option<String>),
+    Changed(String, Ejson, Ejson),
+    Moved(String, Ejson, Option<String>),
+    Removed(String, Ejson),
+}
+
+/// A query whose results are kept up to date as the cache changes.
+pub struct LiveQuery {
+    query:     Query,
+    ordered:   bool,
+    results:   Vec<(String, Ejson)>,
+    callbacks: Callbacks,
+}
+
+impl LiveQuery {
+    pub fn observe(query: Query, callbacks: Observe) -> Self {
+        LiveQuery::new(query, Callbacks::Docs(callbacks))
+    }
+
+    pub fn observe_changes(query: Query, callbacks: ObserveChanges) -> Self {
+        LiveQuery::new(query, Callbacks::Fields(callbacks))
+    }
+
+    fn new(query: Query, callbacks: Callbacks) -> Self {
+        LiveQuery {
+            ordered:   callbacks.ordered(),
+            query:     query,
+            results:   Vec::new(),
+            callbacks: callbacks,
+        }
+    }
+
+    /// Recomputes the results and returns what changed. `id` is the document
+    /// that was touched and `before` its previous version, if there was one.
+    pub fn refresh(&mut self, docs: &Documents, id: Option<(&str, Option<&Ejson>)>) -> Vec<Change> {
+        if let Some((id, before)) = id {
+            let matched_before = before.map_or(false, |doc| self.query.matches(doc));
+            let matches_now = docs.get(id).map_or(false, |doc| self.query.matches(doc));
+            if !matched_before && !matches_now {
+                return Vec::new();
+            }
+        }
+
+        let results: Vec<(String, Ejson)> = self.query.select(docs.iter()).into_iter()
+            .map(|doc| (doc["_id"].as_str().unwrap_or("").to_string(), self.query.project(doc)))
+            .collect();
+        let changes = if self.ordered {
+            diff_ordered(&self.results, &results)
+        } else {
+            diff_unordered(&self.results, &results)
+        };
+        self.results = results;
+        changes
+    }
+
+    pub fn dispatch(&self, changes: &[Change]) {
+        for change in changes.iter() {
+            match self.callbacks {
+                Callbacks::Docs(ref o)   => dispatch_docs(o, change),
+                Callbacks::Fields(ref o) => dispatch_fields(o, change),
+            }
+        }
+    }
+}
+
+fn dispatch_docs(o: &Observe, change: &Change) {
+    match *change {
+        Change::Added(_, ref doc, ref before) => match (&o.added_before, &o.added) {
+            (&Some(ref f), _)    => f(doc, before.as_ref().map(|b| &b[..])),
+            (&None, &Some(ref f)) => f(doc),
+            _ => {},
+        },
+        Change::Changed(_, ref new, ref old) => if let Some(ref f) = o.changed {
+            f(new, old);
+        },
+        Change::Moved(_, ref doc, ref before) => if let Some(ref f) = o.moved_before {
+            f(doc, before.as_ref().map(|b| &b[..]));
+        },
+        Change::Removed(_, ref old) => if let Some(ref f) = o.removed {
+            f(old);
+        },
+    }
+}
+
+fn dispatch_fields(o: &ObserveChanges, change: &Change) {
+    match *change {
+        Change::Added(ref id, ref doc, ref before) => {
+            let fields = without_id(doc);
+            match (&o.added_before, &o.added) {
+                (&Some(ref f), _)     => f(id, &fields, before.as_ref().map(|b| &b[..])),
+                (&None, &Some(ref f)) => f(id, &fields),
+                _ => {},
+            }
+        },
+        Change::Changed(ref id, ref new, ref old) => if let Some(ref f) = o.changed {
+            let (fields, cleared) = diff(new, old);
+            f(id, fields.as_ref(), cleared.as_ref());
+        },
+        Change::Moved(ref id, _, ref before) => if let Some(ref f) = o.moved_before {
+            f(id, before.as_ref().map(|b| &b[..]));
+        },
+        Change::Removed(ref id, _) => if let Some(ref f) = o.removed {
+            f(id);
+        },
+    }
+}
+
+fn without_id(doc: &Ejson) -> Ejson {
+    let mut fields = doc.clone();
+    if let Ejson::Object(ref mut fields) = fields {
+        fields.remove("_id");
+    }
+    fields
+}
+
+fn diff_unordered(old: &[(String, Ejson)], new: &[(String, Ejson)]) -> Vec<Change> {
+    let before: HashMap<&str, &Ejson> = old.iter().map(|&(ref id, ref doc)| (&id[..], doc)).collect();
+    let after: HashSet<&str> = new.iter().map(|&(ref id, _)| &id[..]).collect();
+    let mut changes = Vec::new();
+
+    for &(ref id, ref doc) in old.iter() {
+        if !after.contains(&id[..]) {
+            changes.push(Change::Removed(id.clone(), doc.clone()));
+        }
+    }
+    for &(ref id, ref doc) in new.iter() {
+        match before.get(&id[..]) {
+            None => changes.push(Change::Added(id.clone(), doc.clone(), None)),
+            Some(old) if *old != doc => changes.push(Change::Changed(id.clone(), doc.clone(), (*old).clone())),
+            Some(_) => {},
+        }
+    }
+    changes
+}
+
+// Removes what's gone, then walks the new order backwards so every document is
+// placed in front of one that is already where it belongs. Documents on the
+// longest run that kept their relative order never move.
+fn diff_ordered(old: &[(String, Ejson)], new: &[(String, Ejson)]) -> Vec<Change> {
+    let position: HashMap<&str, usize> = new.iter().enumerate().map(|(i, &(ref id, _))| (&id[..], i)).collect();
+    let mut changes = Vec::new();
+
+    let mut kept = Vec::new();
+    for &(ref id, ref doc) in old.iter() {
+        match position.get(&id[..]) {
+            Some(&i) => kept.push((i, doc)),
+            None     => changes.push(Change::Removed(id.clone(), doc.clone())),
+        }
+    }
+    let stay: HashSet<usize> = longest_increasing(&kept.iter().map(|&(i, _)| i).collect::<Vec<_>>())
+        .into_iter().collect();
+    let previous: HashMap<usize, &Ejson> = kept.iter().cloned().collect();
+
+    for (i, &(ref id, ref doc)) in new.iter().enumerate().rev() {
+        let before = new.get(i + 1).map(|&(ref id, _)| id.clone());
+        match previous.get(&i) {
+            None => changes.push(Change::Added(id.clone(), doc.clone(), before)),
+            Some(_) if !stay.contains(&i) => changes.push(Change::Moved(id.clone(), doc.clone(), before)),
+            Some(_) => {},
+        }
+    }
+    for (i, &(ref id, ref doc)) in new.iter().enumerate() {
+        if let Some(old) = previous.get(&i) {
+            if *old != doc {
+                changes.push(Change::Changed(id.clone(), doc.clone(), (*old).clone()));
+            }
+        }
+    }
+    changes
+}
+
+// The values of the longest strictly increasing subsequence.
+fn longest_increasing(seq: &[usize]) -> Vec<usize> {
+    let mut tails: Vec<usize> = Vec::new();
+    let mut parent: Vec<Option<usize>> = vec![None; seq.len()];
+
+    for (i, &value) in seq.iter().enumerate() {
+        let at = match tails.binary_search_by(|&t| seq[t].cmp(&value)) {
+            Ok(at) | Err(at) => at,
+        };
+        parent[i] = if at > 0 { Some(tails[at - 1]) } else { None };
+        if at == tails.len() {
+            tails.push(i);
+        } else {
+            tails[at] = i;
+        }
+    }
+
+    let mut longest = Vec::with_capacity(tails.len());
+    let mut next = tails.last().cloned();
+    while let Some(i) = next {
+        longest.push(seq[i]);
+        next = parent[i];
+    }
+    longest
+}
@@ -0,0 +1,484 @@
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+use regex::{Regex, RegexBuilder};
+use serde_json::Map;
+
+use super::messages::Ejson;
+
+/// A Mongo style query evaluated against the local collection cache.
+///
+/// Supports equality, `$eq`, `$ne`, `$gt`, `$gte`, `$lt`, `$lte`, `$in`, `$nin`,
+/// `$exists`, `$regex` (with `$options`), `$not`, `$and`, `$or`, `$nor` and
+/// dotted paths, with sorting, skip, limit and field projections on top.
+#[derive(Clone, Debug)]
+pub struct Query {
+    selector: Selector,
+    sort:     Vec<(String, Order)>,
+    skip:     usize,
+    limit:    Option<usize>,
+    fields:   Option<Projection>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+impl Query {
+    /// Compiles a selector. A bare string selects the document with that `_id`.
+    pub fn new(selector: &Ejson) -> Result<Self, QueryError> {
+        let selector = match *selector {
+            Ejson::String(ref id) => Selector::Field(path("_id"), vec![Condition::Eq(Ejson::String(id.clone()))]),
+            Ejson::Object(ref doc) => Selector::document(doc)?,
+            Ejson::Null => Selector::And(Vec::new()),
+            _ => return Err(QueryError::new("selectors must be objects or ids")),
+        };
+        Ok(Query {
+            selector: selector,
+            sort:     Vec::new(),
+            skip:     0,
+            limit:    None,
+            fields:   None,
+        })
+    }
+
+    /// Matches every document.
+    pub fn all() -> Self {
+        Query {
+            selector: Selector::And(Vec::new()),
+            sort:     Vec::new(),
+            skip:     0,
+            limit:    None,
+            fields:   None,
+        }
+    }
+
+    /// Adds a sort key, earlier keys take precedence.
+    pub fn sort(mut self, field: &str, order: Order) -> Self {
+        self.sort.push((field.to_string(), order));
+        self
+    }
+
+    pub fn skip(mut self, skip: usize) -> Self {
+        self.skip = skip;
+        self
+    }
+
+    /// A limit of 0 means no limit, as in Mongo.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = if limit == 0 { None } else { Some(limit) };
+        self
+    }
+
+    /// Restricts the returned fields, e.g. `{"name": 1}` or `{"secret": 0}`.
+    pub fn fields(mut self, projection: &Ejson) -> Result<Self, QueryError> {
+        self.fields = Some(Projection::new(projection)?);
+        Ok(self)
+    }
+
+    pub fn matches(&self, doc: &Ejson) -> bool {
+        self.selector.matches(doc)
+    }
+
+    /// Filters, sorts, skips, limits and projects `docs`.
+    pub fn run<'a, I>(&self, docs: I) -> Vec<Ejson>
+    where I: IntoIterator<Item = &'a Ejson> {
+        self.select(docs).into_iter().map(|doc| self.project(doc)).collect()
+    }
+
+    // Everything `run` does except the projection.
+    pub(crate) fn select<'a, I>(&self, docs: I) -> Vec<&'a Ejson>
+    where I: IntoIterator<Item = &'a Ejson> {
+        let mut found: Vec<&Ejson> = docs.into_iter().filter(|doc| self.matches(doc)).collect();
+        if !self.sort.is_empty() {
+            found.sort_by(|a, b| self.compare(a, b));
+        }
+        let limit = self.limit.unwrap_or(found.len());
+        found.into_iter().skip(self.skip).take(limit).collect()
+    }
+
+    pub(crate) fn project(&self, doc: &Ejson) -> Ejson {
+        match self.fields {
+            Some(ref fields) => fields.apply(doc),
+            None             => doc.clone(),
+        }
+    }
+
+    fn compare(&self, a: &Ejson, b: &Ejson) -> Ordering {
+        for &(ref field, order) in self.sort.iter() {
+            let path = path(field);
+            let ordering = match order {
+                Order::Ascending  => compare(&sort_key(a, &path, order), &sort_key(b, &path, order)),
+                Order::Descending => compare(&sort_key(b, &path, order), &sort_key(a, &path, order)),
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+#[derive(Debug)]
+pub struct QueryError(String);
+
+impl QueryError {
+    pub(crate) fn new<S: Into<String>>(message: S) -> Self {
+        QueryError(message.into())
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid query: {}", self.0)
+    }
+}
+
+impl Error for QueryError {}
+
+#[derive(Clone, Debug)]
+enum Selector {
+    And(Vec<Selector>),
+    Or(Vec<Selector>),
+    Nor(Vec<Selector>),
+    Field(Vec<String>, Vec<Condition>),
+}
+
+impl Selector {
+    fn document(doc: &Map<String, Ejson>) -> Result<Self, QueryError> {
+        let mut all = Vec::with_capacity(doc.len());
+        for (key, value) in doc.iter() {
+            all.push(match &key[..] {
+                "$and" => Selector::And(Selector::clauses(key, value)?),
+                "$or"  => Selector::Or(Selector::clauses(key, value)?),
+                "$nor" => Selector::Nor(Selector::clauses(key, value)?),
+                _ if key.starts_with('$') => return Err(QueryError::new(format!("unknown operator {}", key))),
+                _ => Selector::Field(path(key), Condition::parse(value)?),
+            });
+        }
+        Ok(Selector::And(all))
+    }
+
+    fn clauses(op: &str, value: &Ejson) -> Result<Vec<Selector>, QueryError> {
+        let clauses = match *value {
+            Ejson::Array(ref clauses) if !clauses.is_empty() => clauses,
+            _ => return Err(QueryError::new(format!("{} needs a non-empty array", op))),
+        };
+        clauses.iter().map(|clause| match *clause {
+            Ejson::Object(ref doc) => Selector::document(doc),
+            _ => Err(QueryError::new(format!("{} clauses must be objects", op))),
+        }).collect()
+    }
+
+    fn matches(&self, doc: &Ejson) -> bool {
+        match *self {
+            Selector::And(ref all) => all.iter().all(|s| s.matches(doc)),
+            Selector::Or(ref any)  => any.iter().any(|s| s.matches(doc)),
+            Selector::Nor(ref any) => !any.iter().any(|s| s.matches(doc)),
+            Selector::Field(ref path, ref conditions) => {
+                let values = lookup(doc, path);
+                conditions.iter().all(|c| c.matches(&values))
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Condition {
+    Eq(Ejson),
+    Ne(Ejson),
+    Cmp(Ordering, bool, Ejson),
+    In(Vec<Ejson>),
+    Nin(Vec<Ejson>),
+    Exists(bool),
+    Regex(Regex),
+    Not(Vec<Condition>),
+}
+
+impl Condition {
+    fn parse(value: &Ejson) -> Result<Vec<Condition>, QueryError> {
+        let ops = match *value {
+            Ejson::Object(ref ops) if is_operator_object(ops) => ops,
+            _ => return Ok(vec![Condition::Eq(value.clone())]),
+        };
+
+        let mut conditions = Vec::with_capacity(ops.len());
+        for (op, arg) in ops.iter() {
+            conditions.push(match &op[..] {
+                "$eq"     => Condition::Eq(arg.clone()),
+                "$ne"     => Condition::Ne(arg.clone()),
+                "$gt"     => Condition::Cmp(Ordering::Greater, false, arg.clone()),
+                "$gte"    => Condition::Cmp(Ordering::Greater, true, arg.clone()),
+                "$lt"     => Condition::Cmp(Ordering::Less, false, arg.clone()),
+                "$lte"    => Condition::Cmp(Ordering::Less, true, arg.clone()),
+                "$in"     => Condition::In(list(op, arg)?),
+                "$nin"    => Condition::Nin(list(op, arg)?),
+                "$exists" => Condition::Exists(truthy(arg)),
+                "$regex"  => Condition::Regex(regex(arg, ops.get("$options"))?),
+                "$options" if ops.contains_key("$regex") => continue,
+                "$not"    => Condition::Not(match *arg {
+                    Ejson::Object(ref not) if is_operator_object(not) => Condition::parse(arg)?,
+                    _ => return Err(QueryError::new("$not needs an operator expression")),
+                }),
+                _ => return Err(QueryError::new(format!("unknown operator {}", op))),
+            });
+        }
+        Ok(conditions)
+    }
+
+    fn matches(&self, values: &[&Ejson]) -> bool {
+        match *self {
+            Condition::Eq(ref expected)   => equals_any(values, expected),
+            Condition::Ne(ref expected)   => !equals_any(values, expected),
+            Condition::In(ref expected)   => expected.iter().any(|e| equals_any(values, e)),
+            Condition::Nin(ref expected)  => !expected.iter().any(|e| equals_any(values, e)),
+            Condition::Exists(exists)     => values.is_empty() != exists,
+            Condition::Not(ref inner)     => !inner.iter().all(|c| c.matches(values)),
+            Condition::Regex(ref regex)   => any_element(values, |v| v.as_str().map_or(false, |s| regex.is_match(s))),
+            Condition::Cmp(wanted, or_equal, ref bound) => any_element(values, |v| {
+                if bracket(v) != bracket(bound) {
+                    return false;
+                }
+                let ordering = compare(v, bound);
+                ordering == wanted || (or_equal && ordering == Ordering::Equal)
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Projection {
+    include: bool,
+    paths:   Vec<Vec<String>>,
+    keep_id: bool,
+}
+
+impl Projection {
+    fn new(spec: &Ejson) -> Result<Self, QueryError> {
+        let spec = match *spec {
+            Ejson::Object(ref spec) => spec,
+            _ => return Err(QueryError::new("field specifiers must be objects")),
+        };
+        let mut include = None;
+        let mut keep_id = true;
+        let mut paths = Vec::new();
+
+        for (field, flag) in spec.iter() {
+            let flag = truthy(flag);
+            if field == "_id" {
+                keep_id = flag;
+                continue;
+            }
+            if include.map_or(false, |include| include != flag) {
+                return Err(QueryError::new("field specifiers can't mix inclusion and exclusion"));
+            }
+            include = Some(flag);
+            paths.push(path(field));
+        }
+
+        Ok(Projection {
+            include: include.unwrap_or(false),
+            paths:   paths,
+            keep_id: keep_id,
+        })
+    }
+
+    fn apply(&self, doc: &Ejson) -> Ejson {
+        let mut projected = if self.include {
+            let mut projected = Ejson::Object(Map::new());
+            for path in self.paths.iter() {
+                copy_path(doc, &mut projected, path);
+            }
+            if let Some(id) = doc.get("_id") {
+                if let Ejson::Object(ref mut projected) = projected {
+                    projected.insert("_id".to_string(), id.clone());
+                }
+            }
+            projected
+        } else {
+            let mut projected = doc.clone();
+            for path in self.paths.iter() {
+                remove_path(&mut projected, path);
+            }
+            projected
+        };
+        if !self.keep_id {
+            if let Ejson::Object(ref mut projected) = projected {
+                projected.remove("_id");
+            }
+        }
+        projected
+    }
+}
+
+fn path(field: &str) -> Vec<String> {
+    field.split('.').map(|part| part.to_string()).collect()
+}
+
+/// Every value found at `path`, descending into arrays along the way.
+fn lookup<'a>(doc: &'a Ejson, path: &[String]) -> Vec<&'a Ejson> {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None        => return vec![doc],
+    };
+    match *doc {
+        Ejson::Object(ref doc) => match doc.get(first) {
+            Some(value) => lookup(value, rest),
+            None        => Vec::new(),
+        },
+        Ejson::Array(ref items) => match first.parse::<usize>() {
+            Ok(index) => items.get(index).map_or(Vec::new(), |item| lookup(item, rest)),
+            Err(_)    => items.iter().flat_map(|item| match *item {
+                Ejson::Object(_) => lookup(item, path),
+                _                => Vec::new(),
+            }).collect(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+fn copy_path(from: &Ejson, to: &mut Ejson, path: &[String]) {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None        => return,
+    };
+    let value = match from.get(first) {
+        Some(value) => value,
+        None        => return,
+    };
+    if let Ejson::Object(ref mut to) = *to {
+        if rest.is_empty() || !value.is_object() {
+            if rest.is_empty() {
+                to.insert(first.clone(), value.clone());
+            }
+            return;
+        }
+        let child = to.entry(first.clone()).or_insert_with(|| Ejson::Object(Map::new()));
+        copy_path(value, child, rest);
+    }
+}
+
+fn remove_path(doc: &mut Ejson, path: &[String]) {
+    if let (Some((first, rest)), &mut Ejson::Object(ref mut doc)) = (path.split_first(), doc) {
+        if rest.is_empty() {
+            doc.remove(first);
+        } else if let Some(child) = doc.get_mut(first) {
+            remove_path(child, rest);
+        }
+    }
+}
+
+fn is_operator_object(doc: &Map<String, Ejson>) -> bool {
+    doc.keys().next().map_or(false, |key| key.starts_with('$')) && !doc.contains_key("$date")
+}
+
+fn list(op: &str, arg: &Ejson) -> Result<Vec<Ejson>, QueryError> {
+    match *arg {
+        Ejson::Array(ref items) => Ok(items.clone()),
+        _ => Err(QueryError::new(format!("{} needs an array", op))),
+    }
+}
+
+fn regex(pattern: &Ejson, options: Option<&Ejson>) -> Result<Regex, QueryError> {
+    let pattern = pattern.as_str().ok_or_else(|| QueryError::new("$regex needs a string"))?;
+    let options = options.and_then(|o| o.as_str()).unwrap_or("");
+    RegexBuilder::new(pattern)
+        .case_insensitive(options.contains('i'))
+        .multi_line(options.contains('m'))
+        .dot_matches_new_line(options.contains('s'))
+        .ignore_whitespace(options.contains('x'))
+        .build()
+        .map_err(|e| QueryError::new(format!("bad $regex: {}", e)))
+}
+
+fn truthy(value: &Ejson) -> bool {
+    match *value {
+        Ejson::Null         => false,
+        Ejson::Bool(b)      => b,
+        Ejson::Number(ref n) => n.as_f64().map_or(false, |n| n != 0.0),
+        _                   => true,
+    }
+}
+
+fn any_element<F>(values: &[&Ejson], f: F) -> bool
+where F: Fn(&Ejson) -> bool {
+    values.iter().any(|value| f(value) || match **value {
+        Ejson::Array(ref items) => items.iter().any(|item| f(item)),
+        _ => false,
+    })
+}
+
+fn equals_any(values: &[&Ejson], expected: &Ejson) -> bool {
+    // `{field: null}` also matches documents without the field.
+    if values.is_empty() {
+        return expected.is_null();
+    }
+    any_element(values, |v| compare(v, expected) == Ordering::Equal && bracket(v) == bracket(expected))
+}
+
+fn sort_key(doc: &Ejson, path: &[String], order: Order) -> Ejson {
+    let mut keys: Vec<&Ejson> = Vec::new();
+    for value in lookup(doc, path) {
+        match *value {
+            Ejson::Array(ref items) if !items.is_empty() => keys.extend(items.iter()),
+            _ => keys.push(value),
+        }
+    }
+    // Arrays sort by their smallest element ascending and their largest descending.
+    let key = match order {
+        Order::Ascending  => keys.into_iter().min_by(|a, b| compare(a, b)),
+        Order::Descending => keys.into_iter().max_by(|a, b| compare(a, b)),
+    };
+    key.cloned().unwrap_or(Ejson::Null)
+}
+
+// Mongo's ordering between values of different types.
+fn bracket(value: &Ejson) -> u8 {
+    match *value {
+        Ejson::Null      => 0,
+        Ejson::Number(_) => 1,
+        Ejson::String(_) => 2,
+        Ejson::Object(ref o) if o.len() == 1 && o.contains_key("$date") => 6,
+        Ejson::Object(_) => 3,
+        Ejson::Array(_)  => 4,
+        Ejson::Bool(_)   => 5,
+    }
+}
+
+/// Total order over values, following Mongo's sort order.
+pub(crate) fn compare(a: &Ejson, b: &Ejson) -> Ordering {
+    let (ta, tb) = (bracket(a), bracket(b));
+    if ta != tb {
+        return ta.cmp(&tb);
+    }
+    match (a, b) {
+        (&Ejson::Number(ref a), &Ejson::Number(ref b)) => {
+            let (a, b) = (a.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0));
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        },
+        (&Ejson::String(ref a), &Ejson::String(ref b)) => a.cmp(b),
+        (&Ejson::Bool(a), &Ejson::Bool(b)) => a.cmp(&b),
+        (&Ejson::Array(ref a), &Ejson::Array(ref b)) => {
+            for (a, b) in a.iter().zip(b.iter()) {
+                let ordering = compare(a, b);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            a.len().cmp(&b.len())
+        },
+        (&Ejson::Object(ref a), &Ejson::Object(ref b)) if ta == 6 => compare(&a["$date"], &b["$date"]),
+        (&Ejson::Object(ref a), &Ejson::Object(ref b)) => {
+            for ((ka, va), (kb, vb)) in a.iter().zip(b.iter()) {
+                let ordering = ka.cmp(kb).then_with(|| compare(va, vb));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            a.len().cmp(&b.len())
+        },
+        _ => Ordering::Equal,
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+type StatusListener = Box<Fn(&Status) + Send + 'static>;
+
+/// What a connection is up to, after Meteor's `Meteor.status()`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum State {
+    Connecting,
+    Connected,
+    /// Waiting to reconnect at `retry_time`, after `retry_count` failed attempts.
+    Waiting {
+        retry_count: u32,
+        retry_time:  SystemTime,
+    },
+    /// Not connected, and not trying to be: a `Connection` doesn't reconnect on
+    /// its own, and neither does a `Client` without a retry policy.
+    Offline,
+    /// The retry policy gave up.
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    state:      State,
+    last_error: Option<String>,
+}
+
+impl Status {
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn connected(&self) -> bool {
+        self.state == State::Connected
+    }
+
+    /// Why the connection last went down or failed to come back, kept after
+    /// it's back up.
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_ref().map(|error| &error[..])
+    }
+}
+
+/// The current status and whoever is listening for changes to it. Listeners
+/// run one transition at a time, with their own lock held, so they can read
+/// the status but not add listeners.
+pub struct Statuses {
+    current:   Mutex<Status>,
+    listeners: Mutex<Vec<StatusListener>>,
+}
+
+impl Statuses {
+    pub fn new() -> Self {
+        Statuses {
+            current:   Mutex::new(Status {
+                state:      State::Connected,
+                last_error: None,
+            }),
+            listeners: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn get(&self) -> Status {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Moves to `state`, with `error` as the last error if there is one.
+    pub fn set(&self, state: State, error: Option<String>) {
+        let listeners = self.listeners.lock().unwrap();
+        let status = {
+            let mut current = self.current.lock().unwrap();
+            let status = Status {
+                state:      state,
+                last_error: error.or_else(|| current.last_error.clone()),
+            };
+            if *current == status {
+                return;
+            }
+            *current = status.clone();
+            status
+        };
+        for listener in listeners.iter() {
+            listener(&status);
+        }
+    }
+
+    pub fn listen(&self, f: StatusListener) {
+        self.listeners.lock().unwrap().push(f);
+    }
+}
@@ -0,0 +1,197 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use native_tls::{Certificate, HandshakeError, Identity, TlsConnector, TlsStream};
+use websocket::client::Url;
+use websocket::stream::sync::{AsTcpStream, Splittable};
+
+use super::connection::DdpConnError;
+
+/// How `wss://` connections are secured. The defaults verify the server against
+/// the system's root certificates.
+#[derive(Clone)]
+pub struct TlsConfig {
+    roots:                Vec<Certificate>,
+    identity:             Option<Identity>,
+    accept_invalid_certs: bool,
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        TlsConfig {
+            roots:                Vec::new(),
+            identity:             None,
+            accept_invalid_certs: false,
+        }
+    }
+
+    /// Trusts `cert` on top of the system's root certificates.
+    pub fn add_root_certificate(&mut self, cert: Certificate) -> &mut Self {
+        self.roots.push(cert);
+        self
+    }
+
+    /// Presents `identity` to servers that ask for a client certificate.
+    pub fn identity(&mut self, identity: Identity) -> &mut Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Skips certificate verification entirely. Only ever use this for testing.
+    pub fn danger_accept_invalid_certs(&mut self, accept: bool) -> &mut Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    pub(crate) fn connector(&self) -> Result<TlsConnector, DdpConnError> {
+        let mut builder = TlsConnector::builder();
+        for cert in self.roots.iter() {
+            builder.add_root_certificate(cert.clone());
+        }
+        if let Some(ref identity) = self.identity {
+            builder.identity(identity.clone());
+        }
+        builder.danger_accept_invalid_certs(self.accept_invalid_certs);
+        builder.build().map_err(|e| DdpConnError::Tls(e))
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig::new()
+    }
+}
+
+/// The socket underneath a connection, either plain TCP or TLS over TCP.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(SharedTls),
+}
+
+impl Stream {
+    pub fn open(url: &Url, tls: Option<&TlsConnector>) -> Result<Stream, DdpConnError> {
+        let host = url.host_str().ok_or(DdpConnError::UrlIsNotWebsocket)?;
+        let port = url.port_or_known_default().ok_or(DdpConnError::UrlIsNotWebsocket)?;
+        let tcp = TcpStream::connect((host, port)).map_err(|e| DdpConnError::IoError(e))?;
+
+        let tls = match tls {
+            Some(tls) => tls,
+            None      => return Ok(Stream::Plain(tcp)),
+        };
+        let raw = tcp.try_clone().map_err(|e| DdpConnError::IoError(e))?;
+        let stream = tls.connect(host, tcp).map_err(|e| match e {
+            HandshakeError::Failure(e) => DdpConnError::Tls(e),
+            HandshakeError::WouldBlock(_) => {
+                DdpConnError::IoError(io::Error::new(io::ErrorKind::WouldBlock, "TLS handshake interrupted"))
+            },
+        })?;
+        // Reads give up the lock every so often so the writing half can get a turn.
+        raw.set_read_timeout(Some(Duration::from_millis(TLS_POLL_MS)))
+            .map_err(|e| DdpConnError::IoError(e))?;
+
+        Ok(Stream::Tls(SharedTls {
+            tls:     Arc::new(Mutex::new(stream)),
+            writers: Arc::new(AtomicUsize::new(0)),
+            tcp:     raw,
+        }))
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.read(buf),
+            Stream::Tls(ref mut s)   => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.write(buf),
+            Stream::Tls(ref mut s)   => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) => s.flush(),
+            Stream::Tls(ref mut s)   => s.flush(),
+        }
+    }
+}
+
+impl Splittable for Stream {
+    type Reader = Stream;
+    type Writer = Stream;
+
+    fn split(self) -> io::Result<(Stream, Stream)> {
+        match self {
+            Stream::Plain(s) => {
+                let reader = s.try_clone()?;
+                Ok((Stream::Plain(reader), Stream::Plain(s)))
+            },
+            Stream::Tls(s) => {
+                let reader = SharedTls {
+                    tls:     s.tls.clone(),
+                    writers: s.writers.clone(),
+                    tcp:     s.tcp.try_clone()?,
+                };
+                Ok((Stream::Tls(reader), Stream::Tls(s)))
+            },
+        }
+    }
+}
+
+impl AsTcpStream for Stream {
+    fn as_tcp(&self) -> &TcpStream {
+        match *self {
+            Stream::Plain(ref s) => s,
+            Stream::Tls(ref s)   => &s.tcp,
+        }
+    }
+}
+
+/// A TLS session can't be split like a `TcpStream`, so both halves share it.
+pub struct SharedTls {
+    tls:     Arc<Mutex<TlsStream<TcpStream>>>,
+    writers: Arc<AtomicUsize>,
+    tcp:     TcpStream,
+}
+
+impl Read for SharedTls {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.tls.lock().unwrap().read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                           || e.kind() == io::ErrorKind::TimedOut => {},
+                result => return result,
+            }
+            while self.writers.load(Ordering::SeqCst) > 0 {
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+impl Write for SharedTls {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writers.fetch_add(1, Ordering::SeqCst);
+        let result = self.tls.lock().unwrap().write(buf);
+        self.writers.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writers.fetch_add(1, Ordering::SeqCst);
+        let result = self.tls.lock().unwrap().flush();
+        self.writers.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+}
+
+const TLS_POLL_MS: u64 = 50;
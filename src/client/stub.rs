@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use super::connection::{Collection, Connection};
+use super::messages::Ejson;
+use super::minimongo::id_string;
+use super::modifier;
+use super::query::{Query, QueryError};
+
+use crate::random::RandomStream;
+
+/// What a method stub gets to work with. Writes made through it go into a
+/// layer of their own, on top of what the server published, and are dropped
+/// once the server's `updated` says the method's real writes have arrived, or
+/// the method fails before reaching it. Writing to the collections directly
+/// sends the writes to the server instead, as methods of their own.
+///
+/// Ids of inserted documents come from the method's `randomSeed`, so they
+/// match the ones the server picks when running the same inserts.
+pub struct StubContext<'a> {
+    conn:   &'a Connection,
+    method: &'a str,
+    seed:   &'a str,
+    random: RandomStream,
+    layers: Vec<Arc<Collection>>,
+}
+
+impl<'a> StubContext<'a> {
+    pub(crate) fn new(conn: &'a Connection, method: &'a str, seed: &'a str) -> Self {
+        StubContext {
+            conn:   conn,
+            method: method,
+            seed:   seed,
+            random: RandomStream::new(seed),
+            layers: Vec::new(),
+        }
+    }
+
+    pub(crate) fn into_layers(self) -> Vec<Arc<Collection>> {
+        self.layers
+    }
+
+    /// The id of the method being simulated.
+    pub fn method_id(&self) -> &str {
+        self.method
+    }
+
+    pub fn user_id(&self) -> Option<String> {
+        self.conn.user_id()
+    }
+
+    pub fn random_seed(&self) -> &str {
+        self.seed
+    }
+
+    /// The same stream the server has for the method, see `RandomStream`.
+    pub fn random_stream(&mut self) -> &mut RandomStream {
+        &mut self.random
+    }
+
+    /// Returns the document's id, generated like the server does without an
+    /// `_id`. `None` if `doc` isn't a document.
+    pub fn insert(&mut self, collection: &str, doc: &Ejson) -> Option<String> {
+        let mut doc = doc.clone();
+        let id = match doc.as_object_mut() {
+            Some(fields) => match fields.get("_id") {
+                Some(id) => id_string(id),
+                None     => {
+                    let id = self.random.sequence(&format!("/collection/{}", collection)).id();
+                    fields.insert("_id".to_string(), json!(id));
+                    id
+                },
+            },
+            None => return None,
+        };
+        self.layer(collection).simulate(self.method, &id, Some(doc));
+        Some(id)
+    }
+
+    /// Modifies the first document matching `selector`, which is either a
+    /// Mongo selector or an id. Returns how many documents were modified.
+    pub fn update(&mut self, collection: &str, selector: &Ejson, modifier: &Ejson) -> Result<usize, QueryError> {
+        self.modify(collection, selector, modifier, false)
+    }
+
+    /// Like `update`, but modifies every document matching `selector`.
+    pub fn update_all(&mut self, collection: &str, selector: &Ejson, modifier: &Ejson) -> Result<usize, QueryError> {
+        self.modify(collection, selector, modifier, true)
+    }
+
+    /// Removes every document matching `selector`, returning how many there were.
+    pub fn remove(&mut self, collection: &str, selector: &Ejson) -> Result<usize, QueryError> {
+        let query = query(selector)?;
+        let layer = self.layer(collection);
+        let removed = layer.find(&query);
+        for doc in removed.iter() {
+            layer.simulate(self.method, &id_string(&doc["_id"]), None);
+        }
+        Ok(removed.len())
+    }
+
+    /// Reads see the writes of every stub that hasn't settled yet.
+    pub fn find(&self, collection: &str, query: &Query) -> Vec<Ejson> {
+        self.conn.mongo(collection.to_string()).find(query)
+    }
+
+    pub fn find_one(&self, collection: &str, id: &str) -> Option<Ejson> {
+        self.conn.mongo(collection.to_string()).find_one(id)
+    }
+
+    // Every document is modified before any of them is written, so a bad
+    // modifier leaves the collection alone.
+    fn modify(&mut self, collection: &str, selector: &Ejson, modifier: &Ejson, all: bool) -> Result<usize, QueryError> {
+        let query = query(selector)?;
+        let query = if all { query } else { query.limit(1) };
+        let layer = self.layer(collection);
+        let modified = layer.find(&query).iter()
+            .map(|doc| Ok((id_string(&doc["_id"]), modifier::apply(doc, modifier)?)))
+            .collect::<Result<Vec<_>, QueryError>>()?;
+        for &(ref id, ref doc) in modified.iter() {
+            layer.simulate(self.method, id, Some(doc.clone()));
+        }
+        Ok(modified.len())
+    }
+
+    fn layer(&mut self, collection: &str) -> Arc<Collection> {
+        let mongo = self.conn.mongo(collection.to_string());
+        if !self.layers.iter().any(|layer| layer.name() == collection) {
+            self.layers.push(mongo.clone());
+        }
+        mongo
+    }
+}
+
+fn query(selector: &Ejson) -> Result<Query, QueryError> {
+    match selector.as_str() {
+        Some(id) => Query::new(&json!({ "_id": id })),
+        None     => Query::new(selector),
+    }
+}
@@ -0,0 +1,74 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::mpsc::Sender as AtomicSender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Task {
+    at: Instant,
+    f:  Box<FnOnce() + Send + 'static>,
+}
+
+// Earliest first in a BinaryHeap, which pops the greatest.
+impl Ord for Task {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.at.cmp(&self.at)
+    }
+}
+
+impl PartialOrd for Task {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Task {
+    fn eq(&self, other: &Self) -> bool {
+        self.at == other.at
+    }
+}
+
+impl Eq for Task {}
+
+/// Runs closures after a delay, one after the other on a thread of its own.
+/// The thread goes away with the timer, along with whatever hasn't run yet.
+pub struct Timer {
+    tasks: Mutex<AtomicSender<Task>>,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut tasks: BinaryHeap<Task> = BinaryHeap::new();
+            loop {
+                let received = match tasks.peek() {
+                    Some(next) => rx.recv_timeout(next.at.saturating_duration_since(Instant::now())),
+                    None       => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(task) => tasks.push(task),
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+                while tasks.peek().map_or(false, |next| next.at <= Instant::now()) {
+                    (tasks.pop().unwrap().f)();
+                }
+            }
+        });
+        Timer {
+            tasks: Mutex::new(tx),
+        }
+    }
+
+    pub fn after<F>(&self, delay: Duration, f: F)
+    where F: FnOnce() + Send + 'static {
+        let task = Task {
+            at: Instant::now() + delay,
+            f:  Box::new(f),
+        };
+        self.tasks.lock().unwrap().send(task).ok();
+    }
+}
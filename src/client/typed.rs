@@ -0,0 +1,162 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::vec;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::connection::{Collection, ListenerId};
+use super::error::DdpError;
+use super::messages::Ejson;
+use super::query::Query;
+use super::Subscription;
+
+/// A `Collection` whose documents are decoded into `T`. Documents come back
+/// whole, `_id` included, so `T` can pick it up with `#[serde(rename = "_id")]`.
+/// Anything that doesn't decode into `T` is skipped by the cache and the
+/// listeners alike; the untyped collection still sees it. Extended types
+/// reach `T` as their wire JSON, a date as `{"$date": ...}`, see `EjsonValue`.
+pub struct TypedCollection<T> {
+    inner: Arc<Collection>,
+    doc:   PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for TypedCollection<T> {
+    fn clone(&self) -> Self {
+        TypedCollection::from(self.inner.clone())
+    }
+}
+
+impl<T> From<Arc<Collection>> for TypedCollection<T> {
+    fn from(inner: Arc<Collection>) -> Self {
+        TypedCollection {
+            inner: inner,
+            doc:   PhantomData,
+        }
+    }
+}
+
+impl<T> TypedCollection<T>
+where T: DeserializeOwned + Serialize {
+    pub fn new(inner: Arc<Collection>) -> Self {
+        TypedCollection::from(inner)
+    }
+
+    /// The untyped collection underneath, for anything not covered here.
+    pub fn collection(&self) -> &Arc<Collection> {
+        &self.inner
+    }
+
+    /// Called with the whole document once it's added.
+    pub fn on_add<F>(&self, f: F) -> ListenerId
+    where F: Fn(&str, T) + Send + 'static {
+        let inner = Arc::downgrade(&self.inner);
+        self.inner.on_add(move |id, _| {
+            if let Some(doc) = inner.upgrade().and_then(|inner| decode(inner.find_one(id))) {
+                f(id, doc);
+            }
+        })
+    }
+
+    /// Called with the whole document as it is after the change.
+    pub fn on_change<F>(&self, f: F) -> ListenerId
+    where F: Fn(&str, T) + Send + 'static {
+        let inner = Arc::downgrade(&self.inner);
+        self.inner.on_change(move |id, _, _| {
+            if let Some(doc) = inner.upgrade().and_then(|inner| decode(inner.find_one(id))) {
+                f(id, doc);
+            }
+        })
+    }
+
+    pub fn on_remove<F>(&self, f: F) -> ListenerId
+    where F: Fn(&str) + Send + 'static {
+        self.inner.on_remove(f)
+    }
+
+    pub fn clear_listener(&self, id: ListenerId) {
+        self.inner.clear_listener(id)
+    }
+
+    /// Returns the document's id, see `Collection::insert`.
+    pub fn insert<F>(&self, record: &T, mut callback: F) -> Option<String>
+    where F: FnMut(Result<&Ejson, &DdpError>) + Send + 'static {
+        match encode(record) {
+            Ok(record) => self.inner.insert(&record, callback),
+            Err(error) => {
+                callback(Err(&error));
+                None
+            },
+        }
+    }
+
+    /// `modifier` is anything that serializes to a Mongo modifier, or a `T`
+    /// to replace the document outright.
+    pub fn update<M, F>(&self, selector: &Ejson, modifier: &M, mut callback: F)
+    where M: Serialize,
+          F: FnMut(Result<&Ejson, &DdpError>) + Send + 'static {
+        match encode(modifier) {
+            Ok(modifier) => self.inner.update(selector, &modifier, callback),
+            Err(error)   => callback(Err(&error)),
+        }
+    }
+
+    pub fn upsert<M, F>(&self, selector: &Ejson, modifier: &M, mut callback: F)
+    where M: Serialize,
+          F: FnMut(Result<&Ejson, &DdpError>) + Send + 'static {
+        match encode(modifier) {
+            Ok(modifier) => self.inner.upsert(selector, &modifier, callback),
+            Err(error)   => callback(Err(&error)),
+        }
+    }
+
+    pub fn remove<F>(&self, selector: &Ejson, callback: F)
+    where F: FnMut(Result<&Ejson, &DdpError>) + Send + 'static {
+        self.inner.remove(selector, callback)
+    }
+
+    pub fn subscribe(&self) -> Subscription {
+        self.inner.subscribe()
+    }
+
+    pub fn unsubscribe(&self) {
+        self.inner.unsubscribe()
+    }
+
+    pub fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    pub fn find_one(&self, id: &str) -> Option<T> {
+        decode(self.inner.find_one(id))
+    }
+
+    pub fn all(&self) -> Vec<T> {
+        self.inner.all().into_iter().filter_map(|doc| decode(Some(doc))).collect()
+    }
+
+    pub fn find(&self, query: &Query) -> Vec<T> {
+        self.inner.find(query).into_iter().filter_map(|doc| decode(Some(doc))).collect()
+    }
+
+    pub fn iter(&self) -> vec::IntoIter<T> {
+        self.all().into_iter()
+    }
+
+    /// Counts every cached document, including ones that don't decode.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+fn decode<T: DeserializeOwned>(doc: Option<Ejson>) -> Option<T> {
+    doc.and_then(|doc| serde_json::from_value(doc).ok())
+}
+
+fn encode<S: Serialize>(value: &S) -> Result<Ejson, DdpError> {
+    serde_json::to_value(value).map_err(|e| DdpError::local("invalid-document", &e.to_string()))
+}
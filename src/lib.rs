@@ -1,11 +1,20 @@
 // #[macro_use] 
 extern crate log;
 extern crate websocket;
+extern crate native_tls;
+extern crate regex;
+extern crate sha2;
+#[cfg(feature = "async")] extern crate futures;
+#[cfg(feature = "async")] extern crate tokio;
+#[cfg(feature = "async")] extern crate tokio_tungstenite;
 #[macro_use] extern crate serde_derive;
 extern crate serde;
 #[macro_use] extern crate serde_json;
 
 mod random;
 pub mod client;
+pub mod server;
+#[cfg(feature = "test-server")] pub mod mock;
 pub use client::Connection;
-pub use websocket::client::Url;
\ No newline at end of file
+pub use websocket::client::Url;
+pub use native_tls::{Certificate, Identity};
\ No newline at end of file
@@ -0,0 +1,363 @@
+//! A DDP server that runs inside the test process, so `Connection` and
+//! `Collection` can be exercised without a Meteor server. Enabled by the
+//! `test-server` feature.
+//!
+//! Methods and subscriptions are answered by scripts registered by name;
+//! anything unscripted gets the 404 Meteor would send. Everything a client
+//! sends is recorded, and data messages can be pushed at any time.
+
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{self, Value as Ejson};
+use websocket::Message;
+use websocket::client::Url;
+use websocket::sync::{Reader, Writer};
+
+use crate::client::messages::{Added, Changed, Connected, Failed, MethodResult, NoSub, Ping, Pong, Ready, Removed,
+                              Updated, VERSIONS};
+use crate::server::{meteor_error, negotiate, next_text, Gone, Listener};
+
+type MethodScript = Box<Fn(&Peer, &[Ejson]) -> Reply + Send + Sync + 'static>;
+type SubScript = Box<Fn(&Peer, &[Ejson]) -> Result<(), Ejson> + Send + Sync + 'static>;
+type UnsubScript = Box<Fn(&Peer, &[Ejson]) + Send + Sync + 'static>;
+
+/// How a scripted method answers.
+pub enum Reply {
+    /// Sends the `result`, then `updated`.
+    Result(Ejson),
+    /// Sends the error as the `result`, then `updated`.
+    Error(Ejson),
+    /// Sends nothing, leaving the method pending. The test can answer it
+    /// later through the `Peer`.
+    Silence,
+}
+
+/// A `Meteor.Error` as it goes over the wire.
+pub fn error(code: Ejson, reason: &str) -> Ejson {
+    meteor_error(code, reason)
+}
+
+struct Shared {
+    methods:  Mutex<HashMap<String, MethodScript>>,
+    subs:     Mutex<HashMap<String, SubScript>>,
+    unsubs:   Mutex<HashMap<String, UnsubScript>>,
+    versions: Mutex<Vec<String>>,
+    peers:    Mutex<Vec<Peer>>,
+    received: Mutex<Sender<Ejson>>,
+    sessions: AtomicUsize,
+    pongs:    AtomicBool,
+    refusing: AtomicBool,
+}
+
+/// Listens on a free local port until dropped.
+pub struct MockServer {
+    listener: Arc<Listener>,
+    shared:   Arc<Shared>,
+    received: Mutex<Receiver<Ejson>>,
+}
+
+impl MockServer {
+    pub fn start() -> Self {
+        let (tx, rx) = channel();
+        let shared = Arc::new(Shared {
+            methods:  Mutex::new(HashMap::new()),
+            subs:     Mutex::new(HashMap::new()),
+            unsubs:   Mutex::new(HashMap::new()),
+            versions: Mutex::new(VERSIONS.iter().map(|v| v.to_string()).collect()),
+            peers:    Mutex::new(Vec::new()),
+            received: Mutex::new(tx),
+            sessions: AtomicUsize::new(0),
+            pongs:    AtomicBool::new(true),
+            refusing: AtomicBool::new(false),
+        });
+
+        let serving = shared.clone();
+        let (listener, _) = Listener::spawn("127.0.0.1:0", move |client| {
+            if serving.refusing.load(Ordering::SeqCst) {
+                return;
+            }
+            if let Ok((reader, writer)) = client.split() {
+                let peer = Peer {
+                    writer:  Arc::new(Mutex::new(writer)),
+                    session: Arc::new(Mutex::new(None)),
+                };
+                serving.peers.lock().unwrap().push(peer.clone());
+                serve(&serving, peer, reader);
+            }
+        }).expect("mock server could not bind");
+
+        MockServer {
+            listener: listener,
+            shared:   shared,
+            received: Mutex::new(rx),
+        }
+    }
+
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("ws://{}/websocket", self.listener.local_addr())).unwrap()
+    }
+
+    /// Answers calls to `name` with whatever `f` returns for their params.
+    pub fn method<F>(&self, name: &str, f: F)
+    where F: Fn(&Peer, &[Ejson]) -> Reply + Send + Sync + 'static {
+        self.shared.methods.lock().unwrap().insert(name.to_string(), Box::new(f));
+    }
+
+    /// Runs `f` for each subscription to `name`. It can push documents through
+    /// the `Peer`, then `ready` is sent if it returns `Ok`, and `nosub` with
+    /// the error otherwise.
+    pub fn publish<F>(&self, name: &str, f: F)
+    where F: Fn(&Peer, &[Ejson]) -> Result<(), Ejson> + Send + Sync + 'static {
+        self.shared.subs.lock().unwrap().insert(name.to_string(), Box::new(f));
+    }
+
+    /// Runs `f` when a client stops a subscription to `name`, with the params
+    /// it subscribed with, before `nosub` is sent. There's no merge box, so
+    /// this is where the subscription's documents are taken back.
+    pub fn on_unsub<F>(&self, name: &str, f: F)
+    where F: Fn(&Peer, &[Ejson]) + Send + Sync + 'static {
+        self.shared.unsubs.lock().unwrap().insert(name.to_string(), Box::new(f));
+    }
+
+    /// The protocol versions the server speaks, best first. A client asking
+    /// for anything else is told to use the first one it also supports.
+    pub fn versions(&self, versions: &[&str]) {
+        *self.shared.versions.lock().unwrap() = versions.iter().map(|v| v.to_string()).collect();
+    }
+
+    /// Whether DDP pings get a pong, which they do unless told otherwise.
+    pub fn answer_pings(&self, answer: bool) {
+        self.shared.pongs.store(answer, Ordering::SeqCst);
+    }
+
+    /// While set, clients are hung up on as soon as they connect, so a
+    /// reconnecting client keeps failing until it's cleared.
+    pub fn refuse(&self, refuse: bool) {
+        self.shared.refusing.store(refuse, Ordering::SeqCst);
+    }
+
+    /// The next message from any client, waiting up to `timeout`. A client
+    /// closing the socket cleanly shows up as `{ "msg": "close" }`, which
+    /// isn't a DDP message.
+    pub fn next_message(&self, timeout: Duration) -> Option<Ejson> {
+        self.received.lock().unwrap().recv_timeout(timeout).ok()
+    }
+
+    /// Skips ahead to the next message of kind `msg`, waiting up to five
+    /// seconds before panicking.
+    pub fn expect(&self, msg: &str) -> Ejson {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.next_message(left) {
+                Some(message) => if message["msg"] == msg {
+                    return message;
+                },
+                None => panic!("no {} message arrived", msg),
+            }
+        }
+    }
+
+    /// The most recent client to connect, waiting up to five seconds for one.
+    pub fn peer(&self) -> Peer {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(peer) = self.shared.peers.lock().unwrap().last() {
+                return peer.clone();
+            }
+            if Instant::now() >= deadline {
+                panic!("no client connected");
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// How many clients have completed the `connect` handshake.
+    pub fn sessions(&self) -> usize {
+        self.shared.sessions.load(Ordering::SeqCst)
+    }
+
+    pub fn added(&self, collection: &str, id: &str, fields: Ejson) {
+        self.broadcast(|peer| peer.added(collection, id, fields.clone()));
+    }
+
+    pub fn changed(&self, collection: &str, id: &str, fields: Ejson, cleared: &[&str]) {
+        self.broadcast(|peer| peer.changed(collection, id, fields.clone(), cleared));
+    }
+
+    pub fn removed(&self, collection: &str, id: &str) {
+        self.broadcast(|peer| peer.removed(collection, id));
+    }
+
+    pub fn ready(&self, subs: &[&str]) {
+        self.broadcast(|peer| peer.ready(subs));
+    }
+
+    pub fn nosub(&self, id: &str, error: Option<Ejson>) {
+        self.broadcast(|peer| peer.nosub(id, error.clone()));
+    }
+
+    pub fn ping(&self, id: Option<&str>) {
+        self.broadcast(|peer| peer.ping(id));
+    }
+
+    /// Drops every client, as if the server had gone away, but keeps
+    /// listening for them to come back.
+    pub fn hang_up(&self) {
+        self.shared.peers.lock().unwrap().clear();
+        self.listener.hang_up();
+    }
+
+    fn broadcast<F>(&self, f: F) where F: Fn(&Peer) {
+        for peer in self.shared.peers.lock().unwrap().iter() {
+            f(peer);
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.listener.stop();
+    }
+}
+
+/// One connected client.
+#[derive(Clone)]
+pub struct Peer {
+    writer:  Arc<Mutex<Writer<TcpStream>>>,
+    session: Arc<Mutex<Option<String>>>,
+}
+
+impl Peer {
+    /// The session id the client was given, once it has connected.
+    pub fn session(&self) -> Option<String> {
+        self.session.lock().unwrap().clone()
+    }
+
+    /// Sends any message. Errors are ignored, as the client may be gone.
+    pub fn send(&self, message: &Ejson) {
+        self.send_text(message.to_string());
+    }
+
+    pub fn added(&self, collection: &str, id: &str, fields: Ejson) {
+        self.send_text(Added::text(collection, id, &fields));
+    }
+
+    pub fn changed(&self, collection: &str, id: &str, fields: Ejson, cleared: &[&str]) {
+        self.send_text(Changed::text(collection, id, &fields, cleared));
+    }
+
+    pub fn removed(&self, collection: &str, id: &str) {
+        self.send_text(Removed::text(collection, id));
+    }
+
+    pub fn ready(&self, subs: &[&str]) {
+        self.send_text(Ready::text(subs));
+    }
+
+    pub fn nosub(&self, id: &str, error: Option<Ejson>) {
+        self.send_text(NoSub::text(id, error.as_ref()));
+    }
+
+    pub fn ping(&self, id: Option<&str>) {
+        self.send_text(Ping::text(id));
+    }
+
+    pub fn result(&self, id: &str, result: Result<Ejson, Ejson>) {
+        self.send_text(MethodResult::text(id, result.as_ref()));
+    }
+
+    pub fn updated(&self, methods: &[&str]) {
+        self.send_text(Updated::text(methods));
+    }
+
+    pub fn hang_up(&self) {
+        self.writer.lock().unwrap().shutdown_all().ok();
+    }
+
+    fn send_text(&self, text: String) {
+        self.writer.lock().unwrap().send_message(&Message::text(text)).ok();
+    }
+}
+
+fn serve(shared: &Shared, peer: Peer, mut reader: Reader<TcpStream>) {
+    // The name and params of every subscription that went ready, by id.
+    let mut live: HashMap<String, (String, Vec<Ejson>)> = HashMap::new();
+    loop {
+        let text = match next_text(&mut reader, &peer.writer) {
+            Ok(text) => text,
+            Err(Gone::Closed) => {
+                shared.received.lock().unwrap().send(json!({ "msg": "close" })).ok();
+                break;
+            },
+            Err(Gone::Dropped) => break,
+        };
+        let message: Ejson = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(_)      => continue,
+        };
+        shared.received.lock().unwrap().send(message.clone()).ok();
+
+        let id = message["id"].as_str().unwrap_or("");
+        let params = message["params"].as_array().map(|p| &p[..]).unwrap_or(&[]);
+        match message["msg"].as_str() {
+            Some("connect") => {
+                let versions = shared.versions.lock().unwrap();
+                match negotiate(&versions[..], &message) {
+                    Ok(()) => {
+                        let session = format!("mock-{}", shared.sessions.fetch_add(1, Ordering::SeqCst) + 1);
+                        peer.send_text(Connected::text(&session));
+                        *peer.session.lock().unwrap() = Some(session);
+                    },
+                    Err(suggested) => peer.send_text(Failed::text(suggested)),
+                }
+            },
+            Some("ping") => if shared.pongs.load(Ordering::SeqCst) {
+                peer.send_text(Pong::text(message["id"].as_str()));
+            },
+            Some("method") => {
+                let name = message["method"].as_str().unwrap_or("");
+                let reply = match shared.methods.lock().unwrap().get(name) {
+                    Some(script) => script(&peer, params),
+                    None         => Reply::Error(error(json!(404), &format!("Method '{}' not found", name))),
+                };
+                match reply {
+                    Reply::Result(result) => peer.result(id, Ok(result)),
+                    Reply::Error(error)   => peer.result(id, Err(error)),
+                    Reply::Silence        => continue,
+                }
+                peer.updated(&[id]);
+            },
+            Some("sub") => {
+                let name = message["name"].as_str().unwrap_or("");
+                let outcome = match shared.subs.lock().unwrap().get(name) {
+                    Some(script) => script(&peer, params),
+                    None         => Err(error(json!(404), &format!("Subscription '{}' not found", name))),
+                };
+                match outcome {
+                    Ok(()) => {
+                        live.insert(id.to_string(), (name.to_string(), params.to_vec()));
+                        peer.ready(&[id]);
+                    },
+                    Err(error) => peer.nosub(id, Some(error)),
+                }
+            },
+            Some("unsub") => {
+                if let Some((name, params)) = live.remove(id) {
+                    if let Some(script) = shared.unsubs.lock().unwrap().get(&name) {
+                        script(&peer, &params);
+                    }
+                }
+                peer.nosub(id, None);
+            },
+            _ => {},
+        }
+    }
+    shared.peers.lock().unwrap().retain(|other| !Arc::ptr_eq(&other.writer, &peer.writer));
+}
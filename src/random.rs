@@ -1,5 +1,7 @@
 extern crate rand;
 
+use std::collections::HashMap;
+
 use self::rand::Rng;
 
 const UNMISTAKABLE_CHARS: &'static str = "23456789ABCDEFGHJKLMNPQRSTWXYZabcdefghijkmnopqrstuvwxyz";
@@ -30,3 +32,104 @@ impl Random {
 		rand_str
 	}
 }
+
+const BASE64_CHARS: &'static str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_";
+
+impl Random {
+    /// 43 characters, as Meteor's `Random.secret` makes for `randomSeed`.
+    pub fn secret(&mut self) -> String {
+        let set: Vec<char> = BASE64_CHARS.chars().collect();
+        (0..43).map(|_| set[(self.rng.next_f64() * set.len() as f64) as usize]).collect()
+    }
+}
+
+/// Johannes Baagøe's Alea, as Meteor's `Random.createWithSeeds` uses it, so the
+/// same seeds produce the same ids here as on the server.
+pub struct Alea {
+    s0: f64,
+    s1: f64,
+    s2: f64,
+    c:  f64,
+}
+
+impl Alea {
+    pub fn new(seeds: &[&str]) -> Self {
+        let mut mash = Mash(4022871197.0);
+        let (mut s0, mut s1, mut s2) = (mash.mash(" "), mash.mash(" "), mash.mash(" "));
+        let mut sub = |s: f64, seed: &str| {
+            let s = s - mash.mash(seed);
+            if s < 0.0 { s + 1.0 } else { s }
+        };
+        for seed in seeds {
+            s0 = sub(s0, seed);
+            s1 = sub(s1, seed);
+            s2 = sub(s2, seed);
+        }
+        Alea { s0: s0, s1: s1, s2: s2, c: 1.0 }
+    }
+
+    /// The next number in [0, 1).
+    pub fn fraction(&mut self) -> f64 {
+        let t = 2091639.0 * self.s0 + self.c * TWO_TO_MINUS_32;
+        self.s0 = self.s1;
+        self.s1 = self.s2;
+        self.c = t.trunc();
+        self.s2 = t - self.c;
+        self.s2
+    }
+
+    pub fn id(&mut self) -> String {
+        let set: Vec<char> = UNMISTAKABLE_CHARS.chars().collect();
+        (0..17).map(|_| set[(self.fraction() * set.len() as f64) as usize]).collect()
+    }
+}
+
+const TWO_TO_MINUS_32: f64 = 2.3283064365386963e-10;
+
+struct Mash(f64);
+
+impl Mash {
+    // Mirrors the JavaScript, floats and all; `uint32` is `>>> 0`.
+    fn mash(&mut self, data: &str) -> f64 {
+        let uint32 = |x: f64| x.trunc().rem_euclid(4294967296.0);
+        let mut n = self.0;
+        for unit in data.encode_utf16() {
+            n += unit as f64;
+            let mut h = 0.02519603282416938 * n;
+            n = uint32(h);
+            h -= n;
+            h *= n;
+            n = uint32(h);
+            h -= n;
+            n += h * 4294967296.0;
+        }
+        self.0 = n;
+        uint32(n) * TWO_TO_MINUS_32
+    }
+}
+
+/// The generators behind a method's `randomSeed`, one per name, like Meteor's
+/// `DDPCommon.RandomStream`. Inserts into a collection draw their ids from the
+/// `"/collection/<name>"` sequence.
+pub struct RandomStream {
+    seed:      String,
+    sequences: HashMap<String, Alea>,
+}
+
+impl RandomStream {
+    pub fn new<S: Into<String>>(seed: S) -> Self {
+        RandomStream {
+            seed:      seed.into(),
+            sequences: HashMap::new(),
+        }
+    }
+
+    pub fn seed(&self) -> &str {
+        &self.seed
+    }
+
+    pub fn sequence(&mut self, name: &str) -> &mut Alea {
+        let seed = &self.seed;
+        self.sequences.entry(name.to_string()).or_insert_with(|| Alea::new(&[seed, name]))
+    }
+}
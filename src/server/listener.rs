@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+use websocket::sync::{Client, Server};
+
+/// Accepts WebSocket clients until stopped, keeping hold of every connection
+/// so they can be hung up on. `Server` and the mock server both listen
+/// through one.
+pub struct Listener {
+    addr:     SocketAddr,
+    stopping: AtomicBool,
+    clients:  Mutex<HashMap<usize, TcpStream>>,
+    next:     AtomicUsize,
+}
+
+impl Listener {
+    /// Binds `addr` and runs `serve` for each client, on a thread of its own.
+    pub fn spawn<A, F>(addr: A, serve: F) -> io::Result<(Arc<Listener>, JoinHandle<()>)>
+    where A: ToSocketAddrs, F: Fn(Client<TcpStream>) + Send + Sync + 'static {
+        let mut server = Server::bind(addr)?;
+        let listener = Arc::new(Listener {
+            addr:     server.local_addr()?,
+            stopping: AtomicBool::new(false),
+            clients:  Mutex::new(HashMap::new()),
+            next:     AtomicUsize::new(0),
+        });
+        let serve = Arc::new(serve);
+
+        let accepting = listener.clone();
+        let thread = thread::spawn(move || loop {
+            let upgrade = server.accept();
+            if accepting.is_stopping() {
+                return;
+            }
+            // A client that botched its handshake shouldn't take the
+            // server down with it.
+            let upgrade = match upgrade {
+                Ok(upgrade) => upgrade,
+                Err(_)      => continue,
+            };
+            let (listener, serve) = (accepting.clone(), serve.clone());
+            // The handshake happens off the accepting thread so a slow
+            // client can't hold up the others.
+            thread::spawn(move || if let Ok(client) = upgrade.accept() {
+                let key = match client.stream_ref().try_clone() {
+                    Ok(stream) => listener.track(stream),
+                    Err(_)     => return,
+                };
+                serve(client);
+                listener.clients.lock().unwrap().remove(&key);
+            });
+        });
+
+        Ok((listener, thread))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Closes every connection, but keeps accepting new ones.
+    pub fn hang_up(&self) {
+        for stream in self.clients.lock().unwrap().values() {
+            stream.shutdown(Shutdown::Both).ok();
+        }
+    }
+
+    /// Stops accepting and closes every connection.
+    pub fn stop(&self) {
+        if !self.stopping.swap(true, Ordering::SeqCst) {
+            // Wakes the accepting thread so it sees it should stop.
+            let mut addr = self.addr;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            TcpStream::connect(addr).ok();
+        }
+        self.hang_up();
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    // A client accepted just as the listener stopped is hung up on here,
+    // since `stop` may already have been through the others.
+    fn track(&self, stream: TcpStream) -> usize {
+        let key = self.next.fetch_add(1, Ordering::SeqCst);
+        self.clients.lock().unwrap().insert(key, stream);
+        if self.is_stopping() {
+            self.hang_up();
+        }
+        key
+    }
+}
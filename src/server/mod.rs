@@ -0,0 +1,138 @@
+//! A DDP server, so a Rust backend can serve Meteor front-ends. Register
+//! methods and publications by name, then `listen`.
+//!
+//! Each connection gets its own thread, which runs that client's methods and
+//! publication functions one at a time, in the order they arrive, like
+//! Meteor does without `this.unblock()`. A publication can hand its
+//! `Publication` to another thread to keep pushing changes.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use std::thread::JoinHandle;
+
+use crate::client::DdpError;
+use crate::client::messages::Ejson;
+
+mod listener;
+mod session;
+pub(crate) use self::listener::Listener;
+pub(crate) use self::session::{negotiate, next_text, Gone};
+pub use self::session::{MethodContext, Publication};
+
+type MethodHandler = Arc<Fn(&MethodContext, &[Ejson]) -> Result<Ejson, DdpError> + Send + Sync>;
+type Publisher = Arc<Fn(&Publication, &[Ejson]) -> Result<(), DdpError> + Send + Sync>;
+
+struct Handlers {
+    methods:      RwLock<HashMap<String, MethodHandler>>,
+    publications: RwLock<HashMap<String, Publisher>>,
+}
+
+impl Handlers {
+    fn method(&self, name: &str) -> Option<MethodHandler> {
+        self.methods.read().unwrap().get(name).cloned()
+    }
+
+    fn publication(&self, name: &str) -> Option<Publisher> {
+        self.publications.read().unwrap().get(name).cloned()
+    }
+}
+
+/// Methods and publications can be registered before or after `listen`, and
+/// are shared by every address the server listens on.
+pub struct Server {
+    handlers: Arc<Handlers>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server {
+            handlers: Arc::new(Handlers {
+                methods:      RwLock::new(HashMap::new()),
+                publications: RwLock::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Like `Meteor.methods`. The result is sent back, followed by `updated`
+    /// as there's nothing else to wait for. An error goes back as it is, so
+    /// build it with `DdpError::local` or from a `Meteor.Error` shape.
+    pub fn method<F>(&self, name: &str, f: F)
+    where F: Fn(&MethodContext, &[Ejson]) -> Result<Ejson, DdpError> + Send + Sync + 'static {
+        self.handlers.methods.write().unwrap().insert(name.to_string(), Arc::new(f));
+    }
+
+    /// Like `Meteor.publish`. `f` runs once per subscription and should call
+    /// `ready` once the initial documents are out. Returning an error stops
+    /// the subscription with it.
+    pub fn publish<F>(&self, name: &str, f: F)
+    where F: Fn(&Publication, &[Ejson]) -> Result<(), DdpError> + Send + Sync + 'static {
+        self.handlers.publications.write().unwrap().insert(name.to_string(), Arc::new(f));
+    }
+
+    /// Starts accepting WebSocket connections on `addr`, on any path, until
+    /// the returned handle is shut down or dropped.
+    pub fn listen<A: ToSocketAddrs>(&self, addr: A) -> io::Result<ServerHandle> {
+        let handlers = self.handlers.clone();
+        let (listener, thread) = Listener::spawn(addr, move |client| session::run(&handlers, client))?;
+        Ok(ServerHandle {
+            listener: listener,
+            thread:   Some(thread),
+        })
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+/// Dropping it stops the server.
+pub struct ServerHandle {
+    listener: Arc<Listener>,
+    thread:   Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// Where the server is listening, for when it was asked for port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr()
+    }
+
+    /// Stops accepting connections and hangs up on every client, which
+    /// stops their subscriptions.
+    pub fn shutdown(&self) {
+        self.listener.stop();
+    }
+
+    /// Serves for good, for a program that does nothing else.
+    pub fn join(mut self) {
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// A `Meteor.Error` as it goes over the wire.
+pub(crate) fn meteor_error(error: Ejson, reason: &str) -> Ejson {
+    json!({
+        "isClientSafe": true,
+        "error":        error,
+        "reason":       reason,
+        "message":      format!("{} [{}]", reason, error),
+        "errorType":    "Meteor.Error",
+    })
+}
+
+// What Meteor sends for an unknown method or publication.
+pub(crate) fn not_found(reason: String) -> DdpError {
+    DdpError::from(&meteor_error(json!(404), &reason))
+}
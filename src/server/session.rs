@@ -0,0 +1,457 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde_json::{self, Map};
+use websocket::Message;
+use websocket::message::OwnedMessage;
+use websocket::sync::{Client, Reader, Writer};
+
+use crate::client::DdpError;
+use crate::client::messages::{Added, Changed, Connected, Ejson, Failed, Malformed, MethodResult, NoSub, Pong, Ready,
+                              Removed, Updated, VERSIONS};
+use crate::random::Random;
+use super::{not_found, Handlers};
+
+/// One client's connection.
+struct Session {
+    id:      String,
+    writer:  Mutex<Writer<TcpStream>>,
+    user_id: Mutex<Option<String>>,
+    subs:    Mutex<HashMap<String, Publication>>,
+    // Every document the client has, by collection and id. Like Meteor's
+    // merge box, a document only goes away once no subscription has it.
+    view:    Mutex<HashMap<(String, String), DocumentView>>,
+}
+
+impl Session {
+    fn send(&self, text: String) {
+        self.writer.lock().unwrap().send_message(&Message::text(text)).ok();
+    }
+}
+
+// One document as the client has it, like Meteor's SessionDocumentView: the
+// subscriptions publishing it and, for each field, the value every one of
+// them gave it in the order they did. The client sees the first.
+#[derive(Default)]
+struct DocumentView {
+    subs:   HashSet<String>,
+    fields: HashMap<String, Vec<(String, Ejson)>>,
+}
+
+impl DocumentView {
+    // Records `sub`'s value, collecting it in `changes` if the client sees it.
+    fn set(&mut self, sub: &str, key: &str, value: &Ejson, changes: &mut Map<String, Ejson>) {
+        let owners = self.fields.entry(key.to_string()).or_default();
+        match owners.iter().position(|(owner, _)| owner == sub) {
+            Some(i) => {
+                if i == 0 && owners[0].1 != *value {
+                    changes.insert(key.to_string(), value.clone());
+                }
+                owners[i].1 = value.clone();
+            },
+            None    => {
+                if owners.is_empty() {
+                    changes.insert(key.to_string(), value.clone());
+                }
+                owners.push((sub.to_string(), value.clone()));
+            },
+        }
+    }
+
+    // Forgets `sub`'s value. The client sees the next owner's instead, or
+    // has the field cleared if there is none.
+    fn clear(&mut self, sub: &str, key: &str, changes: &mut Map<String, Ejson>, cleared: &mut Vec<String>) {
+        let owners = match self.fields.get_mut(key) {
+            Some(owners) => owners,
+            None         => return,
+        };
+        let (i, value) = match owners.iter().position(|(owner, _)| owner == sub) {
+            Some(i) => (i, owners.remove(i).1),
+            None    => return,
+        };
+        if owners.is_empty() {
+            self.fields.remove(key);
+            cleared.push(key.to_string());
+        } else if i == 0 && owners[0].1 != value {
+            changes.insert(key.to_string(), owners[0].1.clone());
+        }
+    }
+}
+
+// A `changed` message, unless there's nothing to change.
+fn changed_text(collection: &str, id: &str, changes: Map<String, Ejson>, cleared: &[String]) -> Option<String> {
+    if changes.is_empty() && cleared.is_empty() {
+        return None;
+    }
+    let cleared: Vec<&str> = cleared.iter().map(|key| &key[..]).collect();
+    Some(Changed::text(collection, id, &Ejson::Object(changes), &cleared))
+}
+
+pub fn run(handlers: &Handlers, client: Client<TcpStream>) {
+    let (mut reader, writer) = match client.split() {
+        Ok(halves) => halves,
+        Err(_)     => return,
+    };
+    let session = Arc::new(Session {
+        id:      Random::new().id(),
+        writer:  Mutex::new(writer),
+        user_id: Mutex::new(None),
+        subs:    Mutex::new(HashMap::new()),
+        view:    Mutex::new(HashMap::new()),
+    });
+    let mut connected = false;
+
+    while let Ok(text) = next_text(&mut reader, &session.writer) {
+        let message: Ejson = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(_)      => {
+                session.send(Malformed::text("Bad request", None));
+                continue;
+            },
+        };
+        let msg = message["msg"].as_str().unwrap_or("");
+
+        if !connected {
+            if msg != "connect" {
+                session.send(Malformed::text("Must connect first", Some(&message)));
+                continue;
+            }
+            match negotiate(&VERSIONS[..], &message) {
+                Ok(())         => {
+                    session.send(Connected::text(&session.id));
+                    connected = true;
+                    continue;
+                },
+                // Hang up so the client can try again with the suggestion.
+                Err(suggested) => {
+                    session.send(Failed::text(suggested));
+                    break;
+                },
+            }
+        }
+
+        let id = message["id"].as_str();
+        let params = message["params"].as_array().map_or(&[][..], |params| &params[..]);
+        match (msg, id) {
+            ("ping", _) => session.send(Pong::text(id)),
+            ("pong", _) | ("connect", _) => {},
+            ("method", Some(id)) => {
+                let name = message["method"].as_str().unwrap_or("");
+                let context = MethodContext {
+                    session: session.clone(),
+                    id:      id.to_string(),
+                    seed:    message["randomSeed"].as_str().map(|seed| seed.to_string()),
+                };
+                let result = match handlers.method(name) {
+                    Some(handler) => handler(&context, params),
+                    None          => Err(not_found(format!("Method '{}' not found", name))),
+                };
+                match result {
+                    Ok(result) => session.send(MethodResult::text(id, Ok(&result))),
+                    Err(error) => session.send(MethodResult::text(id, Err(error.raw()))),
+                }
+                session.send(Updated::text(&[id]));
+            },
+            ("sub", Some(id)) => {
+                if session.subs.lock().unwrap().contains_key(id) {
+                    continue;
+                }
+                let name = message["name"].as_str().unwrap_or("");
+                let publisher = match handlers.publication(name) {
+                    Some(publisher) => publisher,
+                    None            => {
+                        let error = not_found(format!("Subscription '{}' not found", name));
+                        session.send(NoSub::text(id, Some(error.raw())));
+                        continue;
+                    },
+                };
+                let publication = Publication {
+                    session: session.clone(),
+                    id:      id.to_string(),
+                    name:    name.to_string(),
+                    state:   Arc::new(SubState {
+                        stopped: AtomicBool::new(false),
+                        ready:   AtomicBool::new(false),
+                        on_stop: Mutex::new(Vec::new()),
+                    }),
+                };
+                session.subs.lock().unwrap().insert(id.to_string(), publication.clone());
+                if let Err(error) = publisher(&publication, params) {
+                    publication.end(Some(&error), true);
+                }
+            },
+            ("unsub", Some(id)) => {
+                let publication = session.subs.lock().unwrap().get(id).cloned();
+                match publication {
+                    Some(publication) => publication.end(None, true),
+                    None              => session.send(NoSub::text(id, None)),
+                }
+            },
+            _ => session.send(Malformed::text("Bad request", Some(&message))),
+        }
+    }
+
+    session.writer.lock().unwrap().shutdown_all().ok();
+    let subs: Vec<Publication> = session.subs.lock().unwrap().drain().map(|(_, sub)| sub).collect();
+    for sub in subs {
+        sub.end(None, false);
+    }
+}
+
+/// How a client went away.
+pub enum Gone {
+    /// It sent a close frame.
+    Closed,
+    /// The socket broke or was shut.
+    Dropped,
+}
+
+/// The next text message, answering WebSocket pings on the way.
+pub fn next_text(reader: &mut Reader<TcpStream>, writer: &Mutex<Writer<TcpStream>>) -> Result<String, Gone> {
+    loop {
+        match reader.recv_message() {
+            Ok(OwnedMessage::Text(text)) => return Ok(text),
+            Ok(OwnedMessage::Ping(data)) => {
+                writer.lock().unwrap().send_message(&Message::pong(data)).ok();
+            },
+            Ok(OwnedMessage::Close(_)) => return Err(Gone::Closed),
+            Err(_) => return Err(Gone::Dropped),
+            Ok(_) => {},
+        }
+    }
+}
+
+/// Whether to take a `connect` asking for a version out of `ours`, best
+/// first. If not, the version to suggest in `failed` is the best one the
+/// client also speaks.
+pub fn negotiate<'a, S: AsRef<str>>(ours: &'a [S], connect: &Ejson) -> Result<(), &'a str> {
+    let asked = connect["version"].as_str().unwrap_or("");
+    if ours.iter().any(|v| v.as_ref() == asked) {
+        return Ok(());
+    }
+    let support = connect["support"].as_array().cloned().unwrap_or_default();
+    let suggested = ours.iter().find(|v| support.iter().any(|s| s == v.as_ref())).or_else(|| ours.first());
+    Err(suggested.map_or("", |v| v.as_ref()))
+}
+
+/// What a method handler can find out about the call, like `this` in a
+/// Meteor method.
+pub struct MethodContext {
+    session: Arc<Session>,
+    id:      String,
+    seed:    Option<String>,
+}
+
+impl MethodContext {
+    pub fn method_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session.id
+    }
+
+    /// The seed the client sent, which the method should use for any ids it
+    /// generates so they match the client's simulation.
+    pub fn random_seed(&self) -> Option<&str> {
+        self.seed.as_ref().map(|seed| &seed[..])
+    }
+
+    pub fn user_id(&self) -> Option<String> {
+        self.session.user_id.lock().unwrap().clone()
+    }
+
+    /// Logs the connection in or out. Unlike Meteor, running subscriptions
+    /// aren't restarted; they see the new user from `Publication::user_id`.
+    pub fn set_user_id(&self, user_id: Option<&str>) {
+        *self.session.user_id.lock().unwrap() = user_id.map(|id| id.to_string());
+    }
+}
+
+struct SubState {
+    stopped: AtomicBool,
+    ready:   AtomicBool,
+    on_stop: Mutex<Vec<Box<FnOnce() + Send>>>,
+}
+
+/// One client's subscription, like `this` in a Meteor publish function.
+/// Clones share the subscription, and once it's stopped pushing to it does
+/// nothing.
+#[derive(Clone)]
+pub struct Publication {
+    session: Arc<Session>,
+    id:      String,
+    name:    String,
+    state:   Arc<SubState>,
+}
+
+impl Publication {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session.id
+    }
+
+    pub fn user_id(&self) -> Option<String> {
+        self.session.user_id.lock().unwrap().clone()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.state.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Sent as `changed` if another subscription already gave the client
+    /// this document. Fields another subscription gave it first keep that
+    /// subscription's value.
+    pub fn added(&self, collection: &str, id: &str, fields: &Ejson) {
+        if self.is_stopped() {
+            return;
+        }
+        let mut view = self.session.view.lock().unwrap();
+        let doc = view.entry((collection.to_string(), id.to_string())).or_default();
+        let new = doc.subs.is_empty();
+        doc.subs.insert(self.id.clone());
+
+        let mut changes = Map::new();
+        for (key, value) in fields.as_object().into_iter().flatten() {
+            doc.set(&self.id, key, value, &mut changes);
+        }
+        if new {
+            self.session.send(Added::text(collection, id, &Ejson::Object(changes)));
+        } else if let Some(text) = changed_text(collection, id, changes, &[]) {
+            self.session.send(text);
+        }
+    }
+
+    /// Ignored unless this subscription added the document. Clearing a field
+    /// another subscription also has shows the client that one's value.
+    pub fn changed(&self, collection: &str, id: &str, fields: &Ejson, cleared: &[&str]) {
+        if self.is_stopped() {
+            return;
+        }
+        let mut view = self.session.view.lock().unwrap();
+        let doc = match view.get_mut(&(collection.to_string(), id.to_string())) {
+            Some(doc) if doc.subs.contains(&self.id) => doc,
+            _ => return,
+        };
+        let (mut changes, mut gone) = (Map::new(), Vec::new());
+        for (key, value) in fields.as_object().into_iter().flatten() {
+            doc.set(&self.id, key, value, &mut changes);
+        }
+        for key in cleared {
+            doc.clear(&self.id, key, &mut changes, &mut gone);
+        }
+        if let Some(text) = changed_text(collection, id, changes, &gone) {
+            self.session.send(text);
+        }
+    }
+
+    /// The client only hears about it once no subscription has the document.
+    /// Until then, it loses the fields only this subscription gave it.
+    pub fn removed(&self, collection: &str, id: &str) {
+        if self.is_stopped() {
+            return;
+        }
+        let mut view = self.session.view.lock().unwrap();
+        if let Some(text) = self.take_back(&mut view, &(collection.to_string(), id.to_string())) {
+            self.session.send(text);
+        }
+    }
+
+    /// Tells the client the initial documents are all there. Only the first
+    /// call does anything.
+    pub fn ready(&self) {
+        if !self.is_stopped() && !self.state.ready.swap(true, Ordering::SeqCst) {
+            self.session.send(Ready::text(&[&self.id]));
+        }
+    }
+
+    /// Stops the subscription, taking its documents back from the client.
+    pub fn stop(&self) {
+        self.end(None, true);
+    }
+
+    /// Stops the subscription with `error`, which the client gets in `nosub`.
+    pub fn error(&self, error: &DdpError) {
+        self.end(Some(error), true);
+    }
+
+    /// Runs `f` once the subscription stops, whether the client unsubscribed,
+    /// went away or the server stopped it. Runs it straight away if that
+    /// already happened.
+    pub fn on_stop<F>(&self, f: F)
+    where F: FnOnce() + Send + 'static {
+        {
+            let mut on_stop = self.state.on_stop.lock().unwrap();
+            if !self.is_stopped() {
+                on_stop.push(Box::new(f));
+                return;
+            }
+        }
+        f();
+    }
+
+    // `tell` is false once the client is gone, when there's nobody to send
+    // the removals and `nosub` to.
+    fn end(&self, error: Option<&DdpError>, tell: bool) {
+        let callbacks = {
+            let mut on_stop = self.state.on_stop.lock().unwrap();
+            if self.state.stopped.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            mem::take(&mut *on_stop)
+        };
+        self.session.subs.lock().unwrap().remove(&self.id);
+
+        {
+            let mut view = self.session.view.lock().unwrap();
+            let keys: Vec<(String, String)> = view.iter()
+                .filter(|&(_, doc)| doc.subs.contains(&self.id))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in keys {
+                match self.take_back(&mut view, &key) {
+                    Some(text) if tell => self.session.send(text),
+                    _ => {},
+                }
+            }
+        }
+        if tell {
+            self.session.send(NoSub::text(&self.id, error.map(|error| error.raw())));
+        }
+
+        for f in callbacks {
+            f();
+        }
+    }
+
+    // Drops this subscription's share of a document, returning what to tell
+    // the client, if anything.
+    fn take_back(&self, view: &mut HashMap<(String, String), DocumentView>, key: &(String, String)) -> Option<String> {
+        {
+            let doc = view.get_mut(key)?;
+            if !doc.subs.remove(&self.id) {
+                return None;
+            }
+            if !doc.subs.is_empty() {
+                let (mut changes, mut cleared) = (Map::new(), Vec::new());
+                let fields: Vec<String> = doc.fields.keys().cloned().collect();
+                for field in fields {
+                    doc.clear(&self.id, &field, &mut changes, &mut cleared);
+                }
+                return changed_text(&key.0, &key.1, changes, &cleared);
+            }
+        }
+        view.remove(key);
+        Some(Removed::text(&key.0, &key.1))
+    }
+}
@@ -0,0 +1,136 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::time::{Duration, UNIX_EPOCH};
+
+use ddp::client::{Client, State, User};
+use ddp::mock::{self, MockServer, Peer, Reply};
+use serde_json::Value;
+
+use common::next;
+
+// sha256("secret")
+const DIGEST: &str = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+fn login(token: &str) -> Value {
+    json!({ "id": "ann", "token": token, "tokenExpires": { "$date": 1500000000000i64 } })
+}
+
+type Users = Arc<Mutex<HashMap<String, Value>>>;
+
+// Who each session is logged in as.
+fn log_in(users: &Users, peer: &Peer, user: Value) {
+    users.lock().unwrap().insert(peer.session().unwrap(), user);
+}
+
+// Knows one user, "ann", with the password "secret" and the tokens "t1" and
+// "t2". The "whoami" method answers with the user of the session.
+fn serve() -> MockServer {
+    let server = MockServer::start();
+    let users: Users = Arc::new(Mutex::new(HashMap::new()));
+
+    let logins = users.clone();
+    server.method("login", move |peer, params| {
+        let params = &params[0];
+        let token = match params["resume"].as_str() {
+            Some(token) if token == "t1" || token == "t2" => Some(token),
+            Some(_) => None,
+            None if params["user"]["username"] == "ann" && params["password"]["digest"] == DIGEST
+                 && params["password"]["algorithm"] == "sha-256" => Some("t1"),
+            None => None,
+        };
+        match token {
+            Some(token) => {
+                log_in(&logins, peer, json!("ann"));
+                Reply::Result(login(token))
+            },
+            None => {
+                log_in(&logins, peer, Value::Null);
+                Reply::Error(mock::error(json!(403), "Incorrect password"))
+            },
+        }
+    });
+    let logins = users.clone();
+    server.method("getNewToken", move |peer, _| {
+        log_in(&logins, peer, json!("ann"));
+        Reply::Result(login("t2"))
+    });
+    server.method("removeOtherTokens", |_, _| Reply::Result(Value::Null));
+    let logins = users.clone();
+    server.method("logout", move |peer, _| {
+        log_in(&logins, peer, Value::Null);
+        Reply::Result(Value::Null)
+    });
+    server.method("whoami", move |peer, _| {
+        let user = users.lock().unwrap().get(&peer.session().unwrap()).cloned();
+        Reply::Result(user.unwrap_or(Value::Null))
+    });
+    server
+}
+
+#[test]
+fn logs_in_and_out() {
+    let server = serve();
+    let client = Client::new(server.url()).unwrap();
+    let ann = User::Username("ann".to_string());
+
+    let (tx, results) = channel();
+    let failed = tx.clone();
+    client.login_with_password(&ann, "wrong", move |result| failed.send(result.map(|l| l.clone()).map_err(|e| e.clone())).unwrap());
+    let error = next(&results).unwrap_err();
+    assert_eq!(*error.code(), 403);
+    assert_eq!(client.user_id(), None);
+
+    client.login_with_password(&ann, "secret", move |result| tx.send(result.map(|l| l.clone()).map_err(|e| e.clone())).unwrap());
+    let login = next(&results).unwrap();
+    assert_eq!(login.user_id(), "ann");
+    assert_eq!(login.token(), "t1");
+    assert_eq!(login.expires(), Some(UNIX_EPOCH + Duration::from_millis(1500000000000)));
+    assert_eq!(client.user_id(), Some("ann".to_string()));
+    assert_eq!(client.login_token(), Some("t1".to_string()));
+
+    let (tx, done) = channel();
+    let other = tx.clone();
+    client.logout_other_clients(move |result| other.send(result.is_ok()).unwrap());
+    assert!(next(&done));
+    assert_eq!(client.login_token(), Some("t2".to_string()));
+
+    client.logout(move |result| tx.send(result.is_ok()).unwrap());
+    assert!(next(&done));
+    assert_eq!(client.user_id(), None);
+    assert_eq!(client.login_token(), None);
+}
+
+#[test]
+fn logs_back_in_before_replaying() {
+    let server = serve();
+    let mut client = Client::new(server.url()).unwrap();
+    client.retry_custom(|_, _| Some(50));
+    let (tx, statuses) = channel();
+    client.on_status(move |status| { tx.send(status.state().clone()).ok(); });
+
+    let (tx, results) = channel();
+    client.login_with_token("t1", move |result| tx.send(result.is_ok()).unwrap());
+    assert!(next(&results));
+    assert_eq!(server.expect("method")["method"], "login");
+
+    // Called while offline, so it's replayed once the client is back.
+    server.refuse(true);
+    server.hang_up();
+    while !matches!(next(&statuses), State::Waiting { .. }) {}
+    let (tx, results) = channel();
+    client.call("whoami", None, move |result| tx.send(result.unwrap().clone()).unwrap());
+
+    server.refuse(false);
+    let method = server.expect("method");
+    assert_eq!((&method["method"], &method["params"]), (&json!("login"), &json!([{ "resume": "t1" }])));
+    assert_eq!(server.expect("method")["method"], "whoami");
+    assert_eq!(next(&results), json!("ann"));
+    assert_eq!(client.user_id(), Some("ann".to_string()));
+}
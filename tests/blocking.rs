@@ -0,0 +1,44 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::time::Duration;
+
+use ddp::client::BlockingClient;
+
+use common::serve;
+
+#[test]
+fn call_returns_the_result() {
+    let server = serve();
+    let client = BlockingClient::new(server.url()).unwrap();
+    let param = json!({ "a": 1 });
+
+    assert_eq!(client.call("echo", Some(&vec![&param])).unwrap(), param);
+    assert_eq!(*client.call("fail", None).unwrap_err().code(), 500);
+}
+
+#[test]
+fn call_times_out() {
+    let server = serve();
+    let mut client = BlockingClient::new(server.url()).unwrap();
+    client.set_timeout(Some(Duration::from_millis(100)));
+
+    assert_eq!(*client.call("slow", None).unwrap_err().code(), "timeout");
+}
+
+#[test]
+fn subscribe_waits_for_ready() {
+    let server = serve();
+    server.publish("ready", |_, _| Ok(()));
+    let client = BlockingClient::new(server.url()).unwrap();
+
+    let sub = client.subscribe("ready", None).unwrap();
+    assert!(sub.ready());
+    match client.subscribe("missing", None) {
+        Err(error) => assert_eq!(*error.code(), 404),
+        Ok(_)      => panic!("subscribed to a missing publication"),
+    }
+}
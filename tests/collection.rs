@@ -0,0 +1,112 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::sync::mpsc::{channel, Receiver};
+
+use ddp::Connection;
+use ddp::client::{CollectionEvent, Subscription};
+use ddp::mock::MockServer;
+
+use common::next;
+
+// The "things" publication adds a document named after its param and a
+// "shared" one, and removes "shared" then its own on unsub. Like any server
+// without a merge box, it sends "shared" once per subscription.
+fn serve() -> MockServer {
+    let server = MockServer::start();
+    server.publish("things", |peer, params| {
+        let name = params[0].as_str().unwrap();
+        peer.added("things", name, json!({ "n": 1 }));
+        peer.added("things", "shared", json!({ "n": 0 }));
+        Ok(())
+    });
+    server.on_unsub("things", |peer, params| {
+        peer.removed("things", "shared");
+        peer.removed("things", params[0].as_str().unwrap());
+    });
+    server
+}
+
+fn subscribe(conn: &Connection, name: &str) -> Subscription {
+    let param = json!(name);
+    let sub = conn.subscribe("things", Some(&vec![&param]));
+    let (tx, rx) = channel();
+    sub.on_ready(move |result| tx.send(result.is_ok()).unwrap());
+    assert!(next(&rx));
+    sub
+}
+
+fn ids(docs: Vec<serde_json::Value>) -> Vec<String> {
+    docs.iter().map(|doc| doc["_id"].as_str().unwrap().to_string()).collect()
+}
+
+// The id of the next document removed from the cache.
+fn removed(events: &Receiver<CollectionEvent>) -> String {
+    loop {
+        if let CollectionEvent::Removed { id } = next(events) {
+            return id;
+        }
+    }
+}
+
+#[test]
+fn reads_follow_added_changed_and_removed() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let things = conn.mongo("things".to_string());
+    assert!(things.is_empty());
+    assert_eq!(things.find_one("a"), None);
+
+    let a = subscribe(&conn, "a");
+    assert_eq!(things.len(), 2);
+    assert_eq!(things.find_one("a"), Some(json!({ "_id": "a", "n": 1 })));
+    assert_eq!(ids(things.all()), vec!["a", "shared"]);
+    assert_eq!(ids(things.iter().collect()), vec!["a", "shared"]);
+
+    let events = things.events();
+    server.changed("things", "a", json!({ "m": 2 }), &["n"]);
+    next(&events);
+    assert_eq!(things.find_one("a"), Some(json!({ "_id": "a", "m": 2 })));
+    assert_eq!(things.all()[0], json!({ "_id": "a", "m": 2 }));
+
+    a.stop();
+    assert_eq!(removed(&events), "shared");
+    assert_eq!(removed(&events), "a");
+    assert!(things.is_empty());
+    assert_eq!(things.all(), Vec::<serde_json::Value>::new());
+    assert_eq!(things.iter().count(), 0);
+}
+
+#[test]
+fn shared_documents_stay_until_the_last_subscription_removes_them() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let things = conn.mongo("things".to_string());
+
+    let a = subscribe(&conn, "a");
+    let b = subscribe(&conn, "b");
+    assert_eq!(things.len(), 3);
+    assert_eq!(ids(things.all()), vec!["a", "b", "shared"]);
+
+    // Changes to a shared document land once, whichever sub sent them.
+    let events = things.events();
+    server.changed("things", "shared", json!({ "n": 5 }), &[]);
+    next(&events);
+    assert_eq!(things.find_one("shared"), Some(json!({ "_id": "shared", "n": 5 })));
+
+    // "shared" goes before "a", so it's been dealt with once "a" is gone.
+    a.stop();
+    assert_eq!(removed(&events), "a");
+    assert_eq!(things.len(), 2);
+    assert_eq!(ids(things.iter().collect()), vec!["b", "shared"]);
+    assert_eq!(things.find_one("shared"), Some(json!({ "_id": "shared", "n": 5 })));
+
+    b.stop();
+    assert_eq!(removed(&events), "shared");
+    assert_eq!(removed(&events), "b");
+    assert!(things.is_empty());
+    assert_eq!(things.find_one("shared"), None);
+}
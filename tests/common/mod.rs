@@ -0,0 +1,91 @@
+//! Fixtures shared by the integration tests. Each test file only uses some.
+#![allow(dead_code)]
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+use ddp::Url;
+use ddp::mock::{MockServer, Reply};
+use native_tls::{Identity, TlsAcceptor};
+use serde_json::json;
+
+/// Waits up to five seconds for whatever `rx` gets next.
+pub fn next<T>(rx: &Receiver<T>) -> T {
+    rx.recv_timeout(Duration::from_secs(5)).unwrap()
+}
+
+/// A mock server where "echo" answers with its argument, "fail" with a 500
+/// error, "slow" never answers and "hangup" closes the connection. Tests add
+/// their own publications.
+pub fn serve() -> MockServer {
+    let server = MockServer::start();
+    server.method("echo", |_, params| Reply::Result(params[0].clone()));
+    server.method("fail", |_, _| Reply::Error(json!({ "error": 500 })));
+    server.method("slow", |_, _| Reply::Silence);
+    server.method("hangup", |peer, _| {
+        peer.hang_up();
+        Reply::Silence
+    });
+    server
+}
+
+/// Signs the certificate `wss` serves, which is for `localhost`.
+pub const CERT: &'static [u8] = include_bytes!("../fixtures/localhost.pem");
+const IDENTITY: &'static [u8] = include_bytes!("../fixtures/localhost.p12");
+
+/// A `wss://localhost` url for `server`. One connection is accepted, TLS is
+/// terminated with the localhost certificate and the rest passed through.
+pub fn wss(server: &MockServer) -> Url {
+    let backend = server.url();
+    let backend = format!("{}:{}", backend.host_str().unwrap(), backend.port().unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let acceptor = TlsAcceptor::new(Identity::from_pkcs12(IDENTITY, "ddp-test").unwrap()).unwrap();
+
+    thread::spawn(move || {
+        let (tcp, _) = listener.accept().unwrap();
+        let tls = match acceptor.accept(tcp) {
+            Ok(tls) => tls,
+            Err(_)  => return,
+        };
+        // Both directions share the TLS stream, so reads give up now and
+        // then to let writes through.
+        tls.get_ref().set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let tls = Arc::new(Mutex::new(tls));
+        let mut to_backend = TcpStream::connect(backend).unwrap();
+        let mut from_backend = to_backend.try_clone().unwrap();
+
+        let to_client = tls.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while let Ok(n) = from_backend.read(&mut buf) {
+                if n == 0 || to_client.lock().unwrap().write_all(&buf[..n]).is_err() {
+                    break;
+                }
+            }
+            to_client.lock().unwrap().shutdown().ok();
+        });
+
+        let mut buf = [0; 4096];
+        loop {
+            let read = tls.lock().unwrap().read(&mut buf);
+            match read {
+                Ok(0) => break,
+                Ok(n) => if to_backend.write_all(&buf[..n]).is_err() {
+                    break;
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    thread::sleep(Duration::from_millis(1));
+                },
+                Err(_) => break,
+            }
+        }
+        to_backend.shutdown(Shutdown::Both).ok();
+    });
+
+    Url::parse(&format!("wss://localhost:{}/websocket", port)).unwrap()
+}
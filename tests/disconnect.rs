@@ -0,0 +1,145 @@
+extern crate ddp;
+
+mod common;
+
+use std::sync::Mutex;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+use ddp::Connection;
+use ddp::client::{BlockingClient, Client, State};
+use ddp::mock::MockServer;
+
+use common::next;
+
+// "things" is ready straight away, and "slow" is never answered.
+fn serve() -> MockServer {
+    let server = common::serve();
+    server.publish("things", |_, _| Ok(()));
+    server
+}
+
+#[test]
+fn disconnects_gracefully() {
+    let server = serve();
+    let (crashed, crashes) = channel();
+    let crashed = Mutex::new(crashed);
+    let (conn, _) = Connection::new(&server.url(), move || crashed.lock().unwrap().send(()).unwrap()).unwrap();
+
+    let sub = conn.subscribe("things", None);
+    let (tx, events) = channel();
+    let ready = tx.clone();
+    sub.on_ready(move |_| ready.send("ready".to_string()).unwrap());
+    assert_eq!(next(&events), "ready");
+    let stopped = tx.clone();
+    sub.on_stop(move |error| stopped.send(format!("stopped {}", error.is_some())).unwrap());
+    conn.call("slow", None, Box::new(move |result| {
+        tx.send(result.unwrap_err().code().to_string()).unwrap();
+    }));
+
+    conn.disconnect();
+    assert_eq!(next(&events), "stopped false");
+    assert_eq!(next(&events), "disconnected");
+    assert_eq!(server.expect("unsub")["id"], sub.id());
+    server.expect("close");
+    assert_eq!(*conn.status().state(), State::Offline);
+
+    let (tx, results) = channel();
+    conn.call("slow", None, Box::new(move |result| {
+        tx.send(result.unwrap_err().code().to_string()).unwrap();
+    }));
+    assert_eq!(next(&results), "disconnected");
+    assert!(crashes.recv_timeout(Duration::from_millis(200)).is_err());
+}
+
+#[test]
+fn clients_stay_disconnected() {
+    let server = serve();
+    let mut client = Client::new(server.url()).unwrap();
+    client.retry_custom(|_, _| Some(10));
+    let (tx, statuses) = channel();
+    client.on_status(move |status| tx.send(status.state().clone()).unwrap());
+
+    client.disconnect();
+    server.expect("close");
+    assert_eq!(next(&statuses), State::Offline);
+    assert!(statuses.recv_timeout(Duration::from_millis(200)).is_err());
+    assert_eq!(server.sessions(), 1);
+}
+
+#[test]
+fn dropping_hangs_up() {
+    let server = serve();
+    let (conn, handle) = Connection::new(&server.url(), || {}).unwrap();
+    conn.call("slow", None, Box::new(|_| {}));
+
+    drop(conn);
+    server.expect("close");
+    handle.join();
+}
+
+#[test]
+fn disconnecting_while_waiting_to_retry() {
+    let server = serve();
+    let mut client = Client::new(server.url()).unwrap();
+    client.retry_custom(|_, _| Some(300));
+    let (tx, statuses) = channel();
+    client.on_status(move |status| tx.send(status.state().clone()).unwrap());
+
+    server.hang_up();
+    while !matches!(next(&statuses), State::Waiting { .. }) {}
+    client.disconnect();
+    assert_eq!(next(&statuses), State::Offline);
+
+    let (tx, results) = channel();
+    client.call("slow", None, move |result| tx.send(result.unwrap_err().code().to_string()).unwrap());
+    assert_eq!(next(&results), "disconnected");
+    assert!(statuses.recv_timeout(Duration::from_millis(600)).is_err());
+    assert_eq!(*client.status().state(), State::Offline);
+    assert_eq!(server.sessions(), 1);
+}
+
+#[test]
+fn subscribing_once_disconnected() {
+    let server = serve();
+    let mut client = BlockingClient::new(server.url()).unwrap();
+    client.set_timeout(Some(Duration::from_secs(5)));
+    client.client().disconnect();
+
+    let error = client.subscribe("things", None).err().unwrap();
+    assert_eq!(error.code().to_string(), "disconnected");
+
+    let sub = client.client().subscribe("things", None);
+    let (tx, events) = channel();
+    let stopped = tx.clone();
+    sub.on_ready(move |result| tx.send(result.unwrap_err().code().to_string()).unwrap());
+    sub.on_stop(move |error| stopped.send(format!("stopped {}", error.unwrap().code())).unwrap());
+    assert_eq!(next(&events), "disconnected");
+    assert_eq!(next(&events), "stopped disconnected");
+    assert!(server.next_message(Duration::from_millis(200)).map_or(true, |message| message["msg"] != "sub"));
+}
+
+#[test]
+fn crashing_fails_subscriptions() {
+    let server = MockServer::start();
+    // Never ready, until the test is done with it.
+    let (release, released) = channel::<()>();
+    let released = Mutex::new(released);
+    server.publish("waiting", move |_, _| {
+        released.lock().unwrap().recv().ok();
+        Ok(())
+    });
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+
+    let sub = conn.subscribe("waiting", None);
+    let (tx, events) = channel();
+    sub.on_stop(move |error| tx.send(error.unwrap().code().to_string()).unwrap());
+    server.expect("sub");
+    server.hang_up();
+    assert_eq!(next(&events), "disconnected");
+    drop(release);
+
+    let (tx, events) = channel();
+    conn.subscribe("waiting", None).on_stop(move |error| tx.send(error.unwrap().code().to_string()).unwrap());
+    assert_eq!(next(&events), "disconnected");
+}
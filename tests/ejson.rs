@@ -0,0 +1,139 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::channel;
+use std::time::{Duration, UNIX_EPOCH};
+
+use ddp::Connection;
+use ddp::client::{Codec, Custom, CustomType, EjsonValue};
+use serde_json::Value;
+
+use common::{next, serve};
+
+#[derive(Debug, PartialEq)]
+struct Point {
+    x: i64,
+    y: i64,
+}
+
+impl CustomType for Point {
+    fn type_name() -> &'static str {
+        "point"
+    }
+
+    fn to_json(&self) -> Value {
+        json!([self.x, self.y])
+    }
+
+    fn from_json(value: &Value) -> Result<Self, String> {
+        match (value[0].as_i64(), value[1].as_i64()) {
+            (Some(x), Some(y)) => Ok(Point { x: x, y: y }),
+            _ => Err("expected [x, y]".to_string()),
+        }
+    }
+}
+
+#[test]
+fn builtin_types_round_trip() {
+    let wire = json!({
+        "when": { "$date": 1500000000123i64 },
+        "before": { "$date": -1000 },
+        "bytes": { "$binary": "aGVsbG8h" },
+        "odd": { "$binary": "aGk=" },
+        "big": { "$InfNaN": 1 },
+        "small": { "$InfNaN": -1 },
+        "list": [1, "two", null, true],
+    });
+    let value = EjsonValue::decode(&wire).unwrap();
+
+    assert_eq!(value.get("when").unwrap().as_date(), Some(UNIX_EPOCH + Duration::from_millis(1500000000123)));
+    assert_eq!(value.get("before").unwrap().as_date(), Some(UNIX_EPOCH - Duration::from_secs(1)));
+    assert_eq!(value.get("bytes").unwrap().as_binary(), Some(&b"hello!"[..]));
+    assert_eq!(value.get("odd").unwrap().as_binary(), Some(&b"hi"[..]));
+    assert_eq!(value.get("big"), Some(&EjsonValue::NonFinite(f64::INFINITY)));
+    assert_eq!(value.get("small"), Some(&EjsonValue::NonFinite(f64::NEG_INFINITY)));
+    assert_eq!(value.get("list"), Some(&EjsonValue::Array(vec![
+        EjsonValue::from(1),
+        EjsonValue::from("two"),
+        EjsonValue::Null,
+        EjsonValue::Bool(true),
+    ])));
+    assert_eq!(value.encode(), wire);
+
+    match EjsonValue::decode(&json!({ "$InfNaN": 0 })).unwrap() {
+        EjsonValue::NonFinite(f) => assert!(f.is_nan()),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(EjsonValue::from(f64::NAN).encode(), json!({ "$InfNaN": 0 }));
+    assert_eq!(EjsonValue::from(vec![0xffu8, 0, 1]).encode(), json!({ "$binary": "/wAB" }));
+}
+
+#[test]
+fn reserved_keys_are_escaped() {
+    let wire = json!({ "$escape": { "$date": { "$date": 0 } } });
+    let value = EjsonValue::decode(&wire).unwrap();
+
+    let mut expected = BTreeMap::new();
+    expected.insert("$date".to_string(), EjsonValue::from(UNIX_EPOCH));
+    assert_eq!(value, EjsonValue::Object(expected));
+    assert_eq!(value.encode(), wire);
+
+    // Only objects shaped exactly like an extended type need escaping.
+    let plain = json!({ "$date": 1, "other": 2 });
+    assert_eq!(EjsonValue::decode(&plain).unwrap().encode(), plain);
+}
+
+#[test]
+fn custom_types_need_registering() {
+    let wire = json!({ "at": { "$type": "point", "$value": [3, 4] } });
+    assert!(EjsonValue::decode(&wire).is_err());
+
+    let mut codec = Codec::new();
+    codec.register::<Point>();
+    let value = codec.decode(&wire).unwrap();
+    let at = value.get("at").unwrap();
+    assert_eq!(at.as_custom::<Point>(), Some(&Point { x: 3, y: 4 }));
+    assert_eq!(value.encode(), wire);
+
+    assert_eq!(EjsonValue::from(Custom::new(Point { x: 1, y: 2 })).encode(),
+               json!({ "$type": "point", "$value": [1, 2] }));
+    assert!(codec.decode(&json!({ "$type": "point", "$value": "nowhere" })).is_err());
+}
+
+#[test]
+fn values_cross_the_wire_encoded() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let mut codec = Codec::new();
+    codec.register::<Point>();
+
+    let mut param = BTreeMap::new();
+    param.insert("when".to_string(), EjsonValue::from(UNIX_EPOCH + Duration::from_millis(1500000000000)));
+    param.insert("at".to_string(), EjsonValue::from(Custom::new(Point { x: 1, y: 2 })));
+    let param = EjsonValue::Object(param);
+
+    let (tx, results) = channel();
+    conn.call("echo", Some(&vec![&param.encode()]), Box::new(move |result| {
+        tx.send(result.unwrap().clone()).unwrap();
+    }));
+    assert_eq!(server.expect("method")["params"], json!([{
+        "when": { "$date": 1500000000000i64 },
+        "at": { "$type": "point", "$value": [1, 2] },
+    }]));
+    // The result is as it was sent, decoding is up to the caller.
+    let result = next(&results);
+    assert_eq!(result["when"], json!({ "$date": 1500000000000i64 }));
+    assert_eq!(codec.decode(&result).unwrap(), param);
+
+    // Documents too.
+    let things = conn.mongo("things".to_string());
+    let events = things.events();
+    server.added("things", "a", json!({ "at": { "$type": "point", "$value": [3, 4] } }));
+    next(&events);
+    let doc = codec.decode(&things.find_one("a").unwrap()).unwrap();
+    assert_eq!(doc.get("at").unwrap().as_custom::<Point>(), Some(&Point { x: 3, y: 4 }));
+}
@@ -0,0 +1,46 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+use ddp::client::{DdpError, ErrorCode};
+
+#[test]
+fn decodes_meteor_errors() {
+    let raw = json!({
+        "isClientSafe": true,
+        "error": 403,
+        "reason": "Access denied",
+        "details": "not an admin",
+        "message": "Access denied [403]",
+        "errorType": "Meteor.Error"
+    });
+    let error = DdpError::from(&raw);
+
+    assert_eq!(*error.code(), 403);
+    assert_eq!(error.reason(), Some("Access denied"));
+    assert_eq!(error.details(), Some(&json!("not an admin")));
+    assert_eq!(error.error_type(), Some("Meteor.Error"));
+    assert!(error.is_client_safe());
+    assert_eq!(error.raw(), &raw);
+    assert_eq!(error.to_string(), "Access denied [403]");
+
+    let error = DdpError::from(&json!({ "error": 400, "details": { "field": "name" } }));
+    assert_eq!(error.details(), Some(&json!({ "field": "name" })));
+}
+
+#[test]
+fn string_codes_and_unknown_shapes() {
+    let error = DdpError::from(&json!({ "error": "validation-error" }));
+    assert_eq!(*error.code(), "validation-error");
+    assert!(!error.is_client_safe());
+    assert_eq!(error.reason(), None);
+
+    let raw = json!("something broke");
+    let error = DdpError::from(&raw);
+    assert_eq!(*error.code(), ErrorCode::Unknown);
+    assert_eq!(error.to_string(), "\"something broke\"");
+
+    let error = DdpError::local("timeout", "Method timed out");
+    assert_eq!(*error.code(), "timeout");
+    assert_eq!(error.error_type(), Some("Meteor.Error"));
+}
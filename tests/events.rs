@@ -0,0 +1,70 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use ddp::Connection;
+use ddp::client::CollectionEvent;
+use ddp::mock::MockServer;
+
+use common::next;
+
+// Subscribing to "things" adds a document and changes it before going ready,
+// unsubscribing removes it again. Any other publication is refused.
+fn serve() -> MockServer {
+    let server = common::serve();
+    server.publish("things", |peer, _| {
+        peer.added("things", "a", json!({ "n": 1, "old": true }));
+        peer.changed("things", "a", json!({ "n": 2 }), &["old"]);
+        Ok(())
+    });
+    server.on_unsub("things", |peer, _| peer.removed("things", "a"));
+    server
+}
+
+#[test]
+fn events_follow_a_subscription() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let things = conn.mongo("things".to_string());
+    let events = things.events();
+
+    let sub = things.subscribe();
+    assert_eq!(next(&events), CollectionEvent::Added {
+        id:     "a".to_string(),
+        fields: Some(json!({ "n": 1, "old": true })),
+    });
+    assert_eq!(next(&events), CollectionEvent::Changed {
+        id:      "a".to_string(),
+        fields:  Some(json!({ "n": 2 })),
+        cleared: Some(json!(["old"])),
+    });
+    assert_eq!(next(&events), CollectionEvent::Ready { subscription: sub.id().to_string() });
+
+    things.unsubscribe();
+    // Stopping is immediate, the server's clean-up follows.
+    assert_eq!(next(&events), CollectionEvent::NoSub { subscription: sub.id().to_string(), error: None });
+    assert_eq!(next(&events), CollectionEvent::Removed { id: "a".to_string() });
+}
+
+#[test]
+fn refused_subscription_comes_with_its_error() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let missing = conn.mongo("missing".to_string());
+    let events = missing.events();
+
+    let sub = missing.subscribe();
+    match next(&events) {
+        CollectionEvent::NoSub { subscription, error: Some(error) } => {
+            assert_eq!(subscription, sub.id());
+            assert_eq!(*error.code(), 404);
+        },
+        event => panic!("unexpected {:?}", event),
+    }
+
+    // Dropping the receiver just stops the events, the collection carries on.
+    drop(events);
+    missing.subscribe();
+}
@@ -0,0 +1,57 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::sync::Mutex;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+use ddp::Connection;
+use ddp::mock::Reply;
+
+use common::{next, serve};
+
+#[test]
+fn pings_a_quiet_server() {
+    let server = serve();
+    let (crashed, crashes) = channel();
+    let crashed = Mutex::new(crashed);
+    let (conn, _) = Connection::new(&server.url(), move || crashed.lock().unwrap().send(()).unwrap()).unwrap();
+    conn.heartbeat(Duration::from_millis(100), Duration::from_millis(100));
+
+    let first = server.expect("ping");
+    let second = server.expect("ping");
+    assert!(first["id"].is_string());
+    assert!(first["id"] != second["id"]);
+    assert!(crashes.recv_timeout(Duration::from_millis(300)).is_err());
+
+    let (tx, results) = channel();
+    conn.call("echo", Some(&vec![&json!(1)]), Box::new(move |result| tx.send(result.unwrap().clone()).unwrap()));
+    assert_eq!(next(&results), json!(1));
+
+    conn.no_heartbeat();
+    while server.next_message(Duration::from_millis(300)).is_some() {}
+    assert!(server.next_message(Duration::from_millis(300)).is_none());
+}
+
+#[test]
+fn hangs_up_on_a_silent_server() {
+    // Only connects.
+    let server = serve();
+    server.answer_pings(false);
+    server.method("echo", |_, _| Reply::Silence);
+    let (crashed, crashes) = channel();
+    let crashed = Mutex::new(crashed);
+    let (conn, _) = Connection::new(&server.url(), move || crashed.lock().unwrap().send(()).unwrap()).unwrap();
+    conn.heartbeat(Duration::from_millis(100), Duration::from_millis(100));
+
+    let (tx, results) = channel();
+    conn.call("echo", Some(&vec![&json!(1)]), Box::new(move |result| {
+        tx.send(result.map(|r| r.clone()).map_err(|e| e.clone())).unwrap();
+    }));
+    server.expect("ping");
+    next(&crashes);
+    assert_eq!(*next(&results).unwrap_err().code(), "disconnected");
+}
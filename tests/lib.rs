@@ -1,75 +1,106 @@
+extern crate ddp;
 #[macro_use]
 extern crate serde_json;
-extern crate ddp;
 
-use ddp::{Connection, Url};
+mod common;
+
+use std::sync::Mutex;
+use std::sync::mpsc::channel;
+
+use ddp::Connection;
+use ddp::client::{CollectionEvent, User};
+use ddp::mock::{self, MockServer, Reply};
+
+use common::next;
 
 #[test]
 fn test_connect_version() {
-    let url = Url::parse("ws://127.0.0.1:3000/websocket").unwrap(); // Get the URL
-    let ddp_client_result = Connection::new(&url, || {
-        println!("Everything closed!");
+    let server = MockServer::start();
+    server.method("login", |_, params| {
+        if params[0]["password"]["digest"].is_string() {
+            Reply::Result(json!({ "id": "rc_bot", "token": "t1", "tokenExpires": { "$date": 1500000000000i64 } }))
+        } else {
+            Reply::Error(mock::error(json!(400), "Match failed"))
+        }
+    });
+    let (client, _) = Connection::new(&server.url(), || {}).unwrap();
+    assert_eq!(client.session(), "mock-1");
+    assert_eq!(client.version(), "1");
+    assert_eq!(server.expect("connect")["version"], "1");
+
+    let (tx, results) = channel();
+    client.login_with_password(&User::Username("rc_bot".to_string()), "supersecret", move |result| {
+        tx.send(result.map(|login| login.user_id().to_string()).map_err(|e| e.clone())).unwrap();
+    });
+    assert_eq!(next(&results).unwrap(), "rc_bot");
+    assert_eq!(server.expect("method")["params"][0]["user"], json!({ "username": "rc_bot" }));
+
+    let (tx, results) = channel();
+    client.call("not_a_method", None, Box::new(move |result| tx.send(result.is_err()).unwrap()));
+    assert!(next(&results));
+}
+
+#[test]
+fn negotiates_an_older_version() {
+    let server = MockServer::start();
+    server.versions(&["pre1"]);
+    let (client, _) = Connection::new(&server.url(), || {}).unwrap();
+    assert_eq!(client.version(), "pre1");
+    assert_eq!(server.sessions(), 1);
+}
+
+#[test]
+fn syncs_a_collection() {
+    let server = MockServer::start();
+    server.publish("things", |peer, _| {
+        peer.added("things", "a", json!({ "n": 1 }));
+        Ok(())
+    });
+    server.method("/things/insert", |peer, params| {
+        let doc = &params[0];
+        peer.added("things", doc["_id"].as_str().unwrap(), json!({ "n": doc["n"] }));
+        Reply::Result(json!(null))
     });
+    let (client, _) = Connection::new(&server.url(), || {}).unwrap();
+    let things = client.mongo("things".to_string());
+    let events = things.events();
+    things.subscribe();
+
+    assert_eq!(next(&events), CollectionEvent::Added { id: "a".to_string(), fields: Some(json!({ "n": 1 })) });
+    match next(&events) {
+        CollectionEvent::Ready { .. } => {},
+        other => panic!("unexpected event {:?}", other),
+    }
 
-    let (client, handle) = match ddp_client_result {
-        Ok(cnh) => cnh,
-        Err(err)   => panic!("An error occured: {:?}", err),
-    };
+    server.changed("things", "a", json!({ "n": 2 }), &[]);
+    server.removed("things", "a");
+    assert_eq!(next(&events), CollectionEvent::Changed { id: "a".to_string(), fields: Some(json!({ "n": 2 })), cleared: None });
+    assert_eq!(next(&events), CollectionEvent::Removed { id: "a".to_string() });
+    assert_eq!(things.len(), 0);
 
-    println!("The session id is: {} with DDP v{}", client.session(), client.version());
+    let (tx, results) = channel();
+    let id = things.insert(&json!({ "n": 3 }), move |result| tx.send(result.is_ok()).unwrap()).unwrap();
+    assert!(next(&results));
+    assert_eq!(things.find_one(&id).unwrap()["n"], 3);
+}
 
-    let login_message = json!({
-        "user": {
-            "username": "rc_bot",
-        },
-        "password": "supersecret"
+#[test]
+fn refuses_unknown_subscriptions() {
+    let server = MockServer::start();
+    let (client, _) = Connection::new(&server.url(), || {}).unwrap();
+    let (tx, errors) = channel();
+    client.subscribe("nothing", None).on_ready(move |result| {
+        tx.send(result.unwrap_err().code().clone()).unwrap();
     });
-    // let json = json::encode(&login_message).unwrap();
-    println!("\n\nCalling a real method!\n\n");
-    client.call("login", Some(&vec![&login_message]), Box::new(|result| {
-        println!("Ran method, login");
-        match result {
-            Ok(output) => println!("got a result: {}", output),
-            Err(error) => println!("got an error: {}", error),
-        }
-    }));
-    handle.join();
-//
-//    println!("\n\nCalling a fake method!\n\n");
-//    client.call("not_a_method", None, |result| {
-//        print!("Ran method, ");
-//        match result {
-//            Ok(output) => println!("got a result: {}", output),
-//            Err(error) => println!("got an error: {}", error),
-//        }
-//    });
-//
-//    println!("\n\nSubscribing to MongoColl!\n\n");
-//    let mongo = client.mongo("MongoColl");
-//    mongo.on_add(|id, _| {
-//        println!("Added record with id: {}", &id);
-//    });
-//
-//    println!("\n\nInserting a record!\n\n");
-//    let record = json::Json::from_str("{ \"first ever meteor data from rust\": true }").unwrap();
-//    mongo.insert(&record, |result| {
-//        match result {
-//            Ok(_) => println!("First every successful insertion into Meteor through rust!"),
-//            Err(_) =>  println!("Damn! Got an error."),
-//        };
-//    });
-//
-//    println!("\n\nRemoving records...\n\n");
-//    mongo.remove(&record, |result| {
-//        println!("removed records {:?}", result);
-//    });
-//
-//    println!("Subscribing to non existent collection.");
-//    let nomongo = client.mongo("SomethingElse");
-//    nomongo.on_ready(|result| {
-//        match result {
-//            Ok(()) => unreachable!(),
-//            Err(e) => println!("Got an error, this is expected: {}", e),
-//        }
-//    });
+    assert_eq!(next(&errors), 404);
+}
+
+#[test]
+fn notices_hanging_up() {
+    let server = MockServer::start();
+    let (crashed, crashes) = channel();
+    let crashed = Mutex::new(crashed);
+    let (_client, _) = Connection::new(&server.url(), move || crashed.lock().unwrap().send(()).unwrap()).unwrap();
+    server.hang_up();
+    next(&crashes);
 }
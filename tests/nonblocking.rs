@@ -0,0 +1,65 @@
+#![cfg(feature = "async")]
+
+extern crate ddp;
+extern crate futures;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio;
+
+mod common;
+
+use ddp::client::{AsyncClient, CollectionEvent};
+use futures::StreamExt;
+
+use common::serve;
+
+fn run<F: std::future::Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(f)
+}
+
+#[test]
+fn calls_resolve_with_their_result() {
+    let server = serve();
+    run(async {
+        let client = AsyncClient::connect(&server.url()).await.unwrap();
+        assert_eq!(client.session(), "mock-1");
+
+        let param = json!("hello");
+        let (echo, fail) = futures::join!(client.call("echo", Some(&vec![&param])), client.call("fail", None));
+        assert_eq!(echo.unwrap(), param);
+        assert_eq!(*fail.unwrap_err().code(), 500);
+        let seed = server.expect("method")["randomSeed"].clone();
+        assert_eq!(seed.as_str().map(str::len), Some(43));
+        assert!(server.expect("method")["randomSeed"] != seed);
+
+        assert_eq!(*client.call("hangup", None).await.unwrap_err().code(), "disconnected");
+    });
+}
+
+#[test]
+fn subscriptions_and_collection_streams() {
+    let server = serve();
+    server.publish("things", |peer, _| {
+        peer.added("things", "a", json!({ "n": 1 }));
+        Ok(())
+    });
+    run(async {
+        let client = AsyncClient::connect(&server.url()).await.unwrap();
+        let mut things = client.watch("things");
+
+        let sub = client.subscribe("things", None).await.unwrap();
+        assert_eq!(sub.name(), "things");
+        assert_eq!(things.next().await, Some(CollectionEvent::Added {
+            id:     "a".to_string(),
+            fields: Some(json!({ "n": 1 })),
+        }));
+
+        match client.subscribe("missing", None).await {
+            Err(error) => assert_eq!(*error.code(), 404),
+            Ok(_)      => panic!("subscribed to a missing publication"),
+        }
+
+        client.call("hangup", None).await.ok();
+        assert_eq!(things.next().await, None);
+    });
+}
@@ -0,0 +1,80 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ddp::Connection;
+use ddp::client::{Observe, ObserveChanges, Order, Query};
+use ddp::mock::MockServer;
+
+use common::next;
+
+#[test]
+fn observe_follows_the_selector() {
+    let server = MockServer::start();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let things = conn.mongo("things".to_string());
+
+    server.added("things", "a", json!({ "n": 1 }));
+    server.added("things", "b", json!({ "n": 5 }));
+    while things.len() < 2 {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let (tx, events) = channel();
+    let (added_tx, changed_tx, removed_tx) = (tx.clone(), tx.clone(), tx);
+    let query = Query::new(&json!({ "n": { "$gt": 2 } })).unwrap();
+    let handle = things.observe(&query, Observe::new()
+        .on_added(move |doc| added_tx.send(format!("added {}", doc["_id"])).unwrap())
+        .on_changed(move |new, old| changed_tx.send(format!("changed {} -> {}", old["n"], new["n"])).unwrap())
+        .on_removed(move |doc| removed_tx.send(format!("removed {}", doc["_id"])).unwrap()));
+    assert_eq!(next(&events), "added \"b\"");
+
+    server.changed("things", "a", json!({ "n": 3 }), &[]);
+    assert_eq!(next(&events), "added \"a\"");
+    server.changed("things", "b", json!({ "n": 6 }), &[]);
+    assert_eq!(next(&events), "changed 5 -> 6");
+    server.changed("things", "a", json!({ "n": 0 }), &[]);
+    assert_eq!(next(&events), "removed \"a\"");
+
+    things.clear_listener(handle);
+    server.removed("things", "b");
+    assert!(events.recv_timeout(Duration::from_millis(200)).is_err());
+}
+
+#[test]
+fn observe_changes_reports_order() {
+    let server = MockServer::start();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let things = conn.mongo("things".to_string());
+
+    let (tx, events) = channel();
+    let (added_tx, moved_tx, changed_tx, removed_tx) = (tx.clone(), tx.clone(), tx.clone(), tx);
+    let query = Query::all().sort("n", Order::Ascending);
+    things.observe_changes(&query, ObserveChanges::new()
+        .on_added_before(move |id, fields, before| {
+            added_tx.send(format!("added {} {} before {:?}", id, fields, before)).unwrap()
+        })
+        .on_moved_before(move |id, before| moved_tx.send(format!("moved {} before {:?}", id, before)).unwrap())
+        .on_changed(move |id, fields, _| changed_tx.send(format!("changed {} {}", id, fields.unwrap())).unwrap())
+        .on_removed(move |id| removed_tx.send(format!("removed {}", id)).unwrap()));
+
+    server.added("things", "a", json!({ "n": 1 }));
+    assert_eq!(next(&events), "added a {\"n\":1} before None");
+    server.added("things", "b", json!({ "n": 2 }));
+    assert_eq!(next(&events), "added b {\"n\":2} before None");
+    server.added("things", "c", json!({ "n": 0 }));
+    assert_eq!(next(&events), "added c {\"n\":0} before Some(\"a\")");
+
+    server.changed("things", "c", json!({ "n": 3 }), &[]);
+    assert_eq!(next(&events), "moved c before None");
+    assert_eq!(next(&events), "changed c {\"n\":3}");
+
+    server.removed("things", "a");
+    assert_eq!(next(&events), "removed a");
+}
@@ -0,0 +1,160 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ddp::client::{Client, Overflow, State};
+use ddp::mock::{MockServer, Reply};
+
+use common::next;
+
+// A client that has lost its connection and can't get it back until the
+// server stops refusing. Methods answer with their own name, and the "s"
+// publication is ready straight away.
+fn offline_client() -> (Client, MockServer) {
+    let server = MockServer::start();
+    for name in &["a", "b", "c", "d"] {
+        server.method(name, move |_, _| Reply::Result(json!(name)));
+    }
+    server.publish("s", |_, _| Ok(()));
+
+    let mut client = Client::new(server.url()).unwrap();
+    client.retry_custom(|_, _| Some(50));
+    let (tx, statuses) = channel();
+    client.on_status(move |status| { tx.send(status.state().clone()).ok(); });
+    server.refuse(true);
+    server.hang_up();
+    while !matches!(next(&statuses), State::Waiting { .. }) {}
+    (client, server)
+}
+
+// What the client sent next, skipping its attempts to connect.
+fn sent(server: &MockServer) -> String {
+    loop {
+        let message = server.next_message(Duration::from_secs(5)).expect("nothing was sent");
+        match message["msg"].as_str() {
+            Some("method") => return format!("method {}", message["method"].as_str().unwrap()),
+            Some("sub")    => return format!("sub {}", message["name"].as_str().unwrap()),
+            _              => {},
+        }
+    }
+}
+
+#[test]
+fn rejects_once_full_and_flushes_in_order() {
+    let (client, server) = offline_client();
+    client.queue_offline(Some(2), Overflow::Reject);
+
+    let (tx, results) = channel();
+    let (a, c) = (tx.clone(), tx.clone());
+    client.call("a", None, move |result| a.send(format!("a {:?}", result.map(|r| r.clone()).map_err(|e| e.to_string()))).unwrap());
+    let sub = client.subscribe("s", None);
+    client.call("c", None, move |result| c.send(format!("c {}", result.unwrap_err().code())).unwrap());
+
+    assert_eq!(next(&results), "c outbox-full");
+    assert_eq!(client.queued(), 2);
+
+    server.refuse(false);
+    assert_eq!(sent(&server), "method a");
+    assert_eq!(sent(&server), "sub s");
+    assert_eq!(next(&results), "a Ok(String(\"a\"))");
+    let (tx, ready) = channel();
+    sub.on_ready(move |result| tx.send(result.is_ok()).unwrap());
+    assert!(next(&ready));
+    assert_eq!(client.queued(), 0);
+}
+
+#[test]
+fn rejected_callers_can_call_again() {
+    let (client, _server) = offline_client();
+    client.queue_offline(Some(1), Overflow::Reject);
+    let client = Arc::new(client);
+
+    client.call("a", None, |_| {});
+    let (tx, results) = channel();
+    let again = client.clone();
+    client.call("b", None, move |result| {
+        let tx = tx.clone();
+        tx.send(format!("b {}", result.unwrap_err().code())).unwrap();
+        again.call("c", None, move |result| tx.send(format!("c {}", result.unwrap_err().code())).unwrap());
+    });
+    assert_eq!(next(&results), "b outbox-full");
+    assert_eq!(next(&results), "c outbox-full");
+    assert_eq!(client.queued(), 1);
+}
+
+#[test]
+fn drops_the_oldest_once_full() {
+    let (client, server) = offline_client();
+    client.queue_offline(Some(2), Overflow::DropOldest);
+
+    let sub = client.subscribe("s", None);
+    let (tx, dropped) = channel();
+    sub.on_ready(move |result| tx.send(result.unwrap_err().code().to_string()).unwrap());
+    client.call("a", None, |_| {});
+    client.call("c", None, |_| {});
+
+    assert_eq!(next(&dropped), "outbox-dropped");
+    assert_eq!(client.queued(), 2);
+
+    server.refuse(false);
+    assert_eq!(sent(&server), "method a");
+    assert_eq!(sent(&server), "method c");
+}
+
+#[test]
+fn blocks_until_there_is_room_or_it_shuts() {
+    let (client, _server) = offline_client();
+    client.queue_offline(Some(2), Overflow::Block);
+    let client = Arc::new(client);
+
+    let (tx, results) = channel();
+    for name in &["a", "b", "c", "d"] {
+        let (client, tx) = (client.clone(), tx.clone());
+        thread::spawn(move || {
+            client.call(name, None, move |result| tx.send(result.unwrap_err().code().to_string()).unwrap());
+        });
+    }
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.queued(), 2);
+    assert!(results.try_recv().is_err());
+
+    client.disconnect();
+    for _ in 0..4 {
+        assert_eq!(next(&results), "disconnected");
+    }
+    assert_eq!(client.queued(), 0);
+}
+
+#[test]
+fn stub_writes_get_room_before_their_method() {
+    let (client, server) = offline_client();
+    client.queue_offline(Some(1), Overflow::Block);
+    let things = client.mongo("things");
+    client.stub("a", move |_, _| {
+        things.insert(&json!({ "_id": "t" }), |_| {});
+    });
+    let client = Arc::new(client);
+
+    let (tx, done) = channel();
+    let caller = client.clone();
+    thread::spawn(move || {
+        caller.call("a", None, |_| {});
+        tx.send(()).unwrap();
+    });
+    // The insert took the only room, so "a" waits for more.
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.queued(), 1);
+    assert!(done.try_recv().is_err());
+
+    server.refuse(false);
+    next(&done);
+    assert_eq!(sent(&server), "method /things/insert");
+    assert_eq!(sent(&server), "method a");
+}
@@ -0,0 +1,84 @@
+#[macro_use]
+extern crate serde_json;
+extern crate ddp;
+
+use ddp::client::{Order, Query};
+use serde_json::Value;
+
+fn people() -> Vec<Value> {
+    vec![
+        json!({ "_id": "a", "name": "Ada",   "age": 36, "tags": ["math", "code"], "address": { "city": "London" } }),
+        json!({ "_id": "b", "name": "Grace", "age": 85, "tags": ["navy", "code"], "address": { "city": "New York" } }),
+        json!({ "_id": "c", "name": "Alan",  "age": 41, "tags": ["math"] }),
+        json!({ "_id": "d", "name": "Linus", "age": null }),
+    ]
+}
+
+fn ids(docs: &[Value]) -> Vec<&str> {
+    docs.iter().map(|doc| doc["_id"].as_str().unwrap()).collect()
+}
+
+fn find(selector: Value) -> Vec<Value> {
+    Query::new(&selector).unwrap().run(people().iter())
+}
+
+#[test]
+fn equality_and_arrays() {
+    assert_eq!(ids(&find(json!({ "name": "Ada" }))), vec!["a"]);
+    assert_eq!(ids(&find(json!({ "tags": "code" }))), vec!["a", "b"]);
+    assert_eq!(ids(&find(json!({ "tags": ["math"] }))), vec!["c"]);
+    assert_eq!(ids(&find(json!({ "age": null }))), vec!["d"]);
+    assert_eq!(ids(&find(json!("b"))), vec!["b"]);
+}
+
+#[test]
+fn comparison_operators() {
+    assert_eq!(ids(&find(json!({ "age": { "$gt": 40 } }))), vec!["b", "c"]);
+    assert_eq!(ids(&find(json!({ "age": { "$gte": 36, "$lt": 85 } }))), vec!["a", "c"]);
+    assert_eq!(ids(&find(json!({ "name": { "$in": ["Ada", "Alan"] } }))), vec!["a", "c"]);
+    assert_eq!(ids(&find(json!({ "tags": { "$nin": ["code"] } }))), vec!["c", "d"]);
+    assert_eq!(ids(&find(json!({ "age": { "$not": { "$gt": 40 } } }))), vec!["a", "d"]);
+}
+
+#[test]
+fn exists_regex_and_paths() {
+    assert_eq!(ids(&find(json!({ "tags": { "$exists": false } }))), vec!["d"]);
+    assert_eq!(ids(&find(json!({ "name": { "$regex": "^a", "$options": "i" } }))), vec!["a", "c"]);
+    assert_eq!(ids(&find(json!({ "address.city": "London" }))), vec!["a"]);
+    assert_eq!(ids(&find(json!({ "tags.1": "code" }))), vec!["a", "b"]);
+}
+
+#[test]
+fn logical_operators() {
+    let either = json!({ "$or": [{ "name": "Ada" }, { "age": { "$gt": 80 } }] });
+    assert_eq!(ids(&find(either)), vec!["a", "b"]);
+    let both = json!({ "$and": [{ "tags": "math" }, { "age": { "$lt": 40 } }] });
+    assert_eq!(ids(&find(both)), vec!["a"]);
+}
+
+#[test]
+fn sort_skip_limit_and_fields() {
+    let query = Query::all().sort("age", Order::Descending).skip(1).limit(2)
+        .fields(&json!({ "name": 1, "address.city": 1 })).unwrap();
+    let docs = query.run(people().iter());
+    assert_eq!(docs, vec![
+        json!({ "_id": "c", "name": "Alan" }),
+        json!({ "_id": "a", "name": "Ada", "address": { "city": "London" } }),
+    ]);
+
+    let docs = Query::all().sort("name", Order::Ascending)
+        .fields(&json!({ "_id": 0, "tags": 0, "address": 0, "age": 0 })).unwrap()
+        .run(people().iter());
+    assert_eq!(docs[0], json!({ "name": "Ada" }));
+    assert_eq!(docs[3], json!({ "name": "Linus" }));
+
+    assert_eq!(Query::all().limit(0).run(people().iter()).len(), 4);
+}
+
+#[test]
+fn rejects_bad_selectors() {
+    assert!(Query::new(&json!({ "age": { "$near": 1 } })).is_err());
+    assert!(Query::new(&json!({ "$or": [] })).is_err());
+    assert!(Query::new(&json!({ "name": { "$regex": "(" } })).is_err());
+    assert!(Query::all().fields(&json!({ "name": 1, "age": 0 })).is_err());
+}
@@ -0,0 +1,58 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+use ddp::Connection;
+use ddp::client::{Alea, RandomStream};
+use ddp::mock::{MockServer, Reply};
+
+#[test]
+fn alea_matches_meteor() {
+    let mut alea = Alea::new(&["0"]);
+    assert_eq!(alea.id(), "cp9hWvhg8GSvuZ9os");
+    assert_eq!(alea.id(), "3f3k6Xo7rrHCifQhR");
+    assert_eq!(alea.id(), "shxDnjWWmnKPEoLhM");
+    assert_eq!(alea.id(), "6QTjB8C5SEqhmz4ni");
+
+    let mut alea = Alea::new(&["0"]);
+    assert_eq!(alea.fraction(), 0.5945264333859086);
+    assert_eq!(alea.fraction(), 0.8065849216654897);
+
+    let mut stream = RandomStream::new("seed");
+    assert_eq!(stream.sequence("/collection/things").id(), "ajJNHYseWLupPXmni");
+    assert_eq!(stream.sequence("/collection/things").id(), "8C4CcrB5KLHx334Yb");
+}
+
+#[test]
+fn methods_carry_a_random_seed() {
+    let server = MockServer::start();
+    server.method("hello", |_, _| Reply::Result(json!(null)));
+    server.method("/things/insert", |_, _| Reply::Result(json!(null)));
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+
+    let handle = conn.call("hello", None, Box::new(|_| {}));
+    let message = server.expect("method");
+    assert_eq!(message["id"], handle.id());
+    assert_eq!(message["randomSeed"], handle.random_seed());
+    assert_eq!(handle.random_seed().len(), 43);
+
+    let things = conn.mongo("things".to_string());
+    let id = things.insert(&json!({ "n": 1 }), |_| {}).unwrap();
+    let message = server.expect("method");
+    let seed = message["randomSeed"].as_str().unwrap();
+    assert_eq!(RandomStream::new(seed).sequence("/collection/things").id(), id);
+    assert_eq!(message["params"][0], json!({ "_id": id, "n": 1 }));
+
+    let id = things.insert(&json!({ "_id": "mine" }), |_| {}).unwrap();
+    assert_eq!(id, "mine");
+    assert_eq!(server.expect("method")["params"][0], json!({ "_id": "mine" }));
+
+    let oid = json!({ "$type": "oid", "$value": "5f1d7a3b9c8e4f2a1b0c3d4e" });
+    let id = things.insert(&json!({ "_id": oid }), |_| {}).unwrap();
+    assert_eq!(id, "5f1d7a3b9c8e4f2a1b0c3d4e");
+    assert_eq!(server.expect("method")["params"][0], json!({ "_id": oid }));
+
+    let id = things.insert(&json!({ "_id": 7 }), |_| {}).unwrap();
+    assert_eq!(id, "~7");
+    assert_eq!(server.expect("method")["params"][0], json!({ "_id": 7 }));
+}
@@ -0,0 +1,193 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use ddp::client::{Client, MethodCallbacks, Retry, State};
+use ddp::mock::{MockServer, Reply};
+
+use common::{next, serve};
+
+#[test]
+fn delays_follow_the_curve() {
+    let delays = |curve: Retry| (1..6).map(|attempt| curve.delay(attempt, 0).unwrap()).collect::<Vec<_>>();
+    assert_eq!(delays(Retry::Linear), vec![1000, 2000, 3000, 4000, 5000]);
+    assert_eq!(delays(Retry::Exponential), vec![1000, 2000, 4000, 8000, 16000]);
+    assert_eq!(delays(Retry::Quadratic), vec![1000, 4000, 9000, 16000, 25000]);
+
+    // Five minutes at most.
+    assert_eq!(Retry::Linear.delay(400, 0), Some(300 * 1000));
+    assert_eq!(Retry::Exponential.delay(40, 0), Some(300 * 1000));
+    assert_eq!(Retry::Quadratic.delay(100_000, 0), Some(300 * 1000));
+
+    let custom = Retry::Custom(Box::new(|attempt, last| if attempt < 3 { Some(last + 7) } else { None }));
+    assert_eq!(custom.delay(2, 10), Some(17));
+    assert_eq!(custom.delay(3, 17), None);
+}
+
+#[test]
+fn reconnects_to_the_same_session() {
+    let server = MockServer::start();
+    let mut client = Client::new(server.url()).unwrap();
+    client.retry_custom(|_, _| Some(10));
+    let (tx, retries) = channel();
+    client.on_retry(move |attempt, result| tx.send((attempt, result.map(|s| s.to_string()).map_err(|_| ()))).unwrap());
+    assert!(server.expect("connect").get("session").is_none());
+
+    server.hang_up();
+    assert_eq!(server.expect("connect")["session"], "mock-1");
+    assert_eq!(next(&retries), (1, Ok("mock-2".to_string())));
+    assert_eq!(client.session(), "mock-2");
+}
+
+#[test]
+fn gives_up_once_custom_says_so() {
+    let server = MockServer::start();
+    let mut client = Client::new(server.url()).unwrap();
+    let (tx, asked) = channel();
+    client.retry_custom(move |attempt, last| {
+        tx.send((attempt, last)).unwrap();
+        if attempt < 3 { Some(attempt * 10) } else { None }
+    });
+    let (tx, retries) = channel();
+    client.on_retry(move |attempt, result| tx.send((attempt, result.err().map(|e| format!("{:?}", e)))).unwrap());
+    let (tx, statuses) = channel();
+    client.on_status(move |status| { tx.send(status.state().clone()).ok(); });
+
+    // Nothing to reconnect to.
+    drop(server);
+    assert_eq!(next(&asked), (1, 0));
+    for attempt in 1..3 {
+        let (retried, error) = next(&retries);
+        assert_eq!(retried, attempt);
+        assert!(error.is_some());
+    }
+    assert_eq!(next(&asked), (2, 10));
+    assert_eq!(next(&asked), (3, 20));
+    while next(&statuses) != State::Failed {}
+    assert!(retries.recv_timeout(Duration::from_millis(200)).is_err());
+    assert_eq!(*client.status().state(), State::Failed);
+}
+
+#[test]
+fn replays_subscriptions_and_unanswered_methods() {
+    let server = serve();
+    server.publish("things", |_, _| Ok(()));
+
+    let mut client = Client::new(server.url()).unwrap();
+    client.retry_custom(|_, _| Some(50));
+    let (tx, ready) = channel();
+    client.subscribe("things", Some(&vec![&json!(1), &json!("x")])).on_ready(move |result| tx.send(result.is_ok()).unwrap());
+    let sub = server.expect("sub");
+    assert!(next(&ready));
+
+    let (tx, results) = channel();
+    let (echoed, slow) = (tx.clone(), tx);
+    client.call("echo", Some(&vec![&json!("echo")]), move |result| echoed.send(result.unwrap().clone()).unwrap());
+    assert_eq!(server.expect("method")["method"], "echo");
+    assert_eq!(next(&results), json!("echo"));
+    client.call("slow", Some(&vec![&json!({ "a": 1 })]), move |result| slow.send(result.unwrap().clone()).unwrap());
+    let method = server.expect("method");
+    assert_eq!(method["method"], "slow");
+
+    server.hang_up();
+    let resent = server.expect("sub");
+    assert_eq!((&resent["id"], &resent["name"], &resent["params"]), (&sub["id"], &sub["name"], &sub["params"]));
+    let resent = server.expect("method");
+    assert_eq!(resent["method"], "slow");
+    assert_eq!((&resent["id"], &resent["randomSeed"], &resent["params"]), (&method["id"], &method["randomSeed"], &method["params"]));
+    assert!(server.next_message(Duration::from_millis(200)).map_or(true, |message| message["msg"] != "method"));
+
+    server.peer().result(resent["id"].as_str().unwrap(), Ok(json!("slow")));
+    assert_eq!(next(&results), json!("slow"));
+    assert!(results.recv_timeout(Duration::from_millis(300)).is_err());
+}
+
+#[test]
+fn answered_methods_wait_for_resubscriptions() {
+    let server = MockServer::start();
+    server.method("save", |_, _| Reply::Silence);
+    // The second time round, the document "save" wrote is published, but
+    // only once the test says so.
+    let (release, released) = channel();
+    let released = Mutex::new(released);
+    let rounds = AtomicUsize::new(0);
+    server.publish("things", move |peer, _| {
+        if rounds.fetch_add(1, Ordering::SeqCst) > 0 {
+            released.lock().unwrap().recv_timeout(Duration::from_secs(5)).ok();
+            peer.added("things", "saved", json!({}));
+        }
+        Ok(())
+    });
+
+    let mut client = Client::new(server.url()).unwrap();
+    client.retry_custom(|_, _| Some(50));
+    let things = client.mongo("things");
+    let (tx, ready) = channel();
+    client.subscribe("things", None).on_ready(move |result| tx.send(result.is_ok()).unwrap());
+    assert!(next(&ready));
+
+    let (tx, events) = channel();
+    let (result, updated, complete) = (tx.clone(), tx.clone(), tx);
+    let seen = things.clone();
+    client.apply("save", None, MethodCallbacks::new()
+        .on_result(move |_| result.send("result".to_string()).unwrap())
+        .on_updated(move || updated.send(format!("updated {}", seen.find_one("saved").is_some())).unwrap())
+        .on_complete(move |_| complete.send("complete".to_string()).unwrap()));
+    let id = server.expect("method")["id"].as_str().unwrap().to_string();
+
+    // Answered, but the socket goes before `updated` does.
+    server.peer().result(&id, Ok(json!(null)));
+    assert_eq!(next(&events), "result");
+    server.hang_up();
+
+    server.expect("sub");
+    assert!(events.recv_timeout(Duration::from_millis(300)).is_err());
+    release.send(()).unwrap();
+    assert_eq!(next(&events), "updated true");
+    assert_eq!(next(&events), "complete");
+    assert!(server.next_message(Duration::from_millis(200)).map_or(true, |message| message["msg"] != "method"));
+}
+
+// Hangs up on a client that would wait a minute to retry, and returns a
+// receiver that disconnects once the client's reconnecting thread is gone.
+fn waiting(server: &MockServer, client: &mut Client) -> Receiver<()> {
+    client.retry_custom(|_, _| Some(60 * 1000));
+    let (tx, gone) = channel::<()>();
+    client.on_retry(move |_, _| { let _ = &tx; });
+    let (tx, statuses) = channel();
+    client.on_status(move |status| { tx.send(status.state().clone()).ok(); });
+
+    server.hang_up();
+    while !matches!(next(&statuses), State::Waiting { .. }) {}
+    gone
+}
+
+#[test]
+fn disconnecting_ends_the_wait_to_retry() {
+    let server = MockServer::start();
+    let mut client = Client::new(server.url()).unwrap();
+    let gone = waiting(&server, &mut client);
+
+    client.disconnect();
+    assert_eq!(*client.status().state(), State::Offline);
+    drop(client);
+    assert_eq!(gone.recv_timeout(Duration::from_secs(2)), Err(RecvTimeoutError::Disconnected));
+}
+
+#[test]
+fn dropping_the_client_ends_the_wait_to_retry() {
+    let server = MockServer::start();
+    let mut client = Client::new(server.url()).unwrap();
+    let gone = waiting(&server, &mut client);
+
+    drop(client);
+    assert_eq!(gone.recv_timeout(Duration::from_secs(2)), Err(RecvTimeoutError::Disconnected));
+    assert_eq!(server.sessions(), 1);
+}
@@ -0,0 +1,263 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+extern crate websocket;
+
+mod common;
+
+use std::sync::Mutex;
+use std::sync::mpsc::channel;
+
+use ddp::{Connection, Url};
+use ddp::client::{CollectionEvent, DdpError, ErrorCode, MethodCallbacks};
+use ddp::server::{Server, ServerHandle};
+use serde_json::Value;
+use websocket::{ClientBuilder, Message};
+use websocket::message::OwnedMessage;
+
+use common::next;
+
+fn url(handle: &ServerHandle) -> Url {
+    Url::parse(&format!("ws://{}/websocket", handle.local_addr())).unwrap()
+}
+
+fn call(conn: &Connection, method: &str, params: Vec<Value>) -> Result<Value, DdpError> {
+    let (tx, results) = channel();
+    let params: Vec<&Value> = params.iter().collect();
+    conn.call(method, Some(&params), Box::new(move |result| {
+        tx.send(result.map(|r| r.clone()).map_err(|e| e.clone())).unwrap();
+    }));
+    next(&results)
+}
+
+#[test]
+fn answers_methods() {
+    let server = Server::new();
+    server.method("add", |_, params| Ok(json!(params.iter().filter_map(|n| n.as_i64()).sum::<i64>())));
+    server.method("fail", |_, _| Err(DdpError::local("nope", "Not today")));
+    server.method("login", |context, params| {
+        context.set_user_id(params[0].as_str());
+        Ok(json!(null))
+    });
+    server.method("whoami", |context, _| Ok(json!(context.user_id())));
+    server.method("seed", |context, _| Ok(json!(context.random_seed())));
+    let handle = server.listen("127.0.0.1:0").unwrap();
+    let (conn, _) = Connection::new(&url(&handle), || {}).unwrap();
+    assert_eq!(conn.version(), "1");
+
+    assert_eq!(call(&conn, "add", vec![json!(1), json!(2)]).unwrap(), json!(3));
+    let error = call(&conn, "fail", vec![]).unwrap_err();
+    assert_eq!(*error.code(), "nope");
+    assert_eq!(error.reason(), Some("Not today"));
+    assert_eq!(*call(&conn, "missing", vec![]).unwrap_err().code(), ErrorCode::Number(404));
+
+    assert_eq!(call(&conn, "whoami", vec![]).unwrap(), json!(null));
+    call(&conn, "login", vec![json!("ann")]).unwrap();
+    assert_eq!(call(&conn, "whoami", vec![]).unwrap(), json!("ann"));
+
+    let (tx, seeds) = channel();
+    let handle = conn.apply("seed", None, MethodCallbacks::new().on_result(move |result| {
+        tx.send(result.unwrap().clone()).unwrap();
+    }));
+    assert_eq!(next(&seeds), json!(handle.random_seed()));
+}
+
+#[test]
+fn merges_documents_across_subscriptions() {
+    let server = Server::new();
+    server.publish("things", |publication, _| {
+        publication.added("things", "a", &json!({ "n": 1 }));
+        publication.added("things", "b", &json!({ "n": 2 }));
+        publication.ready();
+        Ok(())
+    });
+    server.publish("more", |publication, _| {
+        publication.added("things", "b", &json!({ "n": 2 }));
+        publication.ready();
+        Ok(())
+    });
+    let handle = server.listen("127.0.0.1:0").unwrap();
+    let (conn, _) = Connection::new(&url(&handle), || {}).unwrap();
+    let things = conn.mongo("things".to_string());
+
+    let (tx, ready) = channel();
+    let (first, second) = (tx.clone(), tx);
+    let all = conn.subscribe("things", None);
+    all.on_ready(move |_| first.send(()).unwrap());
+    next(&ready);
+    let more = conn.subscribe("more", None);
+    more.on_ready(move |_| second.send(()).unwrap());
+    next(&ready);
+    assert_eq!(things.len(), 2);
+
+    let (tx, stopped) = channel();
+    more.on_stop(move |_| tx.send(()).unwrap());
+    more.stop();
+    next(&stopped);
+    assert_eq!(things.find_one("b").unwrap()["n"], 2);
+
+    let events = things.events();
+    all.stop();
+    let mut removed: Vec<CollectionEvent> = (0..2).map(|_| next(&events)).collect();
+    removed.sort_by_key(|event| format!("{:?}", event));
+    assert_eq!(removed, vec![CollectionEvent::Removed { id: "a".to_string() },
+                             CollectionEvent::Removed { id: "b".to_string() }]);
+}
+
+#[test]
+fn tracks_who_owns_each_field() {
+    let server = Server::new();
+    server.publish("things", |publication, _| {
+        publication.added("things", "b", &json!({ "n": 2, "mine": 1 }));
+        publication.ready();
+        Ok(())
+    });
+    server.publish("more", |publication, params| {
+        publication.added("things", "b", &json!({ "n": 3, "extra": true }));
+        if params.first() == Some(&json!("briefly")) {
+            publication.removed("things", "b");
+        }
+        publication.ready();
+        Ok(())
+    });
+    let handle = server.listen("127.0.0.1:0").unwrap();
+    let (conn, _) = Connection::new(&url(&handle), || {}).unwrap();
+    let things = conn.mongo("things".to_string());
+    let subscribe = |params: Option<&Vec<&Value>>| {
+        let sub = conn.subscribe(if params.is_some() { "more" } else { "things" }, params);
+        let (tx, ready) = channel();
+        sub.on_ready(move |_| tx.send(()).unwrap());
+        next(&ready);
+        sub
+    };
+
+    let all = subscribe(None);
+    // "n" stays as the first subscription has it.
+    subscribe(Some(&vec![&json!("briefly")]));
+    assert_eq!(things.find_one("b"), Some(json!({ "_id": "b", "n": 2, "mine": 1 })));
+    let more = subscribe(Some(&vec![&json!("for good")]));
+    assert_eq!(things.find_one("b"), Some(json!({ "_id": "b", "n": 2, "mine": 1, "extra": true })));
+
+    let events = things.events();
+    all.stop();
+    assert_eq!(next(&events), CollectionEvent::Changed {
+        id:      "b".to_string(),
+        fields:  Some(json!({ "n": 3 })),
+        cleared: Some(json!(["mine"])),
+    });
+    assert_eq!(things.find_one("b"), Some(json!({ "_id": "b", "n": 3, "extra": true })));
+
+    more.stop();
+    assert_eq!(next(&events), CollectionEvent::Removed { id: "b".to_string() });
+}
+
+#[test]
+fn pushes_until_stopped() {
+    let server = Server::new();
+    let (tx, publications) = channel();
+    let tx = Mutex::new(tx);
+    server.publish("ticker", move |publication, params| {
+        if params.first() == Some(&json!("refuse")) {
+            return Err(DdpError::local("denied", "No ticker for you"));
+        }
+        publication.ready();
+        tx.lock().unwrap().send(publication.clone()).unwrap();
+        Ok(())
+    });
+    let handle = server.listen("127.0.0.1:0").unwrap();
+    let (conn, _) = Connection::new(&url(&handle), || {}).unwrap();
+    let ticks = conn.mongo("ticks".to_string());
+    let events = ticks.events();
+
+    let sub = conn.subscribe("ticker", None);
+    let publication = next(&publications);
+    assert_eq!(publication.name(), "ticker");
+    assert_eq!(publication.id(), sub.id());
+    let (tx, stops) = channel();
+    publication.on_stop(move || tx.send(()).unwrap());
+
+    publication.added("ticks", "t1", &json!({ "at": 1 }));
+    publication.changed("ticks", "elsewhere", &json!({ "at": 2 }), &[]);
+    publication.changed("ticks", "t1", &json!({ "at": 2 }), &[]);
+    publication.removed("ticks", "t1");
+    assert_eq!(next(&events), CollectionEvent::Added { id: "t1".to_string(), fields: Some(json!({ "at": 1 })) });
+    assert_eq!(next(&events), CollectionEvent::Changed { id: "t1".to_string(), fields: Some(json!({ "at": 2 })), cleared: None });
+    assert_eq!(next(&events), CollectionEvent::Removed { id: "t1".to_string() });
+
+    sub.stop();
+    next(&stops);
+    assert!(publication.is_stopped());
+    publication.added("ticks", "t2", &json!({}));
+
+    let (tx, errors) = channel();
+    let refused = conn.subscribe("ticker", Some(&vec![&json!("refuse")]));
+    refused.on_ready(move |result| tx.send(result.unwrap_err().code().clone()).unwrap());
+    assert_eq!(next(&errors), "denied");
+    assert!(ticks.find_one("t2").is_none());
+
+    conn.subscribe("ticker", None);
+    let publication = next(&publications);
+    let (tx, stops) = channel();
+    publication.on_stop(move || tx.send(()).unwrap());
+    drop(conn);
+    next(&stops);
+}
+
+#[test]
+fn negotiates_versions() {
+    let server = Server::new();
+    let handle = server.listen("127.0.0.1:0").unwrap();
+    let mut client = ClientBuilder::new(&url(&handle).to_string()).unwrap().connect_insecure().unwrap();
+
+    let mut send = |message: Value| {
+        client.send_message(&Message::text(message.to_string())).unwrap();
+        match client.recv_message() {
+            Ok(OwnedMessage::Text(text)) => serde_json::from_str::<Value>(&text).unwrap(),
+            other => panic!("unexpected reply {:?}", other),
+        }
+    };
+    assert_eq!(send(json!({ "msg": "sub", "id": "s", "name": "things" }))["msg"], "error");
+    assert_eq!(send(json!({ "msg": "connect", "version": "2", "support": ["2", "pre1"] })),
+               json!({ "msg": "failed", "version": "pre1" }));
+
+    let mut client = ClientBuilder::new(&url(&handle).to_string()).unwrap().connect_insecure().unwrap();
+    client.send_message(&Message::text(json!({ "msg": "connect", "version": "pre1", "support": ["pre1"] }).to_string())).unwrap();
+    match client.recv_message() {
+        Ok(OwnedMessage::Text(text)) => {
+            let reply: Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(reply["msg"], "connected");
+            assert!(reply["session"].is_string());
+        },
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[test]
+fn shuts_down() {
+    let server = Server::new();
+    let (tx, stops) = channel();
+    let tx = Mutex::new(tx);
+    server.publish("things", move |publication, _| {
+        let tx = tx.lock().unwrap().clone();
+        publication.on_stop(move || tx.send(()).unwrap());
+        publication.ready();
+        Ok(())
+    });
+    let handle = server.listen("127.0.0.1:0").unwrap();
+    let (tx, crashes) = channel();
+    let tx = Mutex::new(tx);
+    let (conn, _) = Connection::new(&url(&handle), move || tx.lock().unwrap().send(()).unwrap()).unwrap();
+    let (tx, ready) = channel();
+    conn.subscribe("things", None).on_ready(move |_| tx.send(()).unwrap());
+    next(&ready);
+
+    handle.shutdown();
+    next(&stops);
+    next(&crashes);
+    assert!(Connection::new(&url(&handle), || {}).is_err());
+
+    let handle = server.listen("127.0.0.1:0").unwrap();
+    let url = url(&handle);
+    drop(handle);
+    assert!(Connection::new(&url, || {}).is_err());
+}
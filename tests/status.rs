@@ -0,0 +1,80 @@
+extern crate ddp;
+
+mod common;
+
+use std::sync::mpsc::channel;
+use std::time::{Duration, SystemTime};
+
+use ddp::Connection;
+use ddp::client::{Client, State};
+use ddp::mock::MockServer;
+
+use common::next;
+
+#[test]
+fn reports_reconnecting() {
+    let server = MockServer::start();
+    let mut client = Client::new(server.url()).unwrap();
+    client.retry_custom(|_, _| Some(50));
+    assert_eq!(*client.status().state(), State::Connected);
+    assert!(client.status().connected());
+    assert_eq!(client.status().last_error(), None);
+
+    let (tx, statuses) = channel();
+    client.on_status(move |status| tx.send(status.clone()).unwrap());
+    let before = SystemTime::now();
+    server.hang_up();
+
+    let waiting = next(&statuses);
+    match *waiting.state() {
+        State::Waiting { retry_count, retry_time } => {
+            assert_eq!(retry_count, 0);
+            assert!(retry_time >= before + Duration::from_millis(50));
+        },
+        ref other => panic!("unexpected state {:?}", other),
+    }
+    assert_eq!(waiting.last_error(), Some("The connection was lost"));
+    assert_eq!(*next(&statuses).state(), State::Connecting);
+
+    let connected = next(&statuses);
+    assert!(connected.connected());
+    assert_eq!(connected.last_error(), Some("The connection was lost"));
+    assert_eq!(client.status(), connected);
+}
+
+#[test]
+fn reports_giving_up() {
+    let server = MockServer::start();
+    let mut client = Client::new(server.url()).unwrap();
+    client.retry_custom(|attempt, _| if attempt < 3 { Some(20) } else { None });
+    let (tx, statuses) = channel();
+    client.on_status(move |status| tx.send(status.clone()).unwrap());
+    // Nothing to come back to.
+    drop(server);
+
+    let states: Vec<State> = (0..5).map(|_| next(&statuses).state().clone()).collect();
+    match &states[..] {
+        &[State::Waiting { retry_count: 0, .. }, State::Connecting,
+          State::Waiting { retry_count: 1, .. }, State::Connecting, State::Failed] => {},
+        other => panic!("unexpected states {:?}", other),
+    }
+    assert!(client.status().last_error().unwrap() != "The connection was lost");
+}
+
+#[test]
+fn goes_offline_without_retrying() {
+    let server = MockServer::start();
+    let client = Client::new(server.url()).unwrap();
+    let (tx, statuses) = channel();
+    client.on_status(move |status| tx.send(status.clone()).unwrap());
+    server.hang_up();
+    assert_eq!(*next(&statuses).state(), State::Offline);
+
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let (tx, statuses) = channel();
+    conn.on_status(move |status| tx.send(status.clone()).unwrap());
+    server.hang_up();
+    let offline = next(&statuses);
+    assert_eq!(*offline.state(), State::Offline);
+    assert_eq!(offline.last_error(), Some("The connection was lost"));
+}
@@ -0,0 +1,169 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ddp::Connection;
+use ddp::client::{CollectionEvent, Query, RandomStream};
+use ddp::mock::{MockServer, Reply};
+use serde_json::Value;
+
+use common::next;
+
+// Subscribing to "things" publishes the document "a". "addThing" is left for
+// the test to answer, "bump" fails and "clear" does nothing.
+fn serve() -> MockServer {
+    let server = common::serve();
+    server.publish("things", |peer, _| {
+        peer.added("things", "a", json!({ "n": 1, "tags": ["y"] }));
+        Ok(())
+    });
+    server.method("addThing", |_, _| Reply::Silence);
+    server.method("bump", |_, _| Reply::Error(json!({ "error": 403, "reason": "Not allowed" })));
+    server.method("clear", |_, _| Reply::Result(json!(null)));
+    server
+}
+
+#[test]
+fn stub_writes_show_until_the_method_settles() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    conn.stub("addThing", |context, params| {
+        context.insert("things", params[0]);
+    });
+    let things = conn.mongo("things".to_string());
+    let events = things.events();
+
+    let (tx, results) = channel();
+    let handle = conn.call("addThing", Some(&vec![&json!({ "title": "tea" })]), Box::new(move |result| {
+        tx.send(result.unwrap().clone()).unwrap();
+    }));
+    let id = handle.random_stream().sequence("/collection/things").id();
+    assert_eq!(things.find_one(&id), Some(json!({ "_id": id, "title": "tea" })));
+    match next(&events) {
+        CollectionEvent::Added { id: added, fields } => {
+            assert_eq!(added, id);
+            assert_eq!(fields, Some(json!({ "title": "tea" })));
+        },
+        other => panic!("unexpected event {:?}", other),
+    }
+
+    // The server inserts it the way the stub did, adding a field of its own,
+    // and its version arrives before `updated`, staying hidden.
+    let message = server.expect("method");
+    let seed = message["randomSeed"].as_str().unwrap();
+    let doc = RandomStream::new(seed).sequence("/collection/things").id();
+    let peer = server.peer();
+    peer.result(message["id"].as_str().unwrap(), Ok(json!(doc)));
+    peer.added("things", &doc, json!({ "title": "tea", "server": true }));
+    assert_eq!(next(&results), json!(id));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(things.find_one(&id), Some(json!({ "_id": id, "title": "tea" })));
+
+    peer.updated(&[message["id"].as_str().unwrap()]);
+    match next(&events) {
+        CollectionEvent::Changed { id: changed, fields, cleared } => {
+            assert_eq!(changed, id);
+            assert_eq!(fields, Some(json!({ "server": true })));
+            assert_eq!(cleared, None);
+        },
+        other => panic!("unexpected event {:?}", other),
+    }
+    assert_eq!(things.find_one(&id), Some(json!({ "_id": id, "title": "tea", "server": true })));
+    assert_eq!(things.len(), 1);
+}
+
+#[test]
+fn failed_methods_roll_back() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let (tx, stubbed) = channel();
+    conn.stub("bump", move |context, _| {
+        let modified = context.update("things", &json!("a"), &json!({
+            "$inc":  { "n": 2 },
+            "$push": { "tags": { "$each": ["x", "z"] } },
+            "$set":  { "nested.deep": true },
+        }));
+        let refused = context.update("things", &json!({}), &json!({ "$frobnicate": { "n": 1 } }));
+        tx.send((modified.unwrap(), refused.is_err())).unwrap();
+    });
+    conn.stub("clear", |context, _| {
+        context.remove("things", &json!({ "n": { "$gte": 1 } })).unwrap();
+    });
+    let things = conn.mongo("things".to_string());
+    let events = things.events();
+
+    let sub = conn.subscribe("things", None);
+    next(&events);
+    let (ready, is_ready) = channel();
+    sub.on_ready(move |_| ready.send(()).unwrap());
+    next(&is_ready);
+
+    conn.call("bump", None, Box::new(|_| {}));
+    assert_eq!(next(&stubbed), (1, true));
+    assert_eq!(things.find_one("a"), Some(json!({
+        "_id": "a", "n": 3, "tags": ["y", "x", "z"], "nested": { "deep": true },
+    })));
+    assert_eq!(things.find(&Query::new(&json!({ "tags": "z" })).unwrap()).len(), 1);
+    next(&events);
+
+    // The server refused, so its untouched version comes back.
+    match next(&events) {
+        CollectionEvent::Changed { fields, cleared, .. } => {
+            assert_eq!(fields, Some(json!({ "n": 1, "tags": ["y"] })));
+            assert_eq!(cleared, Some(json!(["nested"])));
+        },
+        other => panic!("unexpected event {:?}", other),
+    }
+    assert_eq!(things.find_one("a"), Some(json!({ "_id": "a", "n": 1, "tags": ["y"] })));
+
+    conn.call("clear", None, Box::new(|_| {}));
+    assert!(things.is_empty());
+    match next(&events) {
+        CollectionEvent::Removed { id } => assert_eq!(id, "a"),
+        other => panic!("unexpected event {:?}", other),
+    }
+    match next(&events) {
+        CollectionEvent::Added { id, .. } => assert_eq!(id, "a"),
+        other => panic!("unexpected event {:?}", other),
+    }
+    assert_eq!(things.len(), 1);
+}
+
+#[test]
+fn arithmetic_only_defaults_missing_fields() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let (tx, stubbed) = channel();
+    conn.stub("clear", move |context, _| {
+        let mut update = |modifier: Value| context.update("things", &json!("a"), &modifier).map_err(|e| e.to_string());
+        update(json!({ "$set": { "empty": null } })).unwrap();
+        let results = vec![
+            update(json!({ "$inc": { "empty": 1 } })),
+            update(json!({ "$mul": { "empty": 2 } })),
+            update(json!({ "$inc": { "missing": 5 } })),
+            update(json!({ "$mul": { "other": 2 } })),
+        ];
+        tx.send(results).unwrap();
+    });
+    let things = conn.mongo("things".to_string());
+    let events = things.events();
+    conn.subscribe("things", None);
+    next(&events);
+
+    conn.call("clear", None, Box::new(|_| {}));
+    assert_eq!(next(&stubbed), vec![
+        Err("invalid query: Cannot apply $inc to a value of non-numeric type".to_string()),
+        Err("invalid query: Cannot apply $mul to a value of non-numeric type".to_string()),
+        Ok(1),
+        Ok(1),
+    ]);
+    assert_eq!(things.find_one("a").unwrap()["empty"], Value::Null);
+    assert_eq!(things.find_one("a").unwrap()["missing"], 5);
+    assert_eq!(things.find_one("a").unwrap()["other"], 0);
+}
@@ -0,0 +1,162 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+use ddp::Connection;
+use ddp::mock::MockServer;
+
+use common::next;
+
+// The "feed" publication adds a document named after its first param, if any,
+// to both "left" and "right", and removes it again on unsub; anything else is
+// refused.
+// There is no merge box, every subscription adds and removes its own
+// documents.
+fn serve() -> MockServer {
+    let server = common::serve();
+    server.publish("feed", |peer, params| {
+        if let Some(doc) = params.first().and_then(|doc| doc.as_str()) {
+            peer.added("left", doc, json!({}));
+            peer.added("right", doc, json!({}));
+        }
+        Ok(())
+    });
+    server.on_unsub("feed", |peer, params| {
+        if let Some(doc) = params.first().and_then(|doc| doc.as_str()) {
+            peer.removed("left", doc);
+            peer.removed("right", doc);
+        }
+    });
+    server
+}
+
+fn ready(sub: &ddp::client::Subscription) -> Receiver<bool> {
+    let (tx, rx) = channel();
+    sub.on_ready(move |result| tx.send(result.is_ok()).unwrap());
+    rx
+}
+
+#[test]
+fn publication_feeds_several_collections() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let left = conn.mongo("left".to_string());
+    let right = conn.mongo("right".to_string());
+
+    let param = json!("doc");
+    let sub = conn.subscribe("feed", Some(&vec![&param]));
+    assert_eq!(sub.name(), "feed");
+
+    let (tx, rx) = channel();
+    sub.on_ready(move |result| tx.send(result.is_ok()).unwrap());
+    assert!(next(&rx));
+    assert!(sub.ready());
+    assert!(left.find_one("doc").is_some());
+    assert!(right.find_one("doc").is_some());
+
+    let (tx, rx) = channel();
+    sub.on_ready(move |result| tx.send(result.is_ok()).unwrap());
+    assert!(rx.recv_timeout(Duration::from_secs(1)).unwrap());
+
+    let (tx, rx) = channel();
+    sub.on_stop(move |error| tx.send(error.cloned()).unwrap());
+    sub.stop();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), None);
+    assert!(!sub.ready());
+}
+
+#[test]
+fn refused_subscription_reports_error() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+
+    let sub = conn.subscribe("secret", None);
+    let (ready_tx, ready_rx) = channel();
+    let (stop_tx, stop_rx) = channel();
+    sub.on_ready(move |result| ready_tx.send(result.err().cloned()).unwrap());
+    sub.on_stop(move |error| stop_tx.send(error.cloned()).unwrap());
+
+    let error = next(&ready_rx).unwrap();
+    assert_eq!(error.reason(), Some("Subscription 'secret' not found"));
+    assert_eq!(*error.code(), 404);
+    assert_eq!(stop_rx.recv_timeout(Duration::from_secs(1)).unwrap(), Some(error));
+    assert!(!sub.ready());
+}
+
+#[test]
+fn shared_documents_outlive_one_subscription() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let left = conn.mongo("left".to_string());
+    let (tx, removed) = channel();
+    left.on_remove(move |id| tx.send(id.to_string()).unwrap());
+
+    let shared = json!("shared");
+    let first = conn.subscribe("feed", Some(&vec![&shared]));
+    let second = conn.subscribe("feed", Some(&vec![&shared]));
+    assert!(first.id() != second.id());
+    assert!(next(&ready(&first)));
+    assert!(next(&ready(&second)));
+
+    first.stop();
+    let other = json!("other");
+    let third = conn.subscribe("feed", Some(&vec![&other]));
+    assert!(next(&ready(&third)));
+    assert!(left.find_one("shared").is_some());
+    assert!(removed.try_recv().is_err());
+
+    second.stop();
+    assert_eq!(next(&removed), "shared");
+    assert!(left.find_one("shared").is_none());
+}
+
+#[test]
+fn collection_subscriptions_are_independent() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let feed = conn.mongo("feed".to_string());
+
+    let (tx, rx) = channel();
+    feed.on_ready(move |result| tx.send(result.is_ok()).unwrap());
+    let first = feed.subscribe();
+    let second = feed.subscribe();
+    assert!(first.id() != second.id());
+    assert!(next(&rx));
+    assert!(next(&ready(&second)));
+
+    feed.unsubscribe();
+    assert!(!first.ready());
+    assert!(!second.ready());
+}
+
+#[test]
+fn late_callbacks_can_subscribe_and_stop() {
+    let server = serve();
+    let conn = Arc::new(Connection::new(&server.url(), || {}).unwrap().0);
+    let param = json!("doc");
+    let sub = conn.subscribe("feed", Some(&vec![&param]));
+    assert!(next(&ready(&sub)));
+
+    let (tx, rx) = channel();
+    let stopping = sub.clone();
+    sub.on_ready(move |_| {
+        stopping.stop();
+        tx.send(()).unwrap();
+    });
+    next(&rx);
+
+    let (tx, rx) = channel();
+    let again = conn.clone();
+    sub.on_stop(move |_| {
+        let param = json!("again");
+        let sub = again.subscribe("feed", Some(&vec![&param]));
+        tx.send(sub.id().to_string()).unwrap();
+    });
+    next(&rx);
+}
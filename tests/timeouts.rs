@@ -0,0 +1,104 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+use ddp::Connection;
+use ddp::client::{Client, MethodCallbacks};
+
+use common::{next, serve};
+
+#[test]
+fn callbacks_can_call_methods() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let conn = Arc::new(conn);
+
+    // Timed out on the timer's thread, and answered on the receiving one.
+    let (tx, results) = channel();
+    let outer = conn.clone();
+    let callbacks = MethodCallbacks::new()
+        .on_result(move |result| {
+            assert_eq!(*result.unwrap_err().code(), "timeout");
+            let (inner, tx) = (outer.clone(), tx.clone());
+            outer.call("echo", Some(&vec![&json!(1)]), Box::new(move |result| {
+                let tx = tx.clone();
+                assert_eq!(*result.unwrap(), json!(1));
+                inner.call("echo", Some(&vec![&json!(2)]), Box::new(move |result| {
+                    tx.send(result.map(|r| r.clone()).map_err(|e| e.clone())).unwrap();
+                }));
+            }));
+        })
+        .timeout(Duration::from_millis(100));
+    conn.apply("slow", None, callbacks);
+    assert_eq!(next(&results).unwrap(), json!(2));
+}
+
+#[test]
+fn times_out_and_cancels() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+
+    let (tx, results) = channel();
+    let timed = tx.clone();
+    let callbacks = MethodCallbacks::new()
+        .on_result(move |result| timed.send(result.map(|r| r.clone()).map_err(|e| e.clone())).unwrap())
+        .timeout(Duration::from_millis(100));
+    let slow = conn.apply("slow", None, callbacks);
+    assert_eq!(*next(&results).unwrap_err().code(), "timeout");
+    assert!(!slow.cancel());
+
+    let answered = tx.clone();
+    let callbacks = MethodCallbacks::new()
+        .on_result(move |result| answered.send(result.map(|r| r.clone()).map_err(|e| e.clone())).unwrap())
+        .timeout(Duration::from_millis(200));
+    conn.apply("echo", Some(&vec![&json!(1)]), callbacks);
+    assert_eq!(next(&results).unwrap(), json!(1));
+
+    let cancelled = conn.call("slow", None, Box::new(move |result| {
+        tx.send(result.map(|r| r.clone()).map_err(|e| e.clone())).unwrap();
+    }));
+    assert!(cancelled.cancel());
+    assert!(!cancelled.cancel());
+    // Neither the cancelled call nor the answered echo calls back again.
+    assert!(results.recv_timeout(Duration::from_millis(400)).is_err());
+}
+
+#[test]
+fn fails_pending_methods_once_disconnected() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+
+    let (tx, results) = channel();
+    let pending = tx.clone();
+    conn.call("slow", None, Box::new(move |result| {
+        pending.send(result.map(|r| r.clone()).map_err(|e| e.clone())).unwrap();
+    }));
+    conn.call("hangup", None, Box::new(|_| {}));
+    assert_eq!(*next(&results).unwrap_err().code(), "disconnected");
+
+    conn.call("echo", Some(&vec![&json!(1)]), Box::new(move |result| {
+        tx.send(result.map(|r| r.clone()).map_err(|e| e.clone())).unwrap();
+    }));
+    assert_eq!(*next(&results).unwrap_err().code(), "disconnected");
+}
+
+#[test]
+fn clients_fail_pending_methods_when_they_give_up() {
+    let server = serve();
+    let client = Client::new(server.url()).unwrap();
+
+    let (tx, results) = channel();
+    client.call("slow", None, move |result| {
+        tx.send(result.map(|r| r.clone()).map_err(|e| e.clone())).unwrap();
+    });
+    server.expect("method");
+    // Gone for good, so there's nothing to reconnect to.
+    drop(server);
+    assert_eq!(*next(&results).unwrap_err().code(), "disconnected");
+}
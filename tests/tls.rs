@@ -0,0 +1,55 @@
+extern crate ddp;
+extern crate native_tls;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::sync::mpsc::channel;
+
+use ddp::{Certificate, Connection};
+use ddp::client::TlsConfig;
+use ddp::mock::MockServer;
+
+use common::{next, serve, wss, CERT};
+
+#[test]
+fn wss_trusts_added_root_certificate() {
+    let server = MockServer::start();
+    let mut tls = TlsConfig::new();
+    tls.add_root_certificate(Certificate::from_pem(CERT).unwrap());
+
+    let (conn, _) = Connection::with_tls(&wss(&server), &tls, || {}).unwrap();
+    assert_eq!(conn.session(), "mock-1");
+}
+
+#[test]
+fn wss_rejects_unknown_certificate() {
+    let server = MockServer::start();
+    assert!(Connection::new(&wss(&server), || {}).is_err());
+}
+
+#[test]
+fn wss_can_accept_invalid_certs() {
+    let server = MockServer::start();
+    let mut tls = TlsConfig::new();
+    tls.danger_accept_invalid_certs(true);
+
+    let (conn, _) = Connection::with_tls(&wss(&server), &tls, || {}).unwrap();
+    assert_eq!(conn.session(), "mock-1");
+}
+
+#[test]
+fn wss_carries_method_calls() {
+    let server = serve();
+    let mut tls = TlsConfig::new();
+    tls.add_root_certificate(Certificate::from_pem(CERT).unwrap());
+    let (conn, _) = Connection::with_tls(&wss(&server), &tls, || {}).unwrap();
+
+    let (tx, rx) = channel();
+    let echo = json!("over tls");
+    conn.call("echo", Some(&vec![&echo]), Box::new(move |result| {
+        tx.send(result.ok().cloned()).unwrap();
+    }));
+    assert_eq!(next(&rx), Some(echo));
+}
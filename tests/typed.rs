@@ -0,0 +1,97 @@
+extern crate ddp;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::sync::mpsc::channel;
+
+use ddp::Connection;
+use ddp::client::{Query, TypedCollection};
+use ddp::mock::{MockServer, Reply};
+
+use common::next;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Thing {
+    #[serde(rename = "_id", skip_serializing_if = "String::is_empty", default)]
+    id:   String,
+    name: String,
+    n:    u32,
+}
+
+// The "things" publication has one good document and one that isn't a
+// `Thing`. Inserts are published back with the id "new", updates with $set
+// are applied to "a".
+fn serve() -> MockServer {
+    let server = common::serve();
+    server.publish("things", |peer, _| {
+        peer.added("things", "a", json!({ "name": "apple", "n": 1 }));
+        peer.added("things", "b", json!({ "colour": "blue" }));
+        Ok(())
+    });
+    server.method("/things/insert", |peer, params| {
+        peer.added("things", "new", params[0].clone());
+        Reply::Result(json!("new"))
+    });
+    server.method("/things/update", |peer, params| {
+        peer.changed("things", "a", params[1]["$set"].clone(), &[]);
+        Reply::Result(json!(1))
+    });
+    server
+}
+
+fn thing(id: &str, name: &str, n: u32) -> Thing {
+    Thing { id: id.to_string(), name: name.to_string(), n: n }
+}
+
+#[test]
+fn cache_and_listeners_yield_documents() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let things: TypedCollection<Thing> = TypedCollection::new(conn.mongo("things".to_string()));
+
+    let (tx, added) = channel();
+    things.on_add(move |_, doc| tx.send(doc).unwrap());
+    let (tx, ready) = channel();
+    things.subscribe().on_ready(move |result| tx.send(result.is_ok()).unwrap());
+    assert!(next(&ready));
+
+    assert_eq!(next(&added), thing("a", "apple", 1));
+    assert_eq!(things.len(), 2);
+    assert_eq!(things.all(), vec![thing("a", "apple", 1)]);
+    assert_eq!(things.find_one("a"), Some(thing("a", "apple", 1)));
+    assert_eq!(things.find_one("b"), None);
+    assert!(things.collection().find_one("b").is_some());
+
+    let query = Query::new(&json!({ "n": { "$gte": 1 } })).unwrap();
+    assert_eq!(things.find(&query), vec![thing("a", "apple", 1)]);
+    assert!(added.try_recv().is_err());
+}
+
+#[test]
+fn writes_take_documents_and_modifiers() {
+    let server = serve();
+    let (conn, _) = Connection::new(&server.url(), || {}).unwrap();
+    let things: TypedCollection<Thing> = TypedCollection::new(conn.mongo("things".to_string()));
+    let (tx, ready) = channel();
+    things.subscribe().on_ready(move |result| tx.send(result.is_ok()).unwrap());
+    assert!(next(&ready));
+
+    let (tx, inserted) = channel();
+    things.insert(&thing("", "pear", 3), move |result| tx.send(result.unwrap().clone()).unwrap());
+    assert_eq!(next(&inserted), json!("new"));
+    assert_eq!(things.find_one("new"), Some(thing("new", "pear", 3)));
+
+    #[derive(Serialize)]
+    struct SetN { n: u32 }
+    #[derive(Serialize)]
+    struct Modifier { #[serde(rename = "$set")] set: SetN }
+
+    let (tx, changed) = channel();
+    things.on_change(move |_, doc| tx.send(doc).unwrap());
+    things.update(&json!({ "_id": "a" }), &Modifier { set: SetN { n: 7 } }, |_| {});
+    assert_eq!(next(&changed), thing("a", "apple", 7));
+}